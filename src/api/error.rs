@@ -1,9 +1,37 @@
-use crate::cast;
+use crate::{cast, library, queue, subtitles, video_encoding};
+use serde::Serialize;
 
 #[derive(Debug)]
 pub enum ApiError {
     ApiError(String),
-    CastError(cast::Error)
+    CastError(cast::Error),
+    LibraryError(library::Error),
+    QueueError(queue::Error),
+    EncodingError(video_encoding::Error),
+    SubtitleError(subtitles::Error),
+}
+
+/// How an error is sent to clients, e.g.
+/// `{"error":"encoding","message":"The codec av1 is not supported"}`
+#[derive(Serialize)]
+struct ErrorReply<'a> {
+    error: &'a str,
+    message: String,
+}
+
+impl ApiError {
+    /// Serialize the error into JSON for a client.
+    pub fn to_json(&self) -> String {
+        let (error, message) = match self {
+            Self::ApiError(message) => ("api", message.clone()),
+            Self::CastError(err) => ("cast", err.to_string()),
+            Self::LibraryError(err) => ("library", err.to_string()),
+            Self::QueueError(err) => ("queue", err.to_string()),
+            Self::EncodingError(err) => ("encoding", err.to_string()),
+            Self::SubtitleError(err) => ("subtitles", err.to_string()),
+        };
+        serde_json::to_string(&ErrorReply { error, message }).unwrap_or_default()
+    }
 }
 
 // ApiError from string
@@ -19,3 +47,31 @@ impl From<cast::Error> for ApiError {
         Self::CastError(e)
     }
 }
+
+// LibraryError
+impl From<library::Error> for ApiError {
+    fn from(e: library::Error) -> Self {
+        Self::LibraryError(e)
+    }
+}
+
+// QueueError
+impl From<queue::Error> for ApiError {
+    fn from(e: queue::Error) -> Self {
+        Self::QueueError(e)
+    }
+}
+
+// EncodingError
+impl From<video_encoding::Error> for ApiError {
+    fn from(e: video_encoding::Error) -> Self {
+        Self::EncodingError(e)
+    }
+}
+
+// SubtitleError
+impl From<subtitles::Error> for ApiError {
+    fn from(e: subtitles::Error) -> Self {
+        Self::SubtitleError(e)
+    }
+}
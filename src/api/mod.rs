@@ -1,9 +1,15 @@
 pub mod error;
 
-use crate::cast;
-use std::net::IpAddr;
+use crate::{
+    cast::{self, device::{MediaMetadata, TextTrackStyle}},
+    library::{details::Details, Library, LibraryEntry, LibraryEvent},
+    queue::{Job, Queue, QueueCommand},
+    subtitles,
+    video_encoding::{self, cache::{self, Cache}, hls, probe::{AudioStream, SubtitleStream}, transcode::TranscodeQuery, Action, Chromecast},
+};
+use std::{collections::HashMap, net::IpAddr, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, RwLock}};
 use serde::{Serialize, Deserialize};
-use tokio::sync::oneshot;
+use tokio::sync::{broadcast, oneshot};
 
 pub type Error = error::ApiError;
 
@@ -20,10 +26,43 @@ pub enum Request {
     Get(GetType, oneshot::Sender<String>),
 }
 
+/// Events are pushed to every client listening on `/api/events` as they happen.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "camelCase")]
+pub enum Event {
+    Library(LibraryEvent),
+    /// A scan asked for with `PutType::ScanLibrary` ended. Its changes are sent
+    /// before it as `Library` events.
+    LibraryScanned {
+        /// How many entries were added, updated or removed
+        changes: usize,
+        /// Why the scan failed, if it did
+        error: Option<String>,
+    },
+    /// A pre-conversion job was queued, started or ended
+    Job(Job),
+    /// The media being cast changed state, e.g. it was paused, seeked or finished
+    MediaStatus(cast::MediaStatus),
+    /// The connection to the chromecast dropped, or was made again
+    Connection(cast::ConnectionState),
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum GetType {
     MediaStatus,
     Chromecasts,
+    Library,
+    /// The pre-conversion jobs and their progress
+    Queue,
+    /// The tracks of the library entry with the matching id that can be chosen
+    /// from when casting it
+    Tracks(u32),
+    /// The offset and style subtitles are shown with
+    Subtitles,
+    /// Whether the caster is connected to the chromecast, or reconnecting to it
+    Connection,
+    /// What the chromecast is doing, including apps and media other senders started
+    Device,
 }
 
 /// PutTypes are used to determine what Put request is being called.
@@ -33,6 +72,17 @@ pub enum PutType {
     Control(CastSignal),
     SelectChromecast(String),
     DiscoverChromecasts,
+    /// Set the generation of the selected chromecast, for models that can't be
+    /// told apart from a less capable one. The third generation reports the same
+    /// model as the first two, so is otherwise treated as one of them.
+    ChromecastGeneration(Chromecast),
+    /// Rescan the library's root directories for new or removed files. The
+    /// scan runs in the background, `Event::LibraryScanned` is sent once it ends.
+    ScanLibrary,
+    /// Manage the queue of library entries to convert ahead of time
+    Queue(QueueCommand),
+    /// Change how subtitles are drawn, now and in later casts
+    SubtitleStyle(TextTrackStyle),
 }
 
 /// CastSignals are used to send requests to the chromecast for playback
@@ -42,10 +92,69 @@ pub enum CastSignal {
     /// CastSignal::Begin takes a u32 representing the index of the video file in the server's
     /// library. This will likely need to be retrieved with a Get before it can be determined.
     Begin(u32),
+    /// Begin casting with a choice of tracks rather than the file's defaults
+    BeginWithTracks(u32, TrackSelection),
     Stop,
     Pause,
     Play,
     Seek(f32),
+    /// Show the subtitle track with the matching id, as listed by `GetType::Tracks`
+    ShowSubtitles(usize),
+    HideSubtitles,
+    /// Switch to the audio stream with the matching container index, as listed
+    /// by `GetType::Tracks`. Playback carries on from where it was.
+    SwitchAudio(usize),
+    /// Shift the subtitles of the current cast by some seconds, negative to
+    /// show them earlier. Playback carries on from where it was.
+    SubtitleOffset(f64),
+    /// Set the volume of the chromecast, from 0.0 for silent to 1.0 for full
+    SetVolume(f32),
+    /// Turn the volume up by `cast::VOLUME_STEP`
+    VolumeUp,
+    /// Turn the volume down by `cast::VOLUME_STEP`
+    VolumeDown,
+    Mute,
+    Unmute,
+}
+
+/// Which of a library entry's tracks to play. Tracks left unset are the
+/// file's defaults.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackSelection {
+    /// Container index of the audio stream, as listed by `GetType::Tracks`
+    #[serde(default)]
+    pub audio: Option<usize>,
+    /// Id of the subtitle track to show from the start, as listed by `GetType::Tracks`.
+    /// Every subtitle track can still be turned on from the TV.
+    #[serde(default)]
+    pub subtitles: Option<usize>,
+    /// Container index of a bitmap subtitle stream to draw onto the video, as
+    /// listed by `GetType::Tracks`. The video has to be transcoded to do so.
+    #[serde(default)]
+    pub burn_in: Option<usize>,
+}
+
+/// How subtitles are shown, as sent to clients.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubtitleSettings<'a> {
+    /// Seconds the subtitles of the current cast are shifted by
+    pub offset: f64,
+    /// `None` while the receiver's default style is used
+    pub style: Option<&'a TextTrackStyle>,
+}
+
+/// The tracks of a library entry, as sent to clients.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Tracks<'a> {
+    pub audio: &'a [AudioStream],
+    /// Container index of the audio stream played when none is chosen
+    pub default_audio: Option<usize>,
+    pub subtitles: Vec<subtitles::Track>,
+    /// Bitmap subtitle streams, which can only be shown by burning them in
+    pub burn_in: Vec<&'a SubtitleStream>,
 }
 
 /// Api serves as an easily manipulated interface with a Caster.
@@ -54,16 +163,42 @@ pub enum CastSignal {
 pub struct Api {
     // TODO move caster to private, once appropriate control functions are in place
     pub caster: cast::Caster,
-    current_chromecast: Option<(String, IpAddr)>,
-    discovered_chromecasts: Vec<(String, IpAddr)>,
+    current_chromecast: Option<cast::DeviceInfo>,
+    discovered_chromecasts: Vec<cast::DeviceInfo>,
+    /// Generations set with `PutType::ChromecastGeneration`, by device address,
+    /// so they outlive rediscovering the devices
+    generation_overrides: HashMap<IpAddr, Chromecast>,
+    library: Arc<RwLock<Library>>,
+    cache: Arc<Mutex<Cache>>,
+    queue: Queue,
+    media_port: u16,
+    events_tx: broadcast::Sender<Event>,
+    /// Id of the library entry last cast and the tracks it was cast with
+    current_cast: Option<(u32, TrackSelection)>,
+    /// Whether a scan asked for through the Api is running
+    scanning: Arc<AtomicBool>,
 }
 
 #[allow(dead_code)]
 impl Api {
-    pub fn new() -> Self {
-        Self {  caster: cast::Caster::new(), 
+    /// Creates a new Api around a shared library. `media_port` is the port that
+    /// `server::host_media` is serving the library on, with converted media kept
+    /// in `cache`. Changes made through the Api are pushed to clients through `events_tx`.
+    pub fn new(library: Arc<RwLock<Library>>, 
+        cache: Arc<Mutex<Cache>>,
+        media_port: u16, 
+        events_tx: broadcast::Sender<Event>) -> Self {
+        Self {  caster: cast::Caster::new(events_tx.clone()), 
                 current_chromecast: None,
-                discovered_chromecasts: Vec::new() }
+                discovered_chromecasts: Vec::new(),
+                generation_overrides: HashMap::new(),
+                library,
+                queue: Queue::new(cache.clone(), events_tx.clone()),
+                cache,
+                media_port,
+                events_tx,
+                current_cast: None,
+                scanning: Arc::new(AtomicBool::new(false)) }
     }
     
     /// Polls the network for mDNS devices to build a list of available chromecasts.
@@ -71,12 +206,10 @@ impl Api {
     /// This function MUST be called on the tokio::runtimes' thread, otherwise, you will need to
     /// use the runtime's handle and replicate this function using that.
     /// # Returns
-    /// `&Vec<(String, IpAddress)` - A vec containing all the found devices as (FriendlyName,
-    /// IpAddress)
     /// `ApiError` - on failure
     pub fn discover_chromecasts(&mut self) -> Result<(), Error> {
         // Call find_chromecasts on tokio::runtime
-        let (tx, mut rx) = oneshot::channel::<Result<Vec<(String, IpAddr)>, cast::Error>>();
+        let (tx, mut rx) = oneshot::channel::<Result<Vec<cast::DeviceInfo>, cast::Error>>();
         tokio::spawn( async move {
             tx.send(cast::find_chromecasts().await).unwrap();
         });
@@ -95,22 +228,27 @@ impl Api {
             Ok(chromecasts) => self.discovered_chromecasts = chromecasts,
             Err(err) => return Err(err.into()),
         }
+        for device in self.discovered_chromecasts.iter_mut() {
+            if let Some(generation) = self.generation_overrides.get(&device.ip) {
+                device.generation = Some(*generation);
+            }
+        }
 
         Ok(())
     }
 
     /// Returns a reference the cached Vec holding all the previously discovered chromecasts.
     /// Note, there is no guarantee that any of the devices are still available.
-    pub fn get_discovered_chromecasts(&self) -> &Vec<(String, IpAddr)> {
+    pub fn get_discovered_chromecasts(&self) -> &Vec<cast::DeviceInfo> {
         &self.discovered_chromecasts
     }
     
     /// Sets the selected chromecast to the passed reference. Note, the device MUST be present
     /// in discovered chromecasts, otherwise this will return an error.
-    pub fn select_chromecast(&mut self, device: &(String, IpAddr)) -> Result<(), Error> {
+    pub fn select_chromecast(&mut self, device: &cast::DeviceInfo) -> Result<(), Error> {
         if self.discovered_chromecasts.contains(&device) {
             self.current_chromecast = Some(device.clone());
-            self.caster.set_device_addr(&device.1.to_string());
+            self.caster.set_device_addr(&device.ip.to_string());
 
             log::info!("[API] Selected chromecast: {:?}", &device);
         }
@@ -121,6 +259,244 @@ impl Api {
         Ok(())
     }
 
+    /// Sets the generation of the selected chromecast, overriding the one matched
+    /// from its model. It is kept for the device's address when chromecasts are
+    /// discovered again.
+    /// ### Returns
+    /// `ApiError` if no chromecast is selected
+    pub fn set_chromecast_generation(&mut self, generation: Chromecast) -> Result<(), Error> {
+        let current = match &mut self.current_chromecast {
+            Some(current) => current,
+            None => return Err(Error::ApiError("No chromecast is selected.".into())),
+        };
+        // Keep the discovered device matching the selected one, so it can be selected again
+        if let Some(device) = self.discovered_chromecasts.iter_mut().find(|device| device == &current) {
+            device.generation = Some(generation);
+        }
+        current.generation = Some(generation);
+        self.generation_overrides.insert(current.ip, generation);
+
+        log::info!("[API] Set the generation of '{}' to {:?}", &current.name, generation);
+        Ok(())
+    }
+
+    /// Returns the generation of the selected chromecast, used to decide what
+    /// media needs converting before it can be played.
+    /// Falls back to the least capable generation if none is selected.
+    pub fn chromecast_generation(&self) -> Chromecast {
+        self.current_chromecast
+            .as_ref()
+            .map_or(Chromecast::FirstAndSecond, cast::DeviceInfo::generation_or_default)
+    }
+
+    /// Begin casting the library entry with the matching id to the selected chromecast.
+    /// Any media already being cast is replaced.
+    /// ### Arguments
+    /// - `id` - Id of the library entry
+    /// - `tracks` - Tracks to play instead of the file's defaults
+    pub fn begin_cast(&mut self, id: u32, tracks: TrackSelection) -> Result<(), Error> {
+        let entry = self.library_entry(id)?;
+        log::info!("[API] Casting {:?} with {:?}", &entry.path, &tracks);
+        let (media_path, content_type) = self.media_source(&entry, &tracks)?;
+
+        // Subtitles are served on their own and attached to whatever is cast
+        let text_tracks: Vec<cast::TextTrack> = subtitles::find(&entry.path, entry.info.as_ref())
+            .into_iter()
+            .map(|track| cast::TextTrack {
+                path: format!("media/{}/subtitles/{}.vtt", entry.id, track.id),
+                id: track.id,
+                name: track.name,
+                language: track.language,
+            })
+            .collect();
+        if let Some(id) = tracks.subtitles {
+            if !text_tracks.iter().any(|track| track.id == id) {
+                return Err(subtitles::Error::TrackNotFound(id).into());
+            }
+        }
+
+        self.caster.begin_cast(self.media_port, &media_path, content_type,
+            Self::media_description(&entry), text_tracks, tracks.subtitles)?;
+        self.current_cast = Some((id, tracks));
+        Ok(())
+    }
+
+    /// Switch the current cast to another audio track of its entry, carrying
+    /// on from where it was.
+    /// ### Arguments
+    /// - `audio` - Container index of the audio stream, as listed by `GetType::Tracks`
+    pub fn switch_audio(&mut self, audio: usize) -> Result<(), Error> {
+        let (id, tracks) = match self.current_cast {
+            Some(current) if self.caster.is_streaming() => current,
+            _ => return Err(Error::ApiError("Nothing is being cast.".into())),
+        };
+        let entry = self.library_entry(id)?;
+        let tracks = TrackSelection { audio: Some(audio), ..tracks };
+
+        log::info!("[API] Switching {:?} to audio stream {}", &entry.path, audio);
+        let (media_path, content_type) = self.media_source(&entry, &tracks)?;
+        self.caster.switch_media(&media_path, content_type)?;
+        self.current_cast = Some((id, tracks));
+        Ok(())
+    }
+
+    /// Show a subtitle track of the current cast, or hide subtitles.
+    /// ### Arguments
+    /// - `subtitles` - Id of the subtitle track, as listed by `GetType::Tracks`,
+    ///     or `None` to hide subtitles
+    pub fn switch_subtitles(&mut self, subtitles: Option<usize>) -> Result<(), Error> {
+        self.caster.set_active_text_track(subtitles)?;
+        if let Some((_, tracks)) = &mut self.current_cast {
+            tracks.subtitles = subtitles;
+        }
+        Ok(())
+    }
+
+    /// Describe a library entry for the receiver to show while casting it.
+    fn media_description(entry: &LibraryEntry) -> cast::MediaDescription {
+        let metadata = match entry.details() {
+            Details::Generic { title } => MediaMetadata::generic(title),
+            Details::Movie { title, release_date } => MediaMetadata::movie(title, release_date),
+            Details::Episode { series, season, episode, title, air_date } =>
+                MediaMetadata::tv_show(series, season, episode, title, air_date),
+        };
+        cast::MediaDescription {
+            metadata,
+            poster_path: entry.poster().map(|_| format!("media/{}/poster", entry.id)),
+        }
+    }
+
+    /// Scan the library on a blocking thread, pushing its changes and then
+    /// `Event::LibraryScanned` to clients.
+    fn scan_library(&self) {
+        let library = self.library.clone();
+        let events_tx = self.events_tx.clone();
+        let scanning = self.scanning.clone();
+        tokio::task::spawn_blocking(move || {
+            let (changes, error) = match Library::scan(&library) {
+                Ok(events) => {
+                    let changes = events.len();
+                    for event in events {
+                        let _ = events_tx.send(Event::Library(event));
+                    }
+                    (changes, None)
+                }
+                Err(err) => {
+                    log::error!("[API] Library scan failed: {:?}", err);
+                    (0, Some(err.to_string()))
+                }
+            };
+            scanning.store(false, Ordering::SeqCst);
+            let _ = events_tx.send(Event::LibraryScanned { changes, error });
+        });
+    }
+
+    /// Returns a copy of the library entry with the matching id.
+    fn library_entry(&self, id: u32) -> Result<LibraryEntry, Error> {
+        match self.library.read().unwrap().get(id) {
+            Some(entry) => Ok(entry.clone()),
+            None => Err(Error::ApiError(format!("No library entry with id {}.", id))),
+        }
+    }
+
+    /// Work out where the media server serves an entry with the chosen tracks
+    /// in a form the selected chromecast can play.
+    /// ### Returns
+    /// The path of the media on the media server and its content type
+    fn media_source(&self, entry: &LibraryEntry, tracks: &TrackSelection)
+        -> Result<(String, &'static str), Error> {
+        let generation = self.chromecast_generation();
+        let (action, audio) = match &entry.info {
+            Some(info) => {
+                let verdict = video_encoding::compat::check(info, generation);
+                let default_audio = info.default_audio().map(|stream| stream.index);
+                // The chromecast always plays the default track, any other has
+                // to be the only one in the output
+                let (action, audio) = match tracks.audio.filter(|audio| Some(*audio) != default_audio) {
+                    Some(audio) => {
+                        if info.audio_stream(audio).is_none() {
+                            return Err(video_encoding::Error::StreamNotFound(audio).into());
+                        }
+                        let video = info.default_video().map(|stream| stream.index);
+                        (verdict.action_for(video, Some(audio)).max(Action::Remux), Some(audio))
+                    }
+                    None => (verdict.action, None),
+                };
+                match tracks.burn_in {
+                    Some(burn_in) => {
+                        let stream = info.subtitle_stream(burn_in)
+                            .ok_or(video_encoding::Error::StreamNotFound(burn_in))?;
+                        if !stream.bitmap {
+                            return Err(video_encoding::Error::UnsupportedCodec(stream.codec.clone()).into());
+                        }
+                        (Action::Transcode, audio)
+                    }
+                    None => (action, audio),
+                }
+            }
+            // Burning in subtitles needs the streams of the file
+            None if tracks.burn_in.is_some() => return Err(video_encoding::Error::NotProbed.into()),
+            // Without probe info there is nothing to convert with, try it as is
+            None => (Action::Direct, None),
+        };
+        let query = TranscodeQuery { chromecast: generation, audio, burn_in: tracks.burn_in };
+
+        if action != Action::Direct {
+            log::info!("[API] {:?} needs {:?} to play on {:?}.", &entry.path, action, generation);
+        }
+        let (media_path, content_type) = match action {
+            Action::Direct => (format!("media/{}", entry.id), entry.content_type()),
+            Action::Remux if audio.is_none() => (format!("media/{}/remux", entry.id), "video/mp4"),
+            // Picking streams goes through the transcode pipeline, which copies
+            // whatever is already compatible.
+            // HLS lets the receiver seek, but the playlist needs to know the duration.
+            // A finished conversion in the cache can be seeked as is.
+            _ if !self.is_transcode_cached(entry, &query)
+                && entry.info.as_ref().and_then(|info| info.duration).is_some() => {
+                (format!("media/{}/hls/playlist.m3u8?{}", entry.id, query.to_query_string()), hls::CONTENT_TYPE)
+            }
+            _ => (format!("media/{}/transcode?{}", entry.id, query.to_query_string()), "video/mp4"),
+        };
+        Ok((media_path, content_type))
+    }
+
+    /// Check if the whole of an entry has been converted as `query` asks, such
+    /// as by the queue.
+    fn is_transcode_cached(&self, entry: &LibraryEntry, query: &TranscodeQuery) -> bool {
+        let plan = match entry.info.as_ref().map(|info| query.plan(info)) {
+            Some(Ok(plan)) => plan,
+            _ => return false,
+        };
+        let key = cache::Key::transcode(&entry.path, entry.modified, &plan);
+        self.cache.lock().unwrap().contains(&key)
+    }
+
+    /// Apply a queue command from a client.
+    /// ### Returns
+    /// The job that was changed, if the command was about a single job
+    pub fn handle_queue_command(&mut self, command: QueueCommand) -> Result<Option<Job>, Error> {
+        let job = match command {
+            QueueCommand::Add { id, priority } => {
+                let entry = match self.library.read().unwrap().get(id) {
+                    Some(entry) => entry.clone(),
+                    None => return Err(Error::ApiError(format!("No library entry with id {}.", id))),
+                };
+                Some(self.queue.add(&entry, self.chromecast_generation(), priority)?)
+            }
+            QueueCommand::Cancel(id) => Some(self.queue.cancel(id)?),
+            QueueCommand::SetPriority { job, priority } => Some(self.queue.set_priority(job, priority)?),
+            QueueCommand::SetConcurrency(concurrency) => {
+                self.queue.set_concurrency(concurrency);
+                None
+            }
+            QueueCommand::ClearFinished => {
+                self.queue.clear_finished();
+                None
+            }
+        };
+        Ok(job)
+    }
+
     /// Handles API requests from a client.
     pub fn handle_request(&mut self, request: Request) {
         match request {
@@ -144,7 +520,7 @@ impl Api {
                         if let Some(device) = &self.discovered_chromecasts
                             .clone()
                             .iter()
-                            .find(|x| x.1.to_string() == addr) {
+                            .find(|x| x.ip.to_string() == addr) {
                             
                             self.select_chromecast(&device.clone()).unwrap();
                             let _ = sender.send("Success.".into());
@@ -153,6 +529,52 @@ impl Api {
                             let _ = sender.send("Chromecast not found.".into());
                         }
                     },
+
+                    PutType::ChromecastGeneration(generation) => {
+                        log::info!("[API] Request recieved: chromecast generation {:?}", generation);
+                        match self.set_chromecast_generation(generation) {
+                            Ok(_) => { let _ = sender.send("Success.".into()); },
+                            Err(err) => { let _ = sender.send(err.to_json()); },
+                        }
+                    },
+
+                    // Rescan the library, probing files takes a while so the reply doesn't wait for it
+                    PutType::ScanLibrary => {
+                        log::info!("[API] Request recieved: ScanLibrary");
+                        if self.scanning.swap(true, Ordering::SeqCst) {
+                            let _ = sender.send("A library scan is already running.".into());
+                        }
+                        else {
+                            self.scan_library();
+                            let _ = sender.send("Library scan started.".into());
+                        }
+                    },
+
+                    PutType::SubtitleStyle(style) => {
+                        log::info!("[API] Request recieved: {:?}", style);
+                        let result = style.validate()
+                            .map_err(Error::ApiError)
+                            .and_then(|_| Ok(self.caster.set_text_track_style(style)?));
+                        match result {
+                            Ok(_) => { let _ = sender.send("Success.".into()); },
+                            Err(err) => {
+                                log::warn!("[API] Failed to set subtitle style: {:?}", err);
+                                let _ = sender.send(err.to_json());
+                            }
+                        }
+                    },
+
+                    PutType::Queue(command) => {
+                        log::info!("[API] Request recieved: {:?}", command);
+                        match self.handle_queue_command(command) {
+                            Ok(Some(job)) => { let _ = sender.send(serde_json::to_string(&job).unwrap()); },
+                            Ok(None) => { let _ = sender.send("Success.".into()); },
+                            Err(err) => {
+                                log::warn!("[API] Queue request failed: {:?}", err);
+                                let _ = sender.send(err.to_json());
+                            }
+                        }
+                    },
                 }
             }
 
@@ -167,23 +589,45 @@ impl Api {
     /// # Parameters
     /// `signal: CastSignal` - The signal to handle, this determines what to tell the chromecast to
     /// do.
-    /// `sender: Sender<String>` - The feedback to return to the client.
+    /// `sender: Sender<String>` - The feedback to return to the client, the error as JSON if
+    /// the signal couldn't be carried out.
     // TODO Only reply to client after chromecast has reacted to signal. This allows for a client to determine when the chromecast has ACTUALLY enacted its request.
-    fn handle_cast_signal(&self, signal: CastSignal, sender: oneshot::Sender<String>) {
-        let _ = sender.send("Request recieved.".into());
+    fn handle_cast_signal(&mut self, signal: CastSignal, sender: oneshot::Sender<String>) {
         log::info!("[API] Request recieved: {:?}", signal);
-        
-        if !self.caster.is_streaming() {
-            log::info!("[API] Failed request. Chromecast is not streaming.");
-            return;
+        match self.apply_cast_signal(signal) {
+            Ok(_) => { let _ = sender.send("Request recieved.".into()); },
+            Err(err) => {
+                log::error!("[API] Failed to handle {:?}: {:?}", signal, err);
+                let _ = sender.send(err.to_json());
+            }
         }
+    }
 
+    /// Tell the chromecast to do what a CastSignal asks.
+    /// ### Returns
+    /// An error if nothing is being cast, or if the chromecast can't be
+    /// reached, e.g. while the caster is reconnecting to it
+    fn apply_cast_signal(&mut self, signal: CastSignal) -> Result<(), Error> {
         match signal {
-            CastSignal::Begin(_) => todo!(),
-            CastSignal::Stop => self.caster.stop().unwrap(),
-            CastSignal::Pause => self.caster.pause().unwrap(),
-            CastSignal::Play => self.caster.resume().unwrap(),
-            CastSignal::Seek(seconds) => self.caster.seek(seconds).unwrap(),
+            // Beginning a cast is the only signal that doesn't need an active stream
+            CastSignal::Begin(id) => self.begin_cast(id, TrackSelection::default()),
+            CastSignal::BeginWithTracks(id, tracks) => self.begin_cast(id, tracks),
+            _ if !self.caster.is_streaming() => {
+                Err(Error::ApiError("Chromecast is not streaming.".into()))
+            }
+            CastSignal::Stop => Ok(self.caster.stop()?),
+            CastSignal::Pause => Ok(self.caster.pause()?),
+            CastSignal::Play => Ok(self.caster.resume()?),
+            CastSignal::Seek(seconds) => Ok(self.caster.seek(seconds)?),
+            CastSignal::ShowSubtitles(id) => self.switch_subtitles(Some(id)),
+            CastSignal::HideSubtitles => self.switch_subtitles(None),
+            CastSignal::SwitchAudio(audio) => self.switch_audio(audio),
+            CastSignal::SubtitleOffset(seconds) => Ok(self.caster.set_subtitle_offset(seconds)?),
+            CastSignal::SetVolume(level) => Ok(self.caster.set_volume(level)?),
+            CastSignal::VolumeUp => Ok(self.caster.step_volume(cast::VOLUME_STEP)?),
+            CastSignal::VolumeDown => Ok(self.caster.step_volume(-cast::VOLUME_STEP)?),
+            CastSignal::Mute => Ok(self.caster.set_muted(true)?),
+            CastSignal::Unmute => Ok(self.caster.set_muted(false)?),
         }
     }
 
@@ -198,14 +642,53 @@ impl Api {
             },
             
             GetType::Chromecasts => {
-                // Build Vec<(String, String)> from &Vec<(String, IpAddr)>
-                let chromecasts: Vec<(String, String)> = self.discovered_chromecasts
-                    .iter()
-                    .map(|x| (x.0.clone(), (x.1).to_string()))
-                    .collect();
-                
-                // Serialize to map in JSON and reply to API caller
-                let _ = sender.send(serde_json::to_string(&chromecasts).unwrap());
+                // Serialize to JSON and reply to API caller
+                let _ = sender.send(serde_json::to_string(&self.discovered_chromecasts).unwrap());
+            },
+
+            GetType::Library => {
+                let library = self.library.read().unwrap();
+                let _ = sender.send(serde_json::to_string(&library.entries()).unwrap());
+            }
+
+            GetType::Subtitles => {
+                let settings = SubtitleSettings {
+                    offset: self.caster.subtitle_offset(),
+                    style: self.caster.text_track_style(),
+                };
+                let _ = sender.send(serde_json::to_string(&settings).unwrap());
+            }
+
+            GetType::Connection => {
+                let state = self.caster.connection_state();
+                let _ = sender.send(serde_json::to_string(&state).unwrap());
+            }
+
+            GetType::Device => {
+                let reply = match self.caster.device_status() {
+                    Ok(status) => serde_json::to_string(&status).unwrap(),
+                    Err(err) => Error::from(err).to_json(),
+                };
+                let _ = sender.send(reply);
+            }
+
+            GetType::Queue => {
+                let _ = sender.send(serde_json::to_string(&self.queue.status()).unwrap());
+            }
+
+            GetType::Tracks(id) => {
+                let library = self.library.read().unwrap();
+                let reply = match library.get(id) {
+                    Some(LibraryEntry { info: Some(info), path, .. }) => serde_json::to_string(&Tracks {
+                        audio: &info.audio,
+                        default_audio: info.default_audio().map(|stream| stream.index),
+                        subtitles: subtitles::find(path, Some(info)),
+                        burn_in: info.subtitles.iter().filter(|stream| stream.bitmap).collect(),
+                    }).unwrap(),
+                    Some(_) => Error::from(video_encoding::Error::NotProbed).to_json(),
+                    None => Error::ApiError(format!("No library entry with id {}.", id)).to_json(),
+                };
+                let _ = sender.send(reply);
             }
         }
     }
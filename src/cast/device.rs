@@ -0,0 +1,791 @@
+use super::{error::CastError, DESTINATION_ID};
+use openssl::ssl::{SslConnector, SslMethod, SslStream, SslVerifyMode};
+use rust_cast::{
+    channels::{
+        connection::ConnectionChannel,
+        heartbeat::HeartbeatChannel,
+        media::{MediaChannel, MediaResponse, Status, StatusEntry},
+        receiver::{self, Application, CastDeviceApp, ReceiverChannel, ReceiverResponse, Volume},
+    },
+    errors::Error as RustCastError,
+    message_manager::{CastMessage, CastMessagePayload, MessageManager},
+    ChannelMessage,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, io::{self, Cursor, Read, Write}, net::{TcpStream, ToSocketAddrs}, thread, time::Duration};
+use std::os::unix::{io::AsRawFd, net::UnixStream};
+use std::sync::{mpsc::{self, RecvTimeoutError, TryRecvError}, Arc, Mutex};
+
+const SENDER_ID: &str = "sender-0";
+pub const MEDIA_NAMESPACE: &str = "urn:x-cast:com.google.cast.media";
+const RECEIVER_NAMESPACE: &str = "urn:x-cast:com.google.cast.receiver";
+/// How long to wait for the chromecast to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a request waits for its reply before the connection is taken to be dead
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Stream = SslStream<TcpStream>;
+/// Where the replies to requests go, by request id
+type Pending = Arc<Mutex<HashMap<i32, mpsc::Sender<CastMessage>>>>;
+
+/// Media to load on the receiver, described the way the cast protocol does.
+/// Unlike rust_cast's `Media`, this can carry tracks.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaInformation {
+    /// URL of the media
+    pub content_id: String,
+    pub content_type: String,
+    /// `BUFFERED` or `LIVE`
+    pub stream_type: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tracks: Vec<Track>,
+    /// How text tracks are drawn, the receiver's defaults if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text_track_style: Option<TextTrackStyle>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<MediaMetadata>,
+}
+
+/// A track of the media, as described to the receiver.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Track {
+    pub track_id: u32,
+    /// `TEXT`, `AUDIO` or `VIDEO`
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub subtype: &'static str,
+    /// URL of the track
+    pub track_content_id: String,
+    pub track_content_type: &'static str,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+}
+
+impl Track {
+    /// A WebVTT subtitle track served at `url`.
+    pub fn subtitles(track_id: u32, url: String, name: String, language: Option<String>) -> Self {
+        Self {
+            track_id,
+            kind: "TEXT",
+            subtype: "SUBTITLES",
+            track_content_id: url,
+            track_content_type: "text/vtt",
+            name,
+            language,
+        }
+    }
+}
+
+/// What the media is, as described to the receiver. It is shown while the
+/// media loads, and by apps following the cast such as Google Home.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaMetadata {
+    /// 0 for generic media, 1 for a movie and 2 for an episode of a TV show
+    pub metadata_type: u8,
+    /// Title of the media, or of the episode itself
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub series_title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub season: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub episode: Option<u32>,
+    /// Release date of generic media or a movie, as an ISO 8601 date or year
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release_date: Option<String>,
+    /// Date an episode first aired, as an ISO 8601 date or year
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub original_air_date: Option<String>,
+    /// Images of the media, e.g. a poster
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<Image>,
+}
+
+impl MediaMetadata {
+    pub fn generic(title: String) -> Self {
+        Self { metadata_type: 0, title: Some(title), ..Default::default() }
+    }
+
+    pub fn movie(title: String, release_date: Option<String>) -> Self {
+        Self { metadata_type: 1, title: Some(title), release_date, ..Default::default() }
+    }
+
+    /// An episode of a series.
+    /// ### Arguments
+    /// - `series_title` - Title of the series
+    /// - `title` - Title of the episode, if it has one
+    pub fn tv_show(series_title: String, season: Option<u32>, episode: Option<u32>,
+        title: Option<String>, original_air_date: Option<String>) -> Self {
+        Self {
+            metadata_type: 2,
+            title,
+            series_title: Some(series_title),
+            season,
+            episode,
+            original_air_date,
+            ..Default::default()
+        }
+    }
+}
+
+/// An image of the media, as described to the receiver.
+#[derive(Debug, Clone, Serialize)]
+pub struct Image {
+    pub url: String,
+}
+
+/// How the receiver draws subtitles. Colours are `#RRGGBBAA`, and fields
+/// left unset keep the receiver's defaults.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TextTrackStyle {
+    /// Size of the text relative to the default, e.g. `1.5`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub font_scale: Option<f64>,
+    /// Colour of the text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub foreground_color: Option<String>,
+    /// Colour of the box behind each line of text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background_color: Option<String>,
+    /// Outline or shadow drawn around the text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edge_type: Option<EdgeType>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edge_color: Option<String>,
+    /// Colour of the box behind the whole cue
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_color: Option<String>,
+}
+
+impl TextTrackStyle {
+    /// Smallest and largest font scale the receiver is asked to draw
+    const FONT_SCALES: (f64, f64) = (0.25, 4.0);
+
+    /// Check the style is one the receiver will accept.
+    /// ### Returns
+    /// What is wrong with the style, if anything
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(scale) = self.font_scale {
+            let (min, max) = Self::FONT_SCALES;
+            if !(min..=max).contains(&scale) {
+                return Err(format!("Font scale {} is outside of {} to {}.", scale, min, max));
+            }
+        }
+        let colors = [&self.foreground_color, &self.background_color, &self.edge_color, &self.window_color];
+        for color in colors.iter().filter_map(|color| color.as_ref()) {
+            let hex = color.strip_prefix('#').unwrap_or_default();
+            if hex.len() != 8 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("Colour {:?} isn't of the form #RRGGBBAA.", color));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Kinds of edges drawn around subtitle text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum EdgeType {
+    None,
+    Outline,
+    DropShadow,
+    Raised,
+    Depressed,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LoadRequest<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    request_id: i32,
+    session_id: &'a str,
+    media: &'a MediaInformation,
+    current_time: f64,
+    autoplay: bool,
+    active_track_ids: &'a [u32],
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EditTracksInfoRequest<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    request_id: i32,
+    media_session_id: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    active_track_ids: Option<&'a [u32]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text_track_style: Option<&'a TextTrackStyle>,
+}
+
+/// A status or playback request to the media app.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MediaRequest {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    request_id: i32,
+    /// `None` asks for the status of every media session
+    #[serde(skip_serializing_if = "Option::is_none")]
+    media_session_id: Option<i32>,
+    /// Seconds to seek to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current_time: Option<f32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReceiverRequest {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    request_id: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    app_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    volume: Option<VolumeRequest>,
+}
+
+#[derive(Serialize)]
+struct VolumeRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    level: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    muted: Option<bool>,
+}
+
+/// The stream rust_cast's message manager writes messages to. They are
+/// handed to the connection's thread, which owns the TLS stream, to send.
+pub struct Outgoing {
+    messages: mpsc::Sender<Vec<u8>>,
+    /// Written to after each message, to wake the thread from waiting on the chromecast
+    wake: UnixStream,
+}
+
+impl Write for Outgoing {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.messages.send(buf.to_vec()).map_err(|_| connection_closed())?;
+        match self.wake.write(&[0]) {
+            // A full socket means the thread is already due to wake
+            Err(err) if err.kind() != io::ErrorKind::WouldBlock => return Err(err),
+            _ => {}
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for Outgoing {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Messages are read by the connection's thread"))
+    }
+}
+
+/// A connection to a chromecast. The TLS stream is owned by a thread of its
+/// own, which sends the messages requests queue and hands each reply to the
+/// request waiting on it, by request id. Everything else the chromecast
+/// says, e.g. pings and the statuses it pushes, is kept for `try_receive`.
+/// Requests don't wait on each other or on `try_receive`.
+///
+/// rust_cast's `CastDevice` isn't used, as its message manager holds one lock
+/// over the stream while it blocks reading, so a request can only be sent
+/// between reads, and whoever reads takes every message, including the
+/// replies other threads are waiting on. Its channels still build and parse
+/// the messages, only the stream underneath is replaced.
+pub struct Device<'a> {
+    /// Frames messages and queues them for the connection's thread
+    message_manager: Arc<MessageManager<Outgoing>>,
+    pending: Pending,
+    /// Messages that aren't replies to a request
+    messages: Mutex<mpsc::Receiver<CastMessage>>,
+    pub connection: ConnectionChannel<'a, Outgoing>,
+    pub heartbeat: HeartbeatChannel<'a, Outgoing>,
+    /// Only used to parse messages, as their requests read from the stream themselves
+    media: MediaChannel<'a, Outgoing>,
+    receiver: ReceiverChannel<'a, Outgoing>,
+}
+
+impl<'a> Device<'a> {
+    /// Open a connection to a chromecast. Chromecasts present self-signed
+    /// certificates, so the host isn't verified.
+    /// ### Arguments
+    /// - `host` - Address of the chromecast
+    /// - `port` - Port of the cast protocol, usually 8009
+    pub fn connect(host: &str, port: u16) -> Result<Self, CastError> {
+        let mut builder = SslConnector::builder(SslMethod::tls()).map_err(RustCastError::from)?;
+        builder.set_verify(SslVerifyMode::NONE);
+        let connector = builder.build();
+
+        let addr = (host, port).to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Device address didn't resolve"))?;
+        let tcp_stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+        tcp_stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        tcp_stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        // Requests are small and waited on, so they shouldn't be held back to batch them
+        tcp_stream.set_nodelay(true)?;
+        let stream = connector.connect(host, tcp_stream).map_err(RustCastError::from)?;
+        // Once connected, the thread only reads what has arrived
+        stream.get_ref().set_nonblocking(true)?;
+        let (wake, wake_rx) = UnixStream::pair()?;
+        wake.set_nonblocking(true)?;
+        wake_rx.set_nonblocking(true)?;
+
+        let (outgoing_tx, outgoing_rx) = mpsc::channel();
+        let (messages_tx, messages_rx) = mpsc::channel();
+        let pending = Pending::default();
+        let thread_pending = pending.clone();
+        thread::spawn(move || run_connection(stream, outgoing_rx, wake_rx, thread_pending, messages_tx));
+
+        let message_manager = Arc::new(MessageManager::new(Outgoing { messages: outgoing_tx, wake }));
+        Ok(Self {
+            connection: ConnectionChannel::new(SENDER_ID, message_manager.clone()),
+            heartbeat: HeartbeatChannel::new(SENDER_ID, DESTINATION_ID, message_manager.clone()),
+            media: MediaChannel::new(SENDER_ID, message_manager.clone()),
+            receiver: ReceiverChannel::new(SENDER_ID, DESTINATION_ID, message_manager.clone()),
+            message_manager,
+            pending,
+            messages: Mutex::new(messages_rx),
+        })
+    }
+
+    /// Wait a while for a message from the chromecast that isn't a reply to
+    /// a request, parsed by whichever channel it belongs to.
+    /// ### Returns
+    /// `None` if no message arrived within `timeout`
+    pub fn try_receive(&self, timeout: Duration) -> Result<Option<ChannelMessage>, CastError> {
+        let message = match self.messages.lock().unwrap().recv_timeout(timeout) {
+            Ok(message) => message,
+            Err(RecvTimeoutError::Timeout) => return Ok(None),
+            Err(RecvTimeoutError::Disconnected) => return Err(connection_closed().into()),
+        };
+
+        let message = if self.connection.can_handle(&message) {
+            ChannelMessage::Connection(self.connection.parse(&message)?)
+        } else if self.heartbeat.can_handle(&message) {
+            ChannelMessage::Heartbeat(self.heartbeat.parse(&message)?)
+        } else if self.media.can_handle(&message) {
+            ChannelMessage::Media(self.media.parse(&message)?)
+        } else if self.receiver.can_handle(&message) {
+            ChannelMessage::Receiver(self.receiver.parse(&message)?)
+        } else {
+            ChannelMessage::Raw(message)
+        };
+        Ok(Some(message))
+    }
+
+    /// Ask the receiver which apps are running, and what its volume is.
+    pub fn receiver_status(&self) -> Result<receiver::Status, CastError> {
+        self.receiver_request("GET_STATUS", None, None)
+    }
+
+    /// Launch an app on the receiver.
+    /// ### Returns
+    /// The app, once it is running
+    pub fn launch_app(&self, app: &CastDeviceApp) -> Result<Application, CastError> {
+        let app_id = app.to_string();
+        let status = self.receiver_request("LAUNCH", Some(app_id.clone()), None)?;
+        status.applications.into_iter()
+            .find(|app| app.app_id == app_id)
+            .ok_or_else(|| RustCastError::Internal("The launched app isn't running.".into()).into())
+    }
+
+    /// Change the volume level of the receiver, whether it is muted, or both.
+    /// ### Returns
+    /// The volume the receiver settled on
+    pub fn set_volume(&self, volume: impl Into<Volume>) -> Result<Volume, CastError> {
+        let volume = volume.into();
+        let volume = VolumeRequest { level: volume.level, muted: volume.muted };
+        Ok(self.receiver_request("SET_VOLUME", None, Some(volume))?.volume)
+    }
+
+    /// Ask the media app what it is playing.
+    /// ### Arguments
+    /// - `destination` - Transport id of the media app
+    pub fn media_status(&self, destination: &str) -> Result<Status, CastError> {
+        self.media_request(destination, "GET_STATUS", None, None)
+    }
+
+    /// Play the loaded media.
+    /// ### Arguments
+    /// - `destination` - Transport id of the media app
+    /// - `media_session_id` - Id of the loaded media, as in its status
+    /// ### Returns
+    /// The status of the media, `None` if its session has ended
+    pub fn play(&self, destination: &str, media_session_id: i32) -> Result<Option<StatusEntry>, CastError> {
+        self.playback_request(destination, "PLAY", media_session_id, None)
+    }
+
+    /// Pause the loaded media. See `play`.
+    pub fn pause(&self, destination: &str, media_session_id: i32) -> Result<Option<StatusEntry>, CastError> {
+        self.playback_request(destination, "PAUSE", media_session_id, None)
+    }
+
+    /// Stop the loaded media, which ends its session. See `play`.
+    pub fn stop(&self, destination: &str, media_session_id: i32) -> Result<Option<StatusEntry>, CastError> {
+        self.playback_request(destination, "STOP", media_session_id, None)
+    }
+
+    /// Seek the loaded media to `current_time` seconds, leaving it playing or
+    /// paused. See `play`.
+    pub fn seek(&self, destination: &str, media_session_id: i32, current_time: f32)
+        -> Result<Option<StatusEntry>, CastError> {
+        self.playback_request(destination, "SEEK", media_session_id, Some(current_time))
+    }
+
+    /// Load media on the media app.
+    /// ### Arguments
+    /// - `destination` - Transport id of the media app
+    /// - `session_id` - Session id of the media app
+    /// - `media` - The media to load
+    /// - `current_time` - Seconds into the media to start playback at
+    /// - `active_track_ids` - Ids of the tracks to turn on, e.g. subtitles
+    /// ### Returns
+    /// The status of the media once it has loaded
+    pub fn load(&self, destination: &str, session_id: &str, media: &MediaInformation,
+        current_time: f64, active_track_ids: &[u32]) -> Result<Status, CastError> {
+        let request_id = self.message_manager.generate_request_id();
+        let reply = self.request(MEDIA_NAMESPACE, destination, request_id, &LoadRequest {
+            kind: "LOAD",
+            request_id,
+            session_id,
+            media,
+            current_time,
+            autoplay: true,
+            active_track_ids,
+        })?;
+        self.media_reply(&reply)
+    }
+
+    /// Change which tracks of the loaded media are on, or how the receiver
+    /// draws its text tracks. Playback carries on where it is.
+    /// ### Arguments
+    /// - `destination` - Transport id of the media app
+    /// - `media_session_id` - Id of the loaded media, as in its status
+    /// - `active_track_ids` - Ids of the tracks to turn on, every other is
+    ///     turned off. `None` leaves the tracks as they are.
+    /// - `style` - The style to draw text tracks with, `None` to keep the current one
+    pub fn edit_tracks_info(&self, destination: &str, media_session_id: i32,
+        active_track_ids: Option<&[u32]>, style: Option<&TextTrackStyle>) -> Result<Status, CastError> {
+        let request_id = self.message_manager.generate_request_id();
+        let reply = self.request(MEDIA_NAMESPACE, destination, request_id, &EditTracksInfoRequest {
+            kind: "EDIT_TRACKS_INFO",
+            request_id,
+            media_session_id,
+            active_track_ids,
+            text_track_style: style,
+        })?;
+        self.media_reply(&reply)
+    }
+
+    fn receiver_request(&self, kind: &'static str, app_id: Option<String>, volume: Option<VolumeRequest>)
+        -> Result<receiver::Status, CastError> {
+        let request_id = self.message_manager.generate_request_id();
+        let reply = self.request(RECEIVER_NAMESPACE, DESTINATION_ID, request_id, &ReceiverRequest {
+            kind,
+            request_id,
+            app_id,
+            volume,
+        })?;
+
+        let err = match self.receiver.parse(&reply)? {
+            ReceiverResponse::Status(status) => return Ok(status),
+            ReceiverResponse::LaunchError(err) => {
+                format!("Could not launch app: {}.", err.reason.unwrap_or_default())
+            }
+            ReceiverResponse::InvalidRequest(err) => {
+                format!("Invalid request: {}.", err.reason.unwrap_or_default())
+            }
+            ReceiverResponse::NotImplemented(kind, _) => format!("Unexpected reply: {}.", kind),
+        };
+        Err(RustCastError::Internal(err).into())
+    }
+
+    fn media_request(&self, destination: &str, kind: &'static str, media_session_id: Option<i32>,
+        current_time: Option<f32>) -> Result<Status, CastError> {
+        let request_id = self.message_manager.generate_request_id();
+        let reply = self.request(MEDIA_NAMESPACE, destination, request_id, &MediaRequest {
+            kind,
+            request_id,
+            media_session_id,
+            current_time,
+        })?;
+        self.media_reply(&reply)
+    }
+
+    fn playback_request(&self, destination: &str, kind: &'static str, media_session_id: i32,
+        current_time: Option<f32>) -> Result<Option<StatusEntry>, CastError> {
+        let status = self.media_request(destination, kind, Some(media_session_id), current_time)?;
+        Ok(status.entries.into_iter().find(|entry| entry.media_session_id == media_session_id))
+    }
+
+    /// Read the reply to a media request: the new status, or why the
+    /// receiver turned the request down.
+    fn media_reply(&self, reply: &CastMessage) -> Result<Status, CastError> {
+        let err = match self.media.parse(reply)? {
+            MediaResponse::Status(status) => return Ok(status),
+            MediaResponse::LoadFailed(_) => "Failed to load media.".to_string(),
+            MediaResponse::LoadCancelled(_) => "Load cancelled by another request.".to_string(),
+            MediaResponse::InvalidPlayerState(_) => "Invalid player state.".to_string(),
+            MediaResponse::InvalidRequest(err) => {
+                format!("Invalid request: {}.", err.reason.unwrap_or_default())
+            }
+            MediaResponse::NotImplemented(kind, _) => format!("Unexpected reply: {}.", kind),
+        };
+        Err(RustCastError::Internal(err).into())
+    }
+
+    /// Send a request and wait for the chromecast to answer it.
+    /// ### Arguments
+    /// - `namespace` - Namespace of the channel the request is on
+    /// - `destination` - `receiver-0`, or the transport id of an app
+    /// - `request_id` - Id the request is sent with, which its reply carries
+    /// ### Returns
+    /// The reply, or an error if none came within `REQUEST_TIMEOUT`
+    fn request<T: Serialize>(&self, namespace: &str, destination: &str, request_id: i32, request: &T)
+        -> Result<CastMessage, CastError> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        self.pending.lock().unwrap().insert(request_id, reply_tx);
+
+        let reply = self.send(namespace, destination, request).and_then(|_| {
+            match reply_rx.recv_timeout(REQUEST_TIMEOUT) {
+                Ok(reply) => Ok(reply),
+                // A device that drops off the network never replies
+                Err(RecvTimeoutError::Timeout) => {
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "The device didn't answer in time").into())
+                }
+                Err(RecvTimeoutError::Disconnected) => Err(connection_closed().into()),
+            }
+        });
+        self.pending.lock().unwrap().remove(&request_id);
+        reply
+    }
+
+    fn send<T: Serialize>(&self, namespace: &str, destination: &str, message: &T) -> Result<(), CastError> {
+        let payload = serde_json::to_string(message).map_err(RustCastError::from)?;
+        self.message_manager.send(CastMessage {
+            namespace: namespace.to_string(),
+            source: SENDER_ID.to_string(),
+            destination: destination.to_string(),
+            payload: CastMessagePayload::String(payload),
+        })?;
+        Ok(())
+    }
+}
+
+/// Run the connection of a device: send what its requests queue, and read
+/// what the chromecast says, until the device is dropped or the connection
+/// breaks. Requests still waiting on a reply then find the connection closed.
+/// The thread sleeps until the chromecast sends something or `wake` is
+/// written to, so it costs nothing while the cast is idle.
+fn run_connection(mut stream: Stream, outgoing: mpsc::Receiver<Vec<u8>>, mut wake: UnixStream,
+    pending: Pending, messages: mpsc::Sender<CastMessage>) {
+    // What has arrived of messages that haven't been taken off yet
+    let mut buffer = Vec::new();
+    let result = 'connection: loop {
+        match send_queued(&mut stream, &outgoing) {
+            Ok(true) => {}
+            // The device was dropped
+            Ok(false) => break Ok(()),
+            Err(err) => break Err(err),
+        }
+
+        let (readable, woken) = match wait(&stream, &wake) {
+            Ok(ready) => ready,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => break Err(err),
+        };
+        if woken {
+            let mut drained = [0; 64];
+            while matches!(wake.read(&mut drained), Ok(read) if read > 0) {}
+        }
+        if !readable {
+            continue;
+        }
+
+        // Read whatever has arrived. A record cut short is kept by the TLS
+        // stream, and a message cut short by the buffer, for the next read.
+        let mut chunk = [0; 4096];
+        loop {
+            match stream.read(&mut chunk) {
+                Ok(0) => break 'connection Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                    "The device closed the connection")),
+                Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => break 'connection Err(err),
+            }
+        }
+
+        while let Some(message) = next_message(&mut buffer) {
+            let message = match message {
+                Ok(message) => message,
+                // Only the one message is lost, the next starts where it ended
+                Err(err) => {
+                    log::warn!("[Device] Message could not be read: {:?}", err);
+                    continue;
+                }
+            };
+            let reply_tx = request_id(&message).and_then(|id| pending.lock().unwrap().remove(&id));
+            let _ = match reply_tx {
+                Some(reply_tx) => reply_tx.send(message),
+                None => messages.send(message),
+            };
+        }
+    };
+
+    if let Err(err) = result {
+        log::warn!("[Device] Connection closed: {:?}", err);
+    }
+    pending.lock().unwrap().clear();
+}
+
+/// Send the messages queued by requests. The socket is made blocking while
+/// they are written, as a TLS write that can't finish has to be retried as is.
+/// ### Returns
+/// `false` once the device is dropped and nothing more can be queued
+fn send_queued(stream: &mut Stream, outgoing: &mpsc::Receiver<Vec<u8>>) -> io::Result<bool> {
+    let mut queued = Vec::new();
+    let connected = loop {
+        match outgoing.try_recv() {
+            Ok(bytes) => queued.push(bytes),
+            Err(TryRecvError::Empty) => break true,
+            Err(TryRecvError::Disconnected) => break false,
+        }
+    };
+    if !queued.is_empty() {
+        // rust_cast writes a message's length and body separately, they go out as one record
+        stream.get_ref().set_nonblocking(false)?;
+        stream.write_all(&queued.concat())?;
+        stream.get_ref().set_nonblocking(true)?;
+    }
+    Ok(connected)
+}
+
+/// Sleep until the chromecast sends something or the thread is woken.
+/// ### Returns
+/// Whether the stream can be read and whether `wake` was written to
+fn wait(stream: &Stream, wake: &UnixStream) -> io::Result<(bool, bool)> {
+    let mut fds = [
+        libc::pollfd { fd: stream.get_ref().as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        libc::pollfd { fd: wake.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+    ];
+    // Decrypted data left over from the last read doesn't show on the socket
+    let timeout = if stream.ssl().pending() > 0 { 0 } else { -1 };
+    if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((fds[0].revents != 0 || stream.ssl().pending() > 0, fds[1].revents != 0))
+}
+
+/// Take the next message off the front of what has been read, once the
+/// whole of it has arrived. Messages are framed by their length, as a big
+/// endian `u32`.
+fn next_message(buffer: &mut Vec<u8>) -> Option<Result<CastMessage, RustCastError>> {
+    let length = u32::from_be_bytes(buffer.get(..4)?.try_into().ok()?) as usize;
+    if buffer.len() < 4 + length {
+        return None;
+    }
+    let frame: Vec<u8> = buffer.drain(..4 + length).collect();
+    // rust_cast only decodes messages from a stream, so it is given one holding just this message
+    Some(MessageManager::new(Cursor::new(frame)).receive())
+}
+
+/// Returns the id of the request a message answers, `None` for messages the
+/// chromecast sends of its own accord.
+fn request_id(message: &CastMessage) -> Option<i32> {
+    let payload = match &message.payload {
+        CastMessagePayload::String(payload) => payload,
+        CastMessagePayload::Binary(_) => return None,
+    };
+    let payload: serde_json::Value = serde_json::from_str(payload).ok()?;
+    payload.get("requestId")?
+        .as_i64()
+        .map(|id| id as i32)
+        .filter(|id| *id != 0)
+}
+
+fn connection_closed() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "The connection to the device is closed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{
+        asn1::Asn1Time, hash::MessageDigest, pkey::PKey, rsa::Rsa,
+        ssl::{SslAcceptor, SslAcceptorBuilder}, x509::{X509, X509NameBuilder},
+    };
+    use std::net::TcpListener;
+
+    /// A TLS acceptor with a throwaway self-signed certificate, like a chromecast's.
+    fn acceptor() -> SslAcceptorBuilder {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&cert.build()).unwrap();
+        acceptor
+    }
+
+    fn message(namespace: &str, payload: String) -> CastMessage {
+        CastMessage {
+            namespace: namespace.into(),
+            source: DESTINATION_ID.into(),
+            destination: SENDER_ID.into(),
+            payload: CastMessagePayload::String(payload),
+        }
+    }
+
+    #[test]
+    fn replies_reach_their_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let acceptor = acceptor().build();
+        // Answers every request with a receiver status, after pinging first
+        thread::spawn(move || {
+            let stream = acceptor.accept(listener.accept().unwrap().0).unwrap();
+            let manager = MessageManager::new(stream);
+            while let Ok(request) = manager.receive() {
+                let id = request_id(&request).unwrap();
+                manager.send(message("urn:x-cast:com.google.cast.tp.heartbeat", r#"{"type":"PING"}"#.into())).unwrap();
+                manager.send(message(RECEIVER_NAMESPACE, format!(
+                    r#"{{"type":"RECEIVER_STATUS","requestId":{},"status":{{"applications":[],"volume":{{"level":{}}}}}}}"#,
+                    id, f64::from(id) / 100.0))).unwrap();
+            }
+        });
+
+        let device = Device::connect("127.0.0.1", port).unwrap();
+        for _ in 0..3 {
+            device.receiver_status().unwrap();
+        }
+        let volume = device.set_volume(0.5).unwrap();
+        // Request ids count up from 2, so the fourth request is answered with 0.05
+        assert_eq!(volume.level, Some(0.05));
+
+        for _ in 0..4 {
+            let ping = device.try_receive(Duration::from_secs(1)).unwrap();
+            assert!(matches!(ping, Some(ChannelMessage::Heartbeat(_))));
+        }
+        assert!(device.try_receive(Duration::from_millis(50)).unwrap().is_none());
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Debug)]
 pub enum CastError {
     RustCastError(rust_cast::errors::Error),
@@ -27,3 +29,16 @@ impl From<mdns::Error> for CastError {
         CastError::MDNSError(err)
    }
 }
+
+impl fmt::Display for CastError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CastError::RustCastError(err) => write!(f, "Cast protocol failed: {}", err),
+            CastError::IoError(err) => write!(f, "I/O failed: {}", err),
+            CastError::HyperError(err) => write!(f, "HTTP request failed: {}", err),
+            CastError::MDNSError(err) => write!(f, "Device discovery failed: {}", err),
+            CastError::ServerError => write!(f, "The media server failed"),
+            CastError::CasterError(message) => write!(f, "{}", message),
+        }
+    }
+}
@@ -1,18 +1,27 @@
 #![allow(dead_code, unused_variables)]
+pub mod device;
 pub mod error;
+pub mod status;
 
+use crate::{api, video_encoding::Chromecast};
 use error::CastError;
 use mdns::{Record, RecordKind};
 use futures_util::{pin_mut, stream::StreamExt};
+use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{Serialize, ser::SerializeStruct};
 use warp::hyper::{Client, body::HttpBody};
-use std::{future, net::{IpAddr, UdpSocket}, sync::{mpsc::{Sender, TryRecvError}, Mutex, Arc}, thread, time::{SystemTime, Duration}};
-use rust_cast::{CastDevice, ChannelMessage, channels::media::MediaResponse};
+use std::{future, net::{IpAddr, UdpSocket}, thread, time::{Duration, Instant, SystemTime}};
+use std::sync::{mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError}, Arc, Mutex};
+use tokio::sync::broadcast;
+use device::{Device, TextTrackStyle};
+use status::DeviceStatus;
+use rust_cast::{ChannelMessage, channels::media::MediaResponse, errors::Error as RustCastError};
 use rust_cast::channels::{
+    connection::ConnectionResponse,
     heartbeat::HeartbeatResponse,
-    media::{Media, StatusEntry, StreamType},
-    receiver::CastDeviceApp,
+    media::{PlayerState, StatusEntry},
+    receiver::{CastDeviceApp, ReceiverResponse, Volume},
 };
 
 pub type Error = error::CastError;
@@ -20,17 +29,32 @@ pub type Error = error::CastError;
 const DESTINATION_ID: &'static str = "receiver-0";
 const SERVICE_NAME: &'static str = "_googlecast._tcp.local";
 const TIMEOUT_SECONDS: u64 = 3;
-const STATUS_UPDATE_INTERVAL: u128 = 500;
+/// How long the device may go without pushing a media status, while no media
+/// is playing, before it is asked for one
+const STATUS_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How long the comm thread waits for a message before checking whether the cast was closed
+const RECEIVE_TIMEOUT: Duration = Duration::from_millis(200);
+/// How long the device may go without sending anything before the connection is taken to be dead
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
+/// Attempts at reconnecting to a device before the cast is given up on
+const MAX_RECONNECT_ATTEMPTS: u32 = 10;
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// How much the volume up and down signals change the volume by
+pub const VOLUME_STEP: f32 = 0.05;
+/// Elements of a chromecast's device description, `/ssdp/device-desc.xml`
+static FRIENDLY_NAME: Lazy<Regex> = Lazy::new(|| Regex::new(r"<friendlyName>(.*)</friendlyName>").unwrap());
+static MODEL_NAME: Lazy<Regex> = Lazy::new(|| Regex::new(r"<modelName>(.*)</modelName>").unwrap());
 
 /// An enum containing useful playback info for the caster, can be serialized.
 #[derive(Debug, Clone)]
 pub enum MediaStatus {
-    Active(StatusEntry),
+    /// Status of the loaded media, and the volume of the device once it is known
+    Active(StatusEntry, Option<Volume>),
     Inactive,
 }
 // Convert from rust-cast StatusEntry type into mucaster MediaStatus
 impl From<StatusEntry> for MediaStatus {
-    fn from(entry: StatusEntry) -> Self { MediaStatus::Active(entry) }
+    fn from(entry: StatusEntry) -> Self { MediaStatus::Active(entry, None) }
 }
 // Serializing is implemented to transmit playback data over HTTP
 impl Serialize for MediaStatus {
@@ -43,27 +67,160 @@ impl Serialize for MediaStatus {
                 state.serialize_field("playbackState", "Inactive").unwrap();
                 state.end()
             }
-            MediaStatus::Active(entry) => {    
+            MediaStatus::Active(entry, volume) => {    
+                let duration = entry.media.as_ref().and_then(|media| media.duration);
+                let level = volume.and_then(|volume| volume.level);
+                let muted = volume.and_then(|volume| volume.muted);
                 let mut num_fields = 1;
                 if entry.current_time.is_some() { num_fields += 1; }
-                if let Some(media) = &entry.media { 
-                    if media.duration.is_some() { num_fields += 1; }
-                }
+                if duration.is_some() { num_fields += 1; }
+                if level.is_some() { num_fields += 1; }
+                if muted.is_some() { num_fields += 1; }
 
                 state = serializer.serialize_struct("status", num_fields).unwrap();
                 state.serialize_field("playbackState", &entry.player_state.to_string()).unwrap();
-                if let Some(media) = &entry.media {
-                    state.serialize_field("videoLength", &media.duration.unwrap()).unwrap();
+                if let Some(duration) = duration {
+                    state.serialize_field("videoLength", &duration).unwrap();
                 }
                 if let Some(time) = &entry.current_time {
                     state.serialize_field("currentTime", time).unwrap();
                 }
+                if let Some(level) = level {
+                    state.serialize_field("volumeLevel", &level).unwrap();
+                }
+                if let Some(muted) = muted {
+                    state.serialize_field("muted", &muted).unwrap();
+                }
                 state.end()
             }
         }
     }
 }
 
+/// A WebVTT subtitle track on the media server. Text tracks are attached to
+/// the media when it is loaded, so they can be turned on from the TV.
+#[derive(Debug, Clone)]
+pub struct TextTrack {
+    /// Id of the subtitle track within the library entry
+    pub id: usize,
+    /// The path of the track on the media server, e.g. `media/3/subtitles/0.vtt`
+    pub path: String,
+    pub name: String,
+    pub language: Option<String>,
+}
+impl TextTrack {
+    /// The id the receiver knows the track by. Receivers expect track ids
+    /// to start at 1.
+    pub fn track_id(&self) -> u32 {
+        self.id as u32 + 1
+    }
+}
+
+/// What the media of a cast is. The receiver shows it while the media loads,
+/// and apps following the cast, such as Google Home, show it as playing.
+#[derive(Debug, Clone)]
+pub struct MediaDescription {
+    pub metadata: device::MediaMetadata,
+    /// The path of a poster of the media on the media server, e.g. `media/3/poster`
+    pub poster_path: Option<String>,
+}
+
+/// Media being cast, as given to `Caster::begin_cast`, and how its subtitles
+/// are shown. It is kept to load the media again when its subtitles change,
+/// or when the media app has to be launched again after the connection drops.
+#[derive(Debug, Clone)]
+struct CastMedia {
+    media_port: u16,
+    media_path: String,
+    content_type: String,
+    text_tracks: Vec<TextTrack>,
+    active_text_track: Option<usize>,
+    /// Seconds the subtitles are shifted by
+    subtitle_offset: f64,
+    /// How the receiver draws subtitles, its defaults if `None`
+    text_track_style: Option<TextTrackStyle>,
+    description: MediaDescription,
+}
+impl CastMedia {
+    /// Describe the media to the receiver, served from this machine.
+    /// ### Arguments
+    /// * local_ip - The address the media server is reached at
+    fn information(&self, local_ip: &str) -> device::MediaInformation {
+        let media_url = |path: &str| format!("http://{}:{}/{}", local_ip, self.media_port, path);
+        let tracks = self.text_tracks.iter()
+            .map(|track| {
+                // A different URL also keeps the receiver from reusing the unshifted track
+                let path = match self.subtitle_offset {
+                    offset if offset != 0.0 => format!("{}?offset={}", track.path, offset),
+                    _ => track.path.clone(),
+                };
+                device::Track::subtitles(
+                    track.track_id(),
+                    media_url(&path),
+                    track.name.clone(),
+                    track.language.clone())
+            })
+            .collect();
+        let metadata = device::MediaMetadata {
+            images: self.description.poster_path.iter()
+                .map(|path| device::Image { url: media_url(path) })
+                .collect(),
+            ..self.description.metadata.clone()
+        };
+
+        device::MediaInformation {
+            content_id: media_url(&self.media_path),
+            content_type: self.content_type.clone(),
+            stream_type: "BUFFERED",
+            tracks,
+            text_track_style: self.text_track_style.clone(),
+            metadata: Some(metadata),
+        }
+    }
+
+    /// Returns the receiver's ids of the tracks to turn on.
+    fn active_track_ids(&self) -> Vec<u32> {
+        self.text_tracks.iter()
+            .filter(|track| Some(track.id) == self.active_text_track)
+            .map(TextTrack::track_id)
+            .collect()
+    }
+}
+
+/// The state of the connection to the chromecast, can be serialized.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "camelCase")]
+pub enum ConnectionState {
+    /// No cast is running
+    Disconnected,
+    /// Connecting to the chromecast to start a cast
+    Connecting,
+    Connected,
+    /// The connection dropped and is being made again
+    Reconnecting { attempt: u32 },
+}
+
+/// The connection to the chromecast of a cast. It is made by the comm thread
+/// and shared with control commands, whose requests go over it while the
+/// comm thread waits for what the device pushes.
+struct Connection {
+    device: Device<'static>,
+    /// Transport id of the media app
+    transport_id: String,
+    /// Session id of the media app
+    session_id: String,
+}
+
+/// What the comm thread of a cast shares with the `Caster`. Each cast gets
+/// its own, so a closing thread can't touch the next one's.
+struct CastState {
+    /// `None` while the comm thread is connecting
+    connection: Mutex<Option<Arc<Connection>>>,
+    connection_state: Mutex<ConnectionState>,
+    /// The media, kept up to date for the comm thread to load after a drop
+    media: Mutex<CastMedia>,
+}
+
 enum PlayerSignal {
     Play,
     Pause,
@@ -75,6 +232,12 @@ pub struct Caster {
     device_addr: Option<String>,
     shutdown_tx: Option<Sender<()>>,
     pub status: Arc<Mutex<MediaStatus>>,
+    /// Changes of the media status and connection are pushed here
+    events_tx: broadcast::Sender<api::Event>,
+    /// State of the last cast, shared with its comm thread
+    cast: Option<Arc<CastState>>,
+    /// How subtitles are drawn, kept from one cast to the next
+    text_track_style: Option<TextTrackStyle>,
 }
 impl Drop for Caster {
     fn drop(&mut self) {
@@ -82,11 +245,16 @@ impl Drop for Caster {
     }
 }
 impl Caster {
-    pub fn new() -> Self {
+    /// Creates a caster that pushes changes of the media status and of the
+    /// connection to the chromecast to `events_tx`.
+    pub fn new(events_tx: broadcast::Sender<api::Event>) -> Self {
         Self {
             device_addr: None,
             shutdown_tx: None,
             status: Arc::from(Mutex::from(MediaStatus::Inactive)),
+            events_tx,
+            cast: None,
+            text_track_style: None,
         }
     }
     
@@ -104,6 +272,34 @@ impl Caster {
         self.device_addr.is_some() && is_active
     }
 
+    /// Returns the state of the connection to the chromecast.
+    pub fn connection_state(&self) -> ConnectionState {
+        match &self.cast {
+            Some(cast) => *cast.connection_state.lock().unwrap(),
+            None => ConnectionState::Disconnected,
+        }
+    }
+
+    /// Ask the chromecast what it is doing, whether or not this caster started
+    /// it. Without a cast running, a connection is opened just to ask.
+    pub fn device_status(&self) -> Result<DeviceStatus, CastError> {
+        let connected = self.cast.as_ref()
+            .map_or(false, |cast| cast.connection.lock().unwrap().is_some());
+        if connected {
+            return self.with_connection(|connection| DeviceStatus::query(&connection.device));
+        }
+
+        let addr = match &self.device_addr {
+            Some(addr) => addr,
+            None => return Err(CastError::CasterError("No device address selected.")),
+        };
+        let device = Device::connect(addr, 8009)?;
+        device.connection.connect(DESTINATION_ID)?;
+        let status = DeviceStatus::query(&device);
+        let _ = device.connection.disconnect(DESTINATION_ID);
+        status
+    }
+
     /// Set the target chromecast IP address to use.
     pub fn set_device_addr(&mut self, addr: &str) {
         self.device_addr = Some(addr.into());
@@ -113,7 +309,9 @@ impl Caster {
     /// if possible.  
     pub fn close(&mut self) {
         if self.is_streaming() {
-            self.stop().unwrap();
+            if let Err(err) = self.stop() {
+                log::warn!("[Chromecast] Failed to stop playback: {:?}", err);
+            }
         }
         // Send a shutdown signal to the keep-alive thread
         if let Some(sender) = &self.shutdown_tx {
@@ -123,8 +321,19 @@ impl Caster {
     }
 
     /// Open a new connection with the Chromecast. An event loop thread will be
-    /// spawned to manage keep alive and poll for media status updates.
-    pub fn begin_cast(&mut self, media_port: u16) -> Result<(), CastError> {
+    /// spawned to manage keep alive and follow the media status. The
+    /// connection is kept open for control commands until the next cast, and
+    /// made again if it drops.
+    /// ### Arguments
+    /// * media_port - The port the local media server is hosted on
+    /// * media_path - The path of the media on the media server, e.g. `media/3`
+    /// * content_type - The MIME type of the media
+    /// * description - What the media is, e.g. its title and poster
+    /// * text_tracks - Subtitles to attach to the media
+    /// * active_text_track - Id of the subtitle track to show from the start, if any
+    pub fn begin_cast(&mut self, media_port: u16, media_path: &str, content_type: &str,
+        description: MediaDescription, text_tracks: Vec<TextTrack>, active_text_track: Option<usize>) 
+        -> Result<(), CastError> {
         // Ensure there is a device to cast to
         let addr = match &self.device_addr {
             Some(addr) => addr.clone(),
@@ -132,160 +341,91 @@ impl Caster {
                 return Err(CastError::CasterError("No device address selected."));
             }
         };
+        // Close the comm thread of any previous cast, the new load replaces its media
+        if let Some(sender) = self.shutdown_tx.take() {
+            let _ = sender.send(());
+        }
+        let media = CastMedia {
+            media_port,
+            media_path: media_path.to_string(),
+            content_type: content_type.to_string(),
+            text_tracks,
+            active_text_track,
+            // Subtitles are only out of sync by the same amount within a file
+            subtitle_offset: 0.0,
+            text_track_style: self.text_track_style.clone(),
+            description,
+        };
+        let cast = Arc::new(CastState {
+            connection: Mutex::new(None),
+            connection_state: Mutex::new(ConnectionState::Connecting),
+            media: Mutex::new(media),
+        });
+        self.cast = Some(cast.clone());
+
         // Channel to kill casting
         let (shutdown_tx, shutdown_rx) = std::sync::mpsc::channel::<()>();
         self.shutdown_tx = Some(shutdown_tx);
 
         // Open a thread to handle recieve status updates
-        let status_ref = self.status.clone();
-        let mut last_media_status = SystemTime::now();
-        let mut status_delay = 5000; 
-        let handle = thread::spawn(move || {
-            // Open the device connection
-            let device = CastDevice::connect_without_host_verification(addr, 8009).unwrap();
-            device.connection.connect(DESTINATION_ID).unwrap();
-            log::info!("[Chromecast] Connected to device");
-
-            // Launch the media player on the device
-            let app = device.receiver.launch_app(
-                &CastDeviceApp::DefaultMediaReceiver).unwrap();
-            let transport_id = app.transport_id.to_string();
-            let session_id = app.session_id.to_string();
-            
-            log::info!("[Chromecast] Launched media app.");
-
-            // Connect to the app and begin playback
-            let media_addr = format!("http://{}:{}", 
-                get_local_ip().unwrap(), 
-                media_port);
-            
-            device.connection.connect(&transport_id).unwrap();
-            device.media.load(
-                &transport_id, 
-                &session_id, 
-                &Media {
-                    content_id: media_addr, 
-                    content_type: "video/mp4".to_string(),
-                    stream_type: StreamType::None,
-                    duration: None,
-                    metadata: None,
-                },
-            ).unwrap();
-
-            log::info!("[Chromecast] Loaded media.");
-            
-            // Chromecast communication loop
-            loop { 
-                // Poll the shutdown reciever
-                match shutdown_rx.try_recv() {
-                    Ok(_) | Err(TryRecvError::Disconnected) => {
-                        // Break the thread loop, closing the thread
-                        log::info!("[Chromecast] Closing comm thread.");
-                        return;                        
-                    },
-                    Err(TryRecvError::Empty) => {}
-                }
-
-                // Handle device communication
-                // If this is not done often enough the connection will die
-                // This blocking call is why media control is on a separate 
-                // thread from status updates
-                // TODO utilize rust-cast 1.6 thread_safe, where was that a year ago :P
-                if let Some((ch_msg, msg)) = Caster::handle_device_status(&device){
-                    log::info!("[Device Message] {}", &msg);
-                }
-
-                let millis_since_last = last_media_status
-                    .elapsed().unwrap()
-                    .as_millis();
-
-                // Update media status if it hasn't recently
-                if millis_since_last >= status_delay {
-                    status_delay = STATUS_UPDATE_INTERVAL;
-                    // Retrieve media status
-                    let statuses = match device.media
-                        .get_status(&transport_id, None) {
-                            Ok(statuses) => statuses,
-                            Err(err) => {
-                                log::info!("[Chromecast] Error: {:?}", err);
-                                continue;
-                            },
-                    };
-                    // Map StatusEntry to MediaStatus enum
-                    let status = match statuses.entries.first() {
-                        Some(status) => MediaStatus::Active(status.clone()),
-                        None => MediaStatus::Inactive
-                    };
-                    log::info!("[Chromecast] [Status] {:?}", &status);
-                    *status_ref.lock().unwrap() = status;
-                    last_media_status = SystemTime::now();
-                }
-                
-            }
-        });
+        let comm_thread = CommThread {
+            addr,
+            cast,
+            status: self.status.clone(),
+            events_tx: self.events_tx.clone(),
+            shutdown_rx,
+        };
+        thread::spawn(move || comm_thread.run());
         
         Ok(())
     }
 
-    /// Block until device status is received.  
+    /// Wait a short while for device status.  
     /// The message is parsed into a string, and returned.  
     /// If the message was a Heartbeat, a pong will be returned to the 
     /// chromecast.
     /// ### Returns
     /// - On success: ***Some(Log message as String)***
-    /// - On error: ***None***
-    fn handle_device_status(device: &CastDevice) 
-        -> Option<(ChannelMessage, String)> {
-        match device.receive() {
-            Ok(msg) => {
-                let log_msg: String;
-                match &msg {
+    /// - If nothing was received, or the message couldn't be read: ***None***
+    /// - If the connection is gone: ***Err***
+    fn handle_device_status(device: &Device) 
+        -> Result<Option<(ChannelMessage, String)>, CastError> {
+        match device.try_receive(RECEIVE_TIMEOUT) {
+            Ok(None) => Ok(None),
+            Ok(Some(msg)) => {
+                let log_msg = match &msg {
                     ChannelMessage::Connection(resp) => {
-                        return Some((msg.clone(), 
-                            format!("[Device=>Connection] {:?}", resp)));
+                        format!("[Device=>Connection] {:?}", resp)
                     }
                     ChannelMessage::Media(resp) => {
-                        Self::handle_media_status(resp);
-                        return Some((msg.clone(), 
-                            format!("[Device=>Media] {:?}", resp)));
+                        format!("[Device=>Media] {:?}", resp)
                     }
                     ChannelMessage::Receiver(resp) => {
-                        return Some((msg.clone(), 
-                            format!("[Device=>Receiver] {:?}", resp)));
+                        format!("[Device=>Receiver] {:?}", resp)
                     }
                     ChannelMessage::Raw(resp) => {
-                        return Some((msg.clone(),
-                            format!("[Device] Message could not 
-                                            be parsed: {:?}", resp)));
+                        format!("[Device] Message could not be parsed: {:?}", resp)
                     }
                     ChannelMessage::Heartbeat(resp) => {
                         // Reply to ping with pong
                         if let HeartbeatResponse::Ping = resp {
-                            device.heartbeat.pong().unwrap();
+                            device.heartbeat.pong()?;
                             log::info!("[Heartbeat] Pong sent.");
                         }
-                        return Some((msg.clone(),
-                            (format!("[Heartbeat] {:?}", resp))));
+                        format!("[Heartbeat] {:?}", resp)
                     }
-                }
+                };
+                Ok(Some((msg, log_msg)))
             },
-            // Failed to receive message
+            Err(err) if is_connection_error(&err) => Err(err),
+            // Failed to read a message, the next one may be fine
             Err(err) => {
-                log::error!("An error occured while recieving 
-                            message from chromecast:\n{:?}", err);
-                return None
+                log::error!("An error occured while recieving message from chromecast:\n{:?}", err);
+                Ok(None)
             }
         }
     }
     
-    // TODO this function can likely be deleted and device message media updates ignored
-    fn handle_media_status(resp: &MediaResponse) {
-        let status = match resp {
-            MediaResponse::Status(status) => status.clone(),
-            _=> {return;}
-        };
-    }
-
     /// Resumes playback on chromecast if it is paused.
     pub fn resume(&self) -> Result<(), CastError> {
         self.change_media_state(PlayerSignal::Play)?;
@@ -313,84 +453,555 @@ impl Caster {
         Ok(())
     }
 
+    /// Set the volume of the chromecast.
+    /// ### Arguments
+    /// * level - The volume, from 0.0 for silent to 1.0 for full
+    pub fn set_volume(&self, level: f32) -> Result<(), CastError> {
+        if !(0.0..=1.0).contains(&level) {
+            return Err(CastError::CasterError("Volume must be between 0 and 1."));
+        }
+        self.change_volume(level)?;
+        log::info!("[Chromecast] Set volume to {}.", level);
+        Ok(())
+    }
+
+    /// Mute or unmute the chromecast, keeping its volume level.
+    pub fn set_muted(&self, muted: bool) -> Result<(), CastError> {
+        self.change_volume(muted)?;
+        log::info!("[Chromecast] Set muted to {}.", muted);
+        Ok(())
+    }
+
+    /// Turn the volume of the chromecast up or down from where it is.
+    /// ### Arguments
+    /// * step - How much to add to the volume, negative to turn it down, e.g.
+    ///     `VOLUME_STEP`. The volume stops at silent and full.
+    pub fn step_volume(&self, step: f32) -> Result<(), CastError> {
+        let volume = self.with_connection(|connection| {
+            // The volume may have been changed from the TV since it was last seen
+            let level = connection.device.receiver_status()?.volume.level.unwrap_or(0.0);
+            let volume = connection.device.set_volume((level + step).clamp(0.0, 1.0))?;
+            Ok(volume)
+        })?;
+        update_volume(&self.status, &self.events_tx, volume);
+        log::info!("[Chromecast] Stepped volume to {:?}.", volume.level);
+        Ok(())
+    }
+
+    /// Send a volume change to the chromecast, keeping the volume it settles on.
+    fn change_volume(&self, volume: impl Into<Volume>) -> Result<(), CastError> {
+        let volume = self.with_connection(|connection| {
+            connection.device.set_volume(volume)
+        })?;
+        update_volume(&self.status, &self.events_tx, volume);
+        Ok(())
+    }
+
+    /// Shift the subtitles of the current cast, for subtitles that are out of
+    /// sync. The media is loaded again with the shifted subtitles, carrying
+    /// on from where it was.
+    /// ### Arguments
+    /// * offset - Seconds to show the subtitles later by, negative to show
+    ///     them earlier. Offsets don't add up, each replaces the last.
+    pub fn set_subtitle_offset(&mut self, offset: f64) -> Result<(), CastError> {
+        let mut media = self.current_media()?;
+        if media.text_tracks.is_empty() {
+            return Err(CastError::CasterError("The media has no subtitles."));
+        }
+        media.subtitle_offset = offset;
+
+        self.reload(&media)?;
+        log::info!("[Chromecast] Shifted subtitles by {}s.", offset);
+        self.update_media(media);
+        Ok(())
+    }
+
+    /// Turn a subtitle track of the current cast on, or subtitles off.
+    /// Playback carries on where it is.
+    /// ### Arguments
+    /// * text_track - Id of the subtitle track to show, `None` to hide subtitles
+    pub fn set_active_text_track(&mut self, text_track: Option<usize>) -> Result<(), CastError> {
+        let mut media = self.current_media()?;
+        if let Some(id) = text_track {
+            if !media.text_tracks.iter().any(|track| track.id == id) {
+                return Err(CastError::CasterError("No subtitle track with that id."));
+            }
+        }
+        media.active_text_track = text_track;
+
+        let status = self.with_connection(|connection| {
+            let status = Self::media_status(connection)?;
+            connection.device.edit_tracks_info(&connection.transport_id, status.media_session_id,
+                Some(&media.active_track_ids()), None)
+        })?;
+        self.update_status(status.entries.into_iter().next());
+        log::info!("[Chromecast] Showing subtitle track {:?}.", text_track);
+        self.update_media(media);
+        Ok(())
+    }
+
+    /// Replace the media of the current cast with another version of it,
+    /// e.g. one converted with a different audio track. Subtitles are kept
+    /// and playback carries on from where it was.
+    /// ### Arguments
+    /// * media_path - The path of the media on the media server
+    /// * content_type - The MIME type of the media
+    pub fn switch_media(&mut self, media_path: &str, content_type: &str) -> Result<(), CastError> {
+        let mut media = self.current_media()?;
+        media.media_path = media_path.to_string();
+        media.content_type = content_type.to_string();
+
+        self.reload(&media)?;
+        log::info!("[Chromecast] Switched media to {}.", media_path);
+        self.update_media(media);
+        Ok(())
+    }
+
+    /// Load media again on the media app, at the time the current media is at.
+    fn reload(&self, media: &CastMedia) -> Result<(), CastError> {
+        let information = media.information(&get_local_ip()?);
+        let status = self.with_connection(|connection| {
+            let status = Self::media_status(connection)?;
+            let current_time = status.current_time.unwrap_or(0.0) as f64;
+            connection.device.load(
+                &connection.transport_id,
+                &connection.session_id,
+                &information,
+                current_time,
+                &media.active_track_ids(),
+            )
+        })?;
+        self.update_status(status.entries.into_iter().next());
+        Ok(())
+    }
+
+    /// Change how subtitles are drawn. The style applies to the current cast
+    /// straight away, if there is one, and to every cast after it.
+    pub fn set_text_track_style(&mut self, style: TextTrackStyle) -> Result<(), CastError> {
+        self.text_track_style = Some(style.clone());
+        if let Some(cast) = &self.cast {
+            cast.media.lock().unwrap().text_track_style = Some(style.clone());
+        }
+        if !self.is_streaming() {
+            return Ok(());
+        }
+
+        let status = self.with_connection(|connection| {
+            let status = Self::media_status(connection)?;
+            connection.device.edit_tracks_info(&connection.transport_id, status.media_session_id,
+                None, Some(&style))
+        })?;
+        self.update_status(status.entries.into_iter().next());
+        log::info!("[Chromecast] Set subtitle style: {:?}", &style);
+        Ok(())
+    }
+
+    /// Returns the seconds the current cast's subtitles are shifted by.
+    pub fn subtitle_offset(&self) -> f64 {
+        match &self.cast {
+            Some(cast) => cast.media.lock().unwrap().subtitle_offset,
+            None => 0.0,
+        }
+    }
+
+    /// Returns the style subtitles are drawn with, `None` for the receiver's defaults.
+    pub fn text_track_style(&self) -> Option<&TextTrackStyle> {
+        self.text_track_style.as_ref()
+    }
+
+    /// Returns the media of the current cast, or an error if nothing is playing.
+    fn current_media(&self) -> Result<CastMedia, CastError> {
+        match &self.cast {
+            Some(cast) if self.is_streaming() => Ok(cast.media.lock().unwrap().clone()),
+            _ => Err(CastError::CasterError("No active media.")),
+        }
+    }
+
+    /// Keep a change to the media of the current cast, once the receiver has it.
+    fn update_media(&self, media: CastMedia) {
+        if let Some(cast) = &self.cast {
+            *cast.media.lock().unwrap() = media;
+        }
+    }
+
+    /// Take the media status a command was answered with, so clients see the
+    /// change without waiting for the status the device pushes to every sender.
+    fn update_status(&self, entry: Option<StatusEntry>) {
+        update_status(&self.status, &self.events_tx, entry);
+    }
+
+    /// Run a command on the connection of the current cast.
+    fn with_connection<T>(&self, command: impl FnOnce(&Connection) -> Result<T, CastError>)
+        -> Result<T, CastError> {
+        let cast = match &self.cast {
+            Some(cast) => cast,
+            None => return Err(CastError::CasterError("Not connected to a device.")),
+        };
+        // The lock is only held to take the connection, so commands don't wait on each other
+        let connection = cast.connection.lock().unwrap().clone();
+        match connection {
+            Some(connection) => command(&connection),
+            None => Err(CastError::CasterError("Not connected to a device.")),
+        }
+    }
+
+    /// Ask the media app what it is playing.
+    /// ### Returns
+    /// The status of the media, or an error if nothing is loaded
+    fn media_status(connection: &Connection) -> Result<StatusEntry, CastError> {
+        let media_status = connection.device.media_status(&connection.transport_id)?;
+        match media_status.entries.into_iter().next() {
+            Some(entry) => Ok(entry),
+            None => Err(CastError::CasterError("No active media.")),
+        }
+    }
+
     /// Calls one of the functions that alter the play state
     /// on the current playback. 
     /// ### Arguments
     /// * state - A MediaState to apply to the current playback
     fn change_media_state(&self, state: PlayerSignal) -> Result<(),CastError> {
-        // Open a new connection
-        let device = self.connect()?;
-        let status = device.receiver.get_status()?;
-        let app = status.applications.first().unwrap();
-
-        // Connect to application
-        device.connection.connect(app.transport_id.to_string())?;
-
-        let media_status = device.media
-            .get_status(
-                app.transport_id.as_str(), 
-                None)?;
-
-        // Ensure that media_status has an entry and take the first
-        if let Some(media_status) = media_status.entries.first(){
-            let transport_id = app.transport_id.as_str();
-            let session_id = media_status.media_session_id;
+        let status = self.with_connection(|connection| {
+            let device = &connection.device;
+            let transport_id = &connection.transport_id;
+            let session_id = match Self::media_status(connection) {
+                Ok(status) => status.media_session_id,
+                Err(_) => return Err(CastError::CasterError(
+                    "Cannot change media state. No active media.")),
+            };
 
             // Signal the state to the chromecast
             match state {
-                PlayerSignal::Play => {
-                    device.media.play(transport_id, session_id)?;
-                }
-                PlayerSignal::Pause => {
-                    device.media.pause(transport_id, session_id)?;
+                PlayerSignal::Play => device.play(transport_id, session_id),
+                PlayerSignal::Pause => device.pause(transport_id, session_id),
+                PlayerSignal::Stop => device.stop(transport_id, session_id),
+                // Playback stays playing or paused, as it was
+                PlayerSignal::Seek(time) => device.seek(transport_id, session_id, time),
+            }
+        })?;
+        self.update_status(status);
+        Ok(())
+    }
+}
+
+/// The thread of a cast that keeps its connection alive and follows the media
+/// status the device pushes. When the connection drops it is made again, backing off between
+/// attempts, and the media picked up where it was.
+struct CommThread {
+    addr: String,
+    cast: Arc<CastState>,
+    status: Arc<Mutex<MediaStatus>>,
+    events_tx: broadcast::Sender<api::Event>,
+    shutdown_rx: Receiver<()>,
+}
+impl CommThread {
+    /// Connect and communicate with the chromecast until the cast is closed,
+    /// or the chromecast can't be reached again.
+    fn run(self) {
+        let mut attempt = 0;
+        // Time to pick the media up at, once it has been loaded
+        let mut resume_at = None;
+        loop {
+            match self.open(resume_at) {
+                Ok(Some(connection)) => {
+                    attempt = 0;
+                    let connection = Arc::new(connection);
+                    *self.cast.connection.lock().unwrap() = Some(connection.clone());
+                    self.set_state(ConnectionState::Connected);
+
+                    let result = self.communicate(&connection);
+                    *self.cast.connection.lock().unwrap() = None;
+                    match result {
+                        Ok(()) => {
+                            // Closing the thread closes the connection
+                            log::info!("[Chromecast] Closing comm thread.");
+                            self.set_state(ConnectionState::Disconnected);
+                            return;
+                        }
+                        Err(err) => log::warn!("[Chromecast] Lost connection to device: {:?}", err),
+                    }
+                    resume_at = Some(self.current_time().unwrap_or(0.0));
                 }
-                PlayerSignal::Stop => {
-                    device.media.stop(transport_id, session_id)?;
+                Ok(None) => {
+                    log::info!("[Chromecast] Another app has taken over the device, ending cast.");
+                    self.end();
+                    return;
                 }
-                PlayerSignal::Seek(time) => {
-                    device.media.seek(
-                        transport_id, session_id,
-                        Some(time),     // Time to seek to
-                        None)?;         // Resume State (leave state unchanged)
+                Err(err) => log::warn!("[Chromecast] Failed to connect to device: {:?}", err),
+            }
+
+            attempt += 1;
+            if attempt > MAX_RECONNECT_ATTEMPTS {
+                log::error!("[Chromecast] Giving up on device after {} attempts.", MAX_RECONNECT_ATTEMPTS);
+                self.end();
+                return;
+            }
+            self.set_state(ConnectionState::Reconnecting { attempt });
+            let delay = reconnect_delay(attempt);
+            log::info!("[Chromecast] Reconnecting in {}s (attempt {}).", delay.as_secs(), attempt);
+            match self.shutdown_rx.recv_timeout(delay) {
+                Err(RecvTimeoutError::Timeout) => {}
+                Ok(_) | Err(RecvTimeoutError::Disconnected) => {
+                    log::info!("[Chromecast] Closing comm thread.");
+                    self.set_state(ConnectionState::Disconnected);
+                    return;
                 }
             }
-        }else{
-            return Err(CastError::CasterError(
-                "Cannot change media state. No active media."));
         }
-        device.connection.disconnect(DESTINATION_ID).unwrap();
-        Ok(())
     }
 
-    /// Create a new CastDevice connection.  
-    /// *Note: This connection must either be kept-alive with ping/pong 
-    /// or closed after a short period of time.*
-    fn connect(&self) -> Result<CastDevice, CastError> {
-        let addr = match &self.device_addr {
-            Some(addr) => addr.clone(), 
+    /// Connect to the chromecast and get the media playing on it.
+    /// ### Arguments
+    /// * resume_at - `None` to launch the media app and load the media from
+    ///     the start. After a drop, the time the media was at. The media app
+    ///     is rejoined if it is still running, and the media loaded again at
+    ///     this time if the app isn't playing it any more.
+    /// ### Returns
+    /// The connection, or `None` if another app took over the device while
+    /// the connection was down
+    fn open(&self, resume_at: Option<f64>) -> Result<Option<Connection>, CastError> {
+        // Open the device connection
+        let device = Device::connect(&self.addr, 8009)?;
+        device.connection.connect(DESTINATION_ID)?;
+        log::info!("[Chromecast] Connected to device");
+
+        let media = self.cast.media.lock().unwrap().clone();
+        let information = media.information(&get_local_ip()?);
+
+        let (transport_id, session_id, playing) = match resume_at {
+            Some(_) => {
+                let status = device.receiver_status()?;
+                let media_app = CastDeviceApp::DefaultMediaReceiver.to_string();
+                let idle_app = CastDeviceApp::Backdrop.to_string();
+                match status.applications.iter().find(|app| app.app_id != idle_app) {
+                    Some(app) if app.app_id == media_app => {
+                        // See if the media app is still playing the media
+                        device.connection.connect(app.transport_id.clone())?;
+                        let statuses = device.media_status(&app.transport_id)?;
+                        let entry = statuses.entries.into_iter().next();
+                        let playing = entry.as_ref()
+                            .and_then(|entry| entry.media.as_ref())
+                            .map_or(false, |media| media.content_id == information.content_id);
+                        if playing {
+                            self.update_status(entry);
+                        }
+                        log::info!("[Chromecast] Rejoined media app.");
+                        (app.transport_id.clone(), app.session_id.clone(), playing)
+                    }
+                    Some(_) => return Ok(None),
+                    None => {
+                        let (transport_id, session_id) = Self::launch(&device)?;
+                        (transport_id, session_id, false)
+                    }
+                }
+            }
             None => {
-                return Err(CastError::CasterError("No device address set."));
+                let (transport_id, session_id) = Self::launch(&device)?;
+                (transport_id, session_id, false)
             }
         };
 
-        let device = match CastDevice::connect_without_host_verification(
-            addr, 
-            8009){
-                
-            Ok(device) => device,
-            Err(err) => {
-                panic!("Failed to establish connection to device: {:?}", err);
+        // Begin playback
+        if !playing {
+            let status = device.load(
+                &transport_id,
+                &session_id,
+                &information,
+                resume_at.unwrap_or(0.0),
+                &media.active_track_ids(),
+            )?;
+            log::info!("[Chromecast] Loaded media.");
+            self.update_status(status.entries.into_iter().next());
+        }
+        let volume = device.receiver_status()?.volume;
+        update_volume(&self.status, &self.events_tx, volume);
+        Ok(Some(Connection { device, transport_id, session_id }))
+    }
+
+    /// Launch the media player on the device and connect to it.
+    /// ### Returns
+    /// The transport id and session id of the media app
+    fn launch(device: &Device) -> Result<(String, String), CastError> {
+        let app = device.launch_app(&CastDeviceApp::DefaultMediaReceiver)?;
+        device.connection.connect(app.transport_id.clone())?;
+        log::info!("[Chromecast] Launched media app.");
+        Ok((app.transport_id, app.session_id))
+    }
+
+    /// Chromecast communication loop, run while connected. The media status
+    /// is taken from the updates the device pushes, and only asked for when
+    /// it has gone quiet with nothing playing.
+    /// ### Returns
+    /// `Ok` when the cast is closed, an error once the connection is gone
+    fn communicate(&self, connection: &Connection) -> Result<(), CastError> {
+        let mut last_status = Instant::now();
+        // Chromecasts ping every few seconds, and answer status requests
+        let mut last_message = Instant::now();
+        loop {
+            // Poll the shutdown reciever
+            match self.shutdown_rx.try_recv() {
+                Ok(_) | Err(TryRecvError::Disconnected) => return Ok(()),
+                Err(TryRecvError::Empty) => {}
             }
-        };
-        device.connection.connect(DESTINATION_ID).unwrap();
-        Ok(device)
+
+            // Handle device communication
+            // If this is not done often enough the connection will die
+            if let Some((ch_msg, msg)) = Caster::handle_device_status(&connection.device)? {
+                log::info!("[Device Message] {}", &msg);
+                last_message = Instant::now();
+                match ch_msg {
+                    // Pushed whenever playback changes, e.g. buffering or pausing from the TV
+                    ChannelMessage::Media(MediaResponse::Status(status)) => {
+                        self.update_status(status.entries.into_iter().next());
+                        last_status = Instant::now();
+                    }
+                    // Pushed when the volume is changed, e.g. with the TV's remote
+                    ChannelMessage::Receiver(ReceiverResponse::Status(status)) => {
+                        update_volume(&self.status, &self.events_tx, status.volume);
+                    }
+                    // The receiver closes the connection when the media app stops
+                    ChannelMessage::Connection(ConnectionResponse::Close) => {
+                        return Err(CastError::CasterError("The device closed the connection."));
+                    }
+                    _ => {}
+                }
+            }
+            if last_message.elapsed() > HEARTBEAT_TIMEOUT {
+                return Err(CastError::CasterError("The device stopped responding."));
+            }
+
+            // The device pushes every change of playback, so it is only asked for
+            // the media status when there may be no session left to push from
+            if last_status.elapsed() >= STATUS_POLL_INTERVAL && self.is_idle() {
+                match connection.device.media_status(&connection.transport_id) {
+                    Ok(statuses) => {
+                        self.update_status(statuses.entries.into_iter().next());
+                        last_status = Instant::now();
+                        last_message = Instant::now();
+                    }
+                    Err(err) if is_connection_error(&err) => return Err(err),
+                    Err(err) => log::info!("[Chromecast] Error: {:?}", err),
+                }
+            }
+        }
+    }
+
+    /// Check if no media session is active, or its player is idle, e.g.
+    /// after the media finished.
+    fn is_idle(&self) -> bool {
+        match &*self.status.lock().unwrap() {
+            MediaStatus::Active(entry, _) => matches!(entry.player_state, PlayerState::Idle),
+            MediaStatus::Inactive => true,
+        }
+    }
+
+    /// Returns the seconds the media was last known to be at.
+    fn current_time(&self) -> Option<f64> {
+        match &*self.status.lock().unwrap() {
+            MediaStatus::Active(entry, _) => entry.current_time.map(f64::from),
+            MediaStatus::Inactive => None,
+        }
+    }
+
+    fn update_status(&self, entry: Option<StatusEntry>) {
+        update_status(&self.status, &self.events_tx, entry);
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        log::info!("[Chromecast] Connection {:?}", state);
+        *self.cast.connection_state.lock().unwrap() = state;
+        let _ = self.events_tx.send(api::Event::Connection(state));
+    }
+
+    /// End the cast after the connection is lost for good.
+    fn end(&self) {
+        self.update_status(None);
+        self.set_state(ConnectionState::Disconnected);
+    }
+}
+
+/// Replace the media status with one from the device, and push it to
+/// clients if they would see a difference.
+/// ### Arguments
+/// * entry - Status of the media, `None` if nothing is loaded. Updates within
+///     a media session may leave out the media, which is then kept from the
+///     last status.
+fn update_status(status: &Mutex<MediaStatus>, events_tx: &broadcast::Sender<api::Event>,
+    entry: Option<StatusEntry>) {
+    let mut status = status.lock().unwrap();
+    let new_status = match (entry, &*status) {
+        (Some(mut entry), MediaStatus::Active(last, volume)) => {
+            if entry.media.is_none() && last.media_session_id == entry.media_session_id {
+                entry.media = last.media.clone();
+            }
+            MediaStatus::Active(entry, *volume)
+        }
+        (Some(entry), MediaStatus::Inactive) => MediaStatus::Active(entry, None),
+        (None, _) => MediaStatus::Inactive,
+    };
+    replace_status(&mut status, events_tx, new_status);
+}
+
+/// Set the volume of the media status, and push it to clients if it changed.
+/// The volume is only kept while media is loaded.
+fn update_volume(status: &Mutex<MediaStatus>, events_tx: &broadcast::Sender<api::Event>, volume: Volume) {
+    let mut status = status.lock().unwrap();
+    let new_status = match &*status {
+        MediaStatus::Active(entry, _) => MediaStatus::Active(entry.clone(), Some(volume)),
+        MediaStatus::Inactive => return,
+    };
+    replace_status(&mut status, events_tx, new_status);
+}
+
+fn replace_status(status: &mut MediaStatus, events_tx: &broadcast::Sender<api::Event>, new_status: MediaStatus) {
+    let changed = serde_json::to_value(&*status).ok() != serde_json::to_value(&new_status).ok();
+    *status = new_status;
+    if changed {
+        log::info!("[Chromecast] [Status] {:?}", status);
+        let _ = events_tx.send(api::Event::MediaStatus(status.clone()));
+    }
+}
+
+/// Check if an error means the connection to the chromecast is gone, rather
+/// than that a single message went wrong.
+fn is_connection_error(err: &CastError) -> bool {
+    matches!(err,
+        CastError::IoError(_)
+        | CastError::RustCastError(RustCastError::Io(_))
+        | CastError::RustCastError(RustCastError::Ssl(_)))
+}
+
+/// Returns how long to wait before a reconnect attempt, doubling from 1s
+/// up to `MAX_RECONNECT_DELAY`.
+fn reconnect_delay(attempt: u32) -> Duration {
+    Duration::from_secs(1 << attempt.saturating_sub(1).min(5)).min(MAX_RECONNECT_DELAY)
+}
+
+/// A chromecast found through mDNS discovery.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceInfo {
+    /// "Friendly name" of the device, e.g. `Living Room TV`
+    pub name: String,
+    pub ip: IpAddr,
+    /// Model name reported by the device, e.g. `Chromecast Ultra`
+    pub model: Option<String>,
+    /// Hardware generation matched from the model or set through the API,
+    /// `None` if it wasn't recognised
+    pub generation: Option<Chromecast>,
+}
+impl DeviceInfo {
+    /// Returns the generation to make compatibility decisions for.
+    /// Unrecognised devices are treated as the least capable generation.
+    pub fn generation_or_default(&self) -> Chromecast {
+        self.generation.unwrap_or(Chromecast::FirstAndSecond)
     }
 }
 
 /// Uses mDNS discovery to find all available Chromecasts on the local network.
 /// ### Returns 
-/// `Vec<(String, IpAddr)` - "Friendly name" and IP addresses of chromecasts
-pub async fn find_chromecasts() -> Result<Vec<(String, IpAddr)>, CastError> {
+/// `Vec<DeviceInfo>` - Name, IP address and model of each chromecast
+pub async fn find_chromecasts() -> Result<Vec<DeviceInfo>, CastError> {
     // Create timeout vars
     let timeout = Duration::from_secs(TIMEOUT_SECONDS);
     let start_time = SystemTime::now();
@@ -401,14 +1012,19 @@ pub async fn find_chromecasts() -> Result<Vec<(String, IpAddr)>, CastError> {
         .take_while(|_|future::ready(start_time.elapsed().unwrap() < timeout));
     pin_mut!(stream);
     
-    // Listen and add devices to vec
-    let mut device_ips = Vec::new();
+    // Listen and add devices to vec, along with the model from their TXT record
+    let mut devices: Vec<(IpAddr, Option<String>)> = Vec::new();
     while let Some(Ok(resp)) = stream.next().await {
         let addr = resp.records()
             .find_map(self::to_ip_addr);
+        let model = resp.records()
+            .find_map(|record| self::txt_value(record, "md"));
         if let Some(addr) = addr {
-            if !device_ips.contains(&addr) {
-                device_ips.push(addr.clone());
+            match devices.iter_mut().find(|(ip, _)| *ip == addr) {
+                Some((_, known_model)) => {
+                    if known_model.is_none() { *known_model = model; }
+                }
+                None => devices.push((addr, model)),
             }
         }
     }
@@ -417,13 +1033,18 @@ pub async fn find_chromecasts() -> Result<Vec<(String, IpAddr)>, CastError> {
 
     // Poll the chromecast for their names
     let client = Client::new();
-    let mut chromecasts = Vec::<(String, IpAddr)>::new();
-    for ip in device_ips {
+    let mut chromecasts = Vec::<DeviceInfo>::new();
+    for (ip, model) in devices {
         // Build the URI to poll the chromecast's description xml
         let uri = format!("http://{}:8008/ssdp/device-desc.xml", ip)
                     .parse()
                     .unwrap();
 
+        // If for some reason we couldn't get the name, 
+        // just call it Unknown and save the ip address
+        let mut name = String::from("Unknown");
+        let mut model = model;
+
         // Send a GET request to the chromecast's device XML 
         if let Ok(mut resp) = client.get(uri).await {
             if resp.status().is_success() {
@@ -431,31 +1052,76 @@ pub async fn find_chromecasts() -> Result<Vec<(String, IpAddr)>, CastError> {
                 if let Some(body) = resp.body_mut().data().await {
                     // Ensure Hyper didnt error
                     if let Ok(body) = body {
-                        // Run the result through regex to pull the name
+                        // Run the result through regex to pull the name and model
                         let body = body.to_vec();
                         let body_string = String::from_utf8(body).unwrap();
-                        let reg = Regex::new(r#"<friendlyName>(.*)</friendlyName>"#).unwrap();
-                        let captures = reg.captures(&body_string);
-                        if let Some(captures) = captures {
-                            // Push the name into a vec with the IP, if there was a match
-                            if let Some(capture) = captures.get(1) {
-                                chromecasts.push((capture.as_str().into(), ip));
-                                continue;
-                            }
+                        if let Some(friendly_name) = xml_value(&body_string, &FRIENDLY_NAME) {
+                            name = friendly_name;
+                        }
+                        if model.is_none() {
+                            model = xml_value(&body_string, &MODEL_NAME);
                         }
                     }
                 }
             }
         }    
 
-        // If for some reason we couldn't get the name, 
-        // just call it Unknown and save the ip address
-        chromecasts.push((String::from("Unknown"), ip));
+        let generation = model.as_deref().and_then(to_generation);
+        log::info!("[Discovery] Found '{}' at {} (model: {:?}, generation: {:?})",
+            name, ip, model, generation);
+        chromecasts.push(DeviceInfo { name, ip, model, generation });
     }
 
     Ok(chromecasts)
 }
 
+/// Map a model name onto the chromecast generation it belongs to.
+/// ### Returns
+/// `None` if the model isn't recognised, e.g. TVs with a chromecast built in
+fn to_generation(model: &str) -> Option<Chromecast> {
+    let model = model.to_lowercase();
+    if model.contains("ultra") {
+        Some(Chromecast::Ultra)
+    } else if model.contains("google tv") || model.contains("chromecast hd") {
+        Some(Chromecast::GoogleTV)
+    } else if model.contains("hub") {
+        Some(Chromecast::NestHub)
+    } else if model.contains("chromecast") || model.contains("eureka dongle") {
+        // The third generation reports the same model as the first two, so
+        // assume the less capable of them unless a client sets it otherwise
+        Some(Chromecast::FirstAndSecond)
+    } else {
+        None
+    }
+}
+
+/// Pull the value of a key out of a DNS TXT record, e.g. `md=Chromecast`.
+/// ### Returns
+/// `Some<String>` If record is TXT and holds the key  
+/// Otherwise   
+/// `None`
+fn txt_value(record: &Record, key: &str) -> Option<String> {
+    match &record.kind {
+        RecordKind::TXT(entries) => entries.iter().find_map(|entry| {
+            let mut split = entry.splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some(entry_key), Some(value)) if entry_key == key => Some(value.to_string()),
+                _ => None,
+            }
+        }),
+        _ => None,
+    }
+}
+
+/// Pull the text of the first matching element out of an XML document.
+/// ### Arguments
+/// - `element` - Matches the element, capturing its text, e.g. `MODEL_NAME`
+fn xml_value(xml: &str, element: &Regex) -> Option<String> {
+    element.captures(xml)
+        .and_then(|captures| captures.get(1))
+        .map(|capture| capture.as_str().to_string())
+}
+
 /// Convert a DNS record to IpAddr
 /// ### Returns
 /// `Some<IpAddr>` If record is A or AAAA  
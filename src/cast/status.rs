@@ -0,0 +1,135 @@
+use super::{device::{Device, MEDIA_NAMESPACE}, error::CastError};
+use rust_cast::channels::{
+    media::{IdleReason, Metadata, StatusEntry},
+    receiver::{Application, CastDeviceApp},
+};
+use serde::Serialize;
+
+/// What a chromecast is doing, whichever sender started it, can be serialized.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceStatus {
+    /// The app on the TV, `None` while it shows the idle screen
+    pub app: Option<AppStatus>,
+    /// The volume, from 0.0 for silent to 1.0 for full
+    pub volume_level: Option<f32>,
+    pub muted: Option<bool>,
+    /// Whether the TV is in standby
+    pub stand_by: bool,
+    /// Whether the chromecast is the TV's active input
+    pub active_input: bool,
+    /// The media the app is playing, if it is a media app with media loaded
+    pub media: Option<MediaState>,
+}
+
+/// A cast app running on a chromecast.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AppStatus {
+    /// Id of the app, e.g. `CC1AD845` for the default media receiver
+    pub app_id: String,
+    /// Name of the app, e.g. `YouTube`
+    pub display_name: String,
+    pub session_id: String,
+    /// What the app says it is doing, e.g. the title of a video
+    pub status_text: String,
+}
+impl From<&Application> for AppStatus {
+    fn from(app: &Application) -> Self {
+        Self {
+            app_id: app.app_id.clone(),
+            display_name: app.display_name.clone(),
+            session_id: app.session_id.clone(),
+            status_text: app.status_text.clone(),
+        }
+    }
+}
+
+/// Media loaded on a chromecast's media app.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaState {
+    /// The media as the app knows it, usually its URL
+    pub content_id: String,
+    pub content_type: String,
+    pub title: Option<String>,
+    /// URL of an image of the media, e.g. a poster or thumbnail
+    pub image: Option<String>,
+    /// `IDLE`, `PLAYING`, `BUFFERING` or `PAUSED`
+    pub player_state: String,
+    /// Why the player is idle: `CANCELLED`, `INTERRUPTED`, `FINISHED` or `ERROR`
+    pub idle_reason: Option<&'static str>,
+    pub current_time: Option<f32>,
+    pub duration: Option<f32>,
+}
+impl MediaState {
+    /// Describe a media status entry.
+    /// ### Returns
+    /// `None` if the entry doesn't say what media is loaded
+    fn from_entry(entry: &StatusEntry) -> Option<Self> {
+        let media = entry.media.as_ref()?;
+        let (title, image) = match &media.metadata {
+            Some(Metadata::Generic(metadata)) => (metadata.title.clone(), metadata.images.first()),
+            Some(Metadata::Movie(metadata)) => (metadata.title.clone(), metadata.images.first()),
+            Some(Metadata::TvShow(metadata)) => {
+                let title = match (&metadata.series_title, &metadata.episode_title) {
+                    (Some(series), Some(episode)) => Some(format!("{} - {}", series, episode)),
+                    (series, episode) => series.clone().or_else(|| episode.clone()),
+                };
+                (title, metadata.images.first())
+            }
+            Some(Metadata::MusicTrack(metadata)) => (metadata.title.clone(), metadata.images.first()),
+            Some(Metadata::Photo(metadata)) => (metadata.title.clone(), None),
+            None => (None, None),
+        };
+        let idle_reason = entry.idle_reason.map(|reason| match reason {
+            IdleReason::Cancelled => "CANCELLED",
+            IdleReason::Interrupted => "INTERRUPTED",
+            IdleReason::Finished => "FINISHED",
+            IdleReason::Error => "ERROR",
+        });
+
+        Some(Self {
+            content_id: media.content_id.clone(),
+            content_type: media.content_type.clone(),
+            title,
+            image: image.map(|image| image.url.clone()),
+            player_state: entry.player_state.to_string(),
+            idle_reason,
+            current_time: entry.current_time,
+            duration: media.duration,
+        })
+    }
+}
+
+impl DeviceStatus {
+    /// Ask a chromecast what it is doing. If the running app plays media, it
+    /// is asked what media it has loaded.
+    /// ### Arguments
+    /// * device - A connection to the chromecast's receiver
+    pub(super) fn query(device: &Device) -> Result<Self, CastError> {
+        let status = device.receiver_status()?;
+        let idle_app = CastDeviceApp::Backdrop.to_string();
+        let app = status.applications.iter().find(|app| app.app_id != idle_app);
+
+        let media = match app {
+            Some(app) if app.namespaces.iter().any(|namespace| namespace == MEDIA_NAMESPACE) => {
+                device.connection.connect(app.transport_id.clone())?;
+                device.media_status(&app.transport_id)?
+                    .entries
+                    .first()
+                    .and_then(MediaState::from_entry)
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            app: app.map(AppStatus::from),
+            volume_level: status.volume.level,
+            muted: status.volume.muted,
+            stand_by: status.is_stand_by,
+            active_input: status.is_active_input,
+            media,
+        })
+    }
+}
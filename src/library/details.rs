@@ -0,0 +1,241 @@
+use crate::video_encoding::MediaInfo;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::{fs, path::{Path, PathBuf}};
+
+/// Extensions of images that can be used as posters
+const POSTER_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "png", "webp"];
+/// Names of images that stand for every file in their directory
+const FOLDER_POSTERS: [&str; 3] = ["poster", "folder", "cover"];
+/// Tags release names put after the title, e.g. `Movie.2010.1080p.BluRay.x264`
+static RELEASE_TAGS: Lazy<Regex> = Lazy::new(|| Regex::new(
+    r"(?i)[ ._\-\[(]+(\d{3,4}p|4k|uhd|web[ ._\-]?(dl|rip)|blu[ ._\-]?ray|[bh]drip|dvdrip|hdtv|hdr|x26[45]|h[ .]?26[45]|hevc|xvid|remux|proper|repack)\b.*$").unwrap());
+/// Episode file names, e.g. `Series S01E02 Title`, `Series.s01e02e03` or `Series 1x02`
+static EPISODE: Lazy<Regex> = Lazy::new(|| Regex::new(
+    r"(?i)^(.*?)[ ._\-]*(?:\b|_)(?:s(\d{1,2})[ ._\-]?e(\d{1,3})(?:[ ._\-]?e\d{1,3})*|(\d{1,2})x(\d{2,3}))(?:\b|_)(.*)$").unwrap());
+/// Movie file names, e.g. `Movie (2010)` or `Movie.2010.1080p`
+static MOVIE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(.+?)[ ._\-(\[]+((?:19|20)\d{2})(?:[ ._\-)\]]|$)").unwrap());
+/// Season directory names, e.g. `Season 1` or `S01`
+static SEASON: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)^(season|series|s)[ ._\-]*\d+$").unwrap());
+
+/// What a library entry is, as told by its tags and file name.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Details {
+    /// Media that isn't recognised as a movie or an episode
+    Generic { title: String },
+    Movie {
+        title: String,
+        /// Release date or year, e.g. `2010-07-16` or `2010`
+        release_date: Option<String>,
+    },
+    Episode {
+        series: String,
+        season: Option<u32>,
+        episode: Option<u32>,
+        /// Title of the episode itself
+        title: Option<String>,
+        /// Date the episode first aired
+        air_date: Option<String>,
+    },
+}
+
+/// Work out what a media file is. Tags written by e.g. iTunes or a media
+/// manager are trusted over the file's name, which is matched against the
+/// usual `Series S01E02 Title` and `Movie (2010)` patterns.
+/// ### Arguments
+/// - `path` - Path of the media file
+/// - `info` - Probed info of the file, if it was probed
+pub fn find(path: &Path, info: Option<&MediaInfo>) -> Details {
+    let tag = |keys: &[&str]| info.and_then(|info| {
+        info.metadata.iter()
+            .find(|(key, value)| keys.iter().any(|k| key.eq_ignore_ascii_case(k)) && !value.trim().is_empty())
+            .map(|(_, value)| value.trim().to_string())
+    });
+    let number = |keys: &[&str]| tag(keys).and_then(|value| value.parse::<u32>().ok());
+    let date = tag(&["date", "date_released", "year"]);
+    let name = path.file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    if let Some(series) = tag(&["show"]) {
+        return Details::Episode {
+            series,
+            season: number(&["season_number"]),
+            episode: number(&["episode_sort"]),
+            title: tag(&["title"]),
+            air_date: date,
+        };
+    }
+
+    if let Some(captures) = EPISODE.captures(&name) {
+        let capture = |i: usize| captures.get(i).map(|capture| capture.as_str());
+        let series = match clean_name(capture(1).unwrap_or_default()) {
+            series if series.is_empty() => series_from_directory(path).unwrap_or(series),
+            series => series,
+        };
+        let title = clean_name(capture(6).unwrap_or_default());
+        return Details::Episode {
+            series,
+            season: capture(2).or(capture(4)).and_then(|season| season.parse().ok()),
+            episode: capture(3).or(capture(5)).and_then(|episode| episode.parse().ok()),
+            title: tag(&["title"]).or_else(|| Some(title).filter(|title| !title.is_empty())),
+            air_date: date,
+        };
+    }
+
+    if let Some(captures) = MOVIE.captures(&name) {
+        return Details::Movie {
+            title: tag(&["title"]).unwrap_or_else(|| clean_name(&captures[1])),
+            release_date: date.or_else(|| Some(captures[2].to_string())),
+        };
+    }
+    // iTunes marks movies with a media type of 9
+    if tag(&["media_type"]).as_deref() == Some("9") {
+        return Details::Movie {
+            title: tag(&["title"]).unwrap_or_else(|| clean_name(&name)),
+            release_date: date,
+        };
+    }
+
+    Details::Generic { title: tag(&["title"]).unwrap_or_else(|| clean_name(&name)) }
+}
+
+/// Find a poster for a media file. For `Movie.mkv` these are looked for in order:
+/// `Movie.jpg` and `Movie-poster.jpg` next to it, a `poster`, `folder` or
+/// `cover` image in its directory, then one in the directory above, which
+/// holds the series' poster when episodes are kept in season directories.
+/// ### Returns
+/// The path of the image, if one was found
+pub fn find_poster(media: &Path) -> Option<PathBuf> {
+    let dir = media.parent()?;
+    let stem = media.file_stem()?.to_string_lossy().to_lowercase();
+
+    let mut names = vec![stem.clone(), format!("{}-poster", stem)];
+    names.extend(FOLDER_POSTERS.iter().map(|name| name.to_string()));
+    find_image(dir, &names).or_else(|| {
+        let names: Vec<String> = FOLDER_POSTERS.iter().map(|name| name.to_string()).collect();
+        find_image(dir.parent()?, &names)
+    })
+}
+
+/// Returns the MIME type of a poster found by `find_poster`.
+pub fn poster_content_type(path: &Path) -> &'static str {
+    let ext = path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    match ext.as_str() {
+        "png" => "image/png",
+        "webp" => "image/webp",
+        _ => "image/jpeg",
+    }
+}
+
+/// Find the first of some names, compared without case, among the images of a directory.
+fn find_image(dir: &Path, names: &[String]) -> Option<PathBuf> {
+    let images: Vec<(String, PathBuf)> = fs::read_dir(dir).ok()?
+        .filter_map(|file| file.ok())
+        .map(|file| file.path())
+        .filter(|path| path.extension().map_or(false, |ext| {
+            POSTER_EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str())
+        }))
+        .filter_map(|path| Some((path.file_stem()?.to_string_lossy().to_lowercase(), path)))
+        .collect();
+
+    names.iter().find_map(|name| {
+        images.iter()
+            .find(|(stem, _)| stem == name)
+            .map(|(_, path)| path.clone())
+    })
+}
+
+/// Name the series of an episode whose file name doesn't, from its
+/// directory, e.g. `Series/Season 1/S01E02.mkv`.
+fn series_from_directory(path: &Path) -> Option<String> {
+    path.ancestors()
+        .skip(1)
+        .filter_map(|dir| dir.file_name())
+        .map(|name| name.to_string_lossy().to_string())
+        .find(|name| !SEASON.is_match(name))
+        .map(|name| clean_name(&name))
+        .filter(|name| !name.is_empty())
+}
+
+/// Turn part of a file name into a readable title, dropping release tags
+/// and turning dots and underscores into spaces, e.g. `The.Movie.1080p` into `The Movie`.
+fn clean_name(name: &str) -> String {
+    let name = RELEASE_TAGS.replace(name, "");
+    // Names with spaces may use dots as part of the title, e.g. `Mr. Robot`
+    let name = match name.contains(' ') {
+        true => name.replace('_', " "),
+        false => name.replace(|c| c == '.' || c == '_', " "),
+    };
+    name.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_matches(|c: char| c == '-' || c.is_whitespace())
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn episode(series: &str, season: u32, episode: u32, title: Option<&str>) -> Details {
+        Details::Episode {
+            series: series.into(),
+            season: Some(season),
+            episode: Some(episode),
+            title: title.map(String::from),
+            air_date: None,
+        }
+    }
+
+    fn tagged(tags: &[(&str, &str)]) -> MediaInfo {
+        let metadata = tags.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        MediaInfo { metadata, ..Default::default() }
+    }
+
+    #[test]
+    fn episode_from_release_name() {
+        let path = Path::new("/tv/Breaking.Bad.S01E02.Cat's.in.the.Bag.720p.WEB-DL.x264.mkv");
+        assert_eq!(find(path, None), episode("Breaking Bad", 1, 2, Some("Cat's in the Bag")));
+    }
+
+    #[test]
+    fn episode_in_other_forms() {
+        assert_eq!(find(Path::new("Mr. Robot - 2x05 - eps2.3.mkv"), None), episode("Mr. Robot", 2, 5, Some("eps2.3")));
+        // Double episodes are named after the first
+        assert_eq!(find(Path::new("show_s03e09e10.mp4"), None), episode("show", 3, 9, None));
+        assert_eq!(find(Path::new("some_show_s01e01_pilot.mp4"), None), episode("some show", 1, 1, Some("pilot")));
+    }
+
+    #[test]
+    fn series_from_directories() {
+        let path = Path::new("/tv/The Office/Season 2/S02E03.mkv");
+        assert_eq!(find(path, None), episode("The Office", 2, 3, None));
+    }
+
+    #[test]
+    fn movie_from_release_name() {
+        let movie = |title: &str, year: &str| Details::Movie { title: title.into(), release_date: Some(year.into()) };
+        assert_eq!(find(Path::new("Inception.2010.1080p.BluRay.x264.mkv"), None), movie("Inception", "2010"));
+        assert_eq!(find(Path::new("The Matrix (1999) [4K].mkv"), None), movie("The Matrix", "1999"));
+    }
+
+    #[test]
+    fn generic_name_is_cleaned() {
+        let details = find(Path::new("/videos/home_video.HDTV.mp4"), None);
+        assert_eq!(details, Details::Generic { title: "home video".into() });
+    }
+
+    #[test]
+    fn tags_win_over_name() {
+        let info = tagged(&[("show", "Tagged Show"), ("season_number", "4"), ("episode_sort", "7"), ("title", "Pilot")]);
+        assert_eq!(find(Path::new("S01E01.mkv"), Some(&info)), episode("Tagged Show", 4, 7, Some("Pilot")));
+
+        let info = tagged(&[("media_type", "9"), ("title", "Tagged Movie"), ("date", "2001-05-04")]);
+        assert_eq!(find(Path::new("movie.m4v"), Some(&info)),
+            Details::Movie { title: "Tagged Movie".into(), release_date: Some("2001-05-04".into()) });
+    }
+}
@@ -0,0 +1,33 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum LibraryError {
+    IoError(std::io::Error),
+    SerdeError(serde_json::Error),
+    WatchError(notify::Error),
+}
+impl From<std::io::Error> for LibraryError {
+    fn from(err: std::io::Error) -> Self {
+        LibraryError::IoError(err)
+    }
+}
+impl From<serde_json::Error> for LibraryError {
+    fn from(err: serde_json::Error) -> Self {
+        LibraryError::SerdeError(err)
+    }
+}
+impl From<notify::Error> for LibraryError {
+    fn from(err: notify::Error) -> Self {
+        LibraryError::WatchError(err)
+    }
+}
+
+impl fmt::Display for LibraryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LibraryError::IoError(err) => write!(f, "I/O failed: {}", err),
+            LibraryError::SerdeError(err) => write!(f, "The library file is invalid: {}", err),
+            LibraryError::WatchError(err) => write!(f, "Watching the library failed: {}", err),
+        }
+    }
+}
@@ -0,0 +1,473 @@
+pub mod details;
+pub mod error;
+mod store;
+pub mod watcher;
+
+use crate::video_encoding::{self, MediaInfo};
+use serde::{Serialize, Deserialize};
+use std::{collections::{BTreeMap, HashMap}, fs, path::{Path, PathBuf}, sync::RwLock, time::UNIX_EPOCH};
+
+pub type Error = error::LibraryError;
+
+/// File extensions that are considered playable media. Whether or not a file
+/// can actually be played on a given Chromecast is decided elsewhere.
+const MEDIA_EXTENSIONS: [&'static str; 9] = [
+    "mp4", "m4v", "mkv", "webm", "mov", "avi", "ts", "m2ts", "mpg",
+];
+
+/// A single playable file within the library.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryEntry {
+    pub id: u32,
+    pub path: PathBuf,
+    pub title: String,
+    /// File size in bytes, at the time of the last probe
+    pub size: u64,
+    /// Modification time in seconds since the unix epoch, at the time of the last probe
+    pub modified: u64,
+    /// The probed streams of the file, `None` if the probe failed
+    #[serde(default)]
+    pub info: Option<MediaInfo>,
+}
+impl LibraryEntry {
+    fn new(id: u32, path: PathBuf) -> Self {
+        Self { 
+            id, 
+            title: title_from_path(&path),
+            path, 
+            size: 0,
+            modified: 0,
+            info: None,
+        }
+    }
+
+    /// Point the entry at a new path, such as after the file is renamed.
+    fn set_path(&mut self, path: PathBuf) {
+        self.title = title_from_path(&path);
+        self.path = path;
+    }
+
+    /// Check if the file has changed since it was last probed, or if the last
+    /// probe failed.
+    fn is_stale(&self, size: u64, modified: u64) -> bool {
+        self.size != size || self.modified != modified || self.info.is_none()
+    }
+
+    /// The MIME type to advertise when serving this entry.
+    pub fn content_type(&self) -> &'static str {
+        content_type(&self.path)
+    }
+
+    /// Returns what the entry is, e.g. a movie or an episode of a series.
+    pub fn details(&self) -> details::Details {
+        details::find(&self.path, self.info.as_ref())
+    }
+
+    /// Returns the path of a poster image for the entry, if one was found.
+    pub fn poster(&self) -> Option<PathBuf> {
+        details::find_poster(&self.path)
+    }
+}
+
+/// A file probed by a scan, to be applied to the library once the scan is done.
+struct ScannedFile {
+    path: PathBuf,
+    size: u64,
+    modified: u64,
+    info: Option<MediaInfo>,
+}
+
+/// A change to the library. These are pushed to API clients as they happen.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "change", rename_all = "camelCase")]
+pub enum LibraryEvent {
+    Added { entry: LibraryEntry },
+    Updated { entry: LibraryEntry },
+    Renamed { from: PathBuf, entry: LibraryEntry },
+    Removed { id: u32 },
+}
+
+/// The media library is a collection of playable files found beneath a set of
+/// root directories. Every file is given an id that remains the same across
+/// rescans, these ids are what `api::CastSignal::Begin` refers to.
+/// When opened with an index path, the library is persisted so that ids also
+/// remain the same across restarts.
+pub struct Library {
+    roots: Vec<PathBuf>,
+    entries: BTreeMap<u32, LibraryEntry>,
+    /// Lookup of entry ids by path
+    paths: HashMap<PathBuf, u32>,
+    next_id: u32,
+    index_path: Option<PathBuf>,
+}
+
+impl Library {
+    /// Creates an empty, in-memory only, library.
+    pub fn new(roots: Vec<PathBuf>) -> Self {
+        // Absolute roots keep paths consistent with those reported by the watcher
+        let roots = roots
+            .into_iter()
+            .map(|root| root.canonicalize().unwrap_or(root))
+            .collect();
+
+        Self {
+            roots,
+            entries: BTreeMap::new(),
+            paths: HashMap::new(),
+            next_id: 0,
+            index_path: None,
+        }
+    }
+
+    /// Opens the library persisted at `index_path`, or an empty library if there 
+    /// is no index there yet. Changes are saved back to `index_path` after each scan.
+    pub fn open(roots: Vec<PathBuf>, index_path: PathBuf) -> Result<Self, Error> {
+        let mut library = Self::new(roots);
+        if let Some(index) = store::load(&index_path)? {
+            library.next_id = index.next_id;
+            for entry in index.entries {
+                library.insert(entry);
+            }
+            log::info!("[Library] Loaded {} entries from {:?}", 
+                library.entries.len(), &index_path);
+        }
+        library.index_path = Some(index_path);
+
+        Ok(library)
+    }
+
+    /// Write the library to its index, if it has one.
+    pub fn save(&self) -> Result<(), Error> {
+        if let Some(path) = &self.index_path {
+            let index = store::LibraryIndex {
+                next_id: self.next_id,
+                entries: self.entries.values().cloned().collect(),
+            };
+            store::save(path, &index)?;
+        }
+        Ok(())
+    }
+
+    /// Walks every root directory and updates the library to match the files
+    /// found. Files already in the library keep their id, new files are given
+    /// the next available id and missing files are removed.
+    /// Only new files, or files whose size or modification time has changed,
+    /// are probed. The library is only locked to see which files it has and to
+    /// apply the changes, so it can be read and watched while files are probed.
+    /// ### Returns
+    /// `Vec<LibraryEvent>` - The changes made to the library
+    pub fn scan(library: &RwLock<Library>) -> Result<Vec<LibraryEvent>, Error> {
+        let (roots, known) = {
+            let library = library.read().unwrap();
+            let known: HashMap<PathBuf, LibraryEntry> = library.entries.values()
+                .map(|entry| (entry.path.clone(), entry.clone()))
+                .collect();
+            (library.roots.clone(), known)
+        };
+
+        let mut found = Vec::new();
+        for root in &roots {
+            if !root.is_dir() {
+                log::warn!("[Library] Skipping missing root: {:?}", root);
+                continue;
+            }
+            find_media(root, &mut found)?;
+        }
+        // Sort so that a fresh library hands out ids in a predictable order
+        found.sort();
+        found.dedup();
+
+        let scanned: Vec<ScannedFile> = found.iter()
+            .filter_map(|path| {
+                let (size, modified) = match file_stats(path) {
+                    Ok(stats) => stats,
+                    Err(err) => {
+                        log::warn!("[Library] Failed to stat {:?}: {:?}", path, err);
+                        return None;
+                    }
+                };
+                match known.get(path) {
+                    Some(entry) if !entry.is_stale(size, modified) => None,
+                    _ => Some(ScannedFile { path: path.clone(), size, modified, info: probe(path) }),
+                }
+            })
+            .collect();
+
+        let mut library = library.write().unwrap();
+        let events = library.apply_scan(&found, scanned);
+        log::info!("[Library] Scan complete, {} entries ({} changes).",
+            library.entries.len(), events.len());
+        library.save()?;
+        Ok(events)
+    }
+
+    /// Update the library with the results of a scan.
+    /// ### Arguments
+    /// - `found` - Every media file beneath the roots, sorted
+    /// - `scanned` - The files that were probed, as they are new or have changed
+    /// ### Returns
+    /// `Vec<LibraryEvent>` - The changes made to the library
+    fn apply_scan(&mut self, found: &[PathBuf], scanned: Vec<ScannedFile>) -> Vec<LibraryEvent> {
+        // Drop entries that no longer exist
+        let missing: Vec<u32> = self.entries
+            .values()
+            .filter(|entry| found.binary_search(&entry.path).is_err())
+            .map(|entry| entry.id)
+            .collect();
+        let mut events: Vec<LibraryEvent> = missing
+            .into_iter()
+            .filter_map(|id| self.remove(id))
+            .collect();
+
+        events.extend(self.apply_scanned(scanned));
+        events
+    }
+
+    /// Add probed files that are new to the library and update the entries of
+    /// those that have changed.
+    /// ### Returns
+    /// `Vec<LibraryEvent>` - The changes made to the library
+    fn apply_scanned(&mut self, scanned: Vec<ScannedFile>) -> Vec<LibraryEvent> {
+        let mut events = Vec::new();
+        for file in scanned {
+            let event = match self.paths.get(&file.path) {
+                Some(id) => {
+                    let entry = self.entries.get_mut(id).unwrap();
+                    entry.size = file.size;
+                    entry.modified = file.modified;
+                    entry.info = file.info;
+                    LibraryEvent::Updated { entry: entry.clone() }
+                }
+                None => {
+                    let id = self.next_id;
+                    self.next_id += 1;
+                    let entry = LibraryEntry { size: file.size, modified: file.modified, info: file.info,
+                        ..LibraryEntry::new(id, file.path) };
+                    self.insert(entry.clone());
+                    LibraryEvent::Added { entry }
+                }
+            };
+            events.push(event);
+        }
+        events
+    }
+
+    /// Bring the library up to date with the file at `path`, or every media file
+    /// beneath it if it is a directory. Files are added if they are new, or
+    /// re-probed if they have changed since they were last probed. Files that
+    /// aren't media or aren't beneath one of the library's roots are ignored.
+    /// Like `scan`, the library is only locked to see which files it has and to
+    /// apply the changes, so probing a whole directory doesn't block readers.
+    /// ### Returns
+    /// `Vec<LibraryEvent>` - The changes made to the library
+    pub fn update_path(library: &RwLock<Library>, path: &Path) -> Vec<LibraryEvent> {
+        let mut found = Vec::new();
+        if path.is_dir() {
+            if let Err(err) = find_media(path, &mut found) {
+                log::warn!("[Library] Failed to scan {:?}: {:?}", path, err);
+            }
+            found.sort();
+        }
+        else {
+            found.push(path.to_path_buf());
+        }
+
+        let stats: Vec<(PathBuf, u64, u64)> = found.into_iter()
+            .filter(|path| is_media_file(path))
+            .filter_map(|path| match file_stats(&path) {
+                Ok((size, modified)) => Some((path, size, modified)),
+                Err(err) => {
+                    log::warn!("[Library] Failed to stat {:?}: {:?}", path, err);
+                    None
+                }
+            })
+            .collect();
+        let stale: Vec<(PathBuf, u64, u64)> = {
+            let library = library.read().unwrap();
+            stats.into_iter()
+                .filter(|(path, _, _)| library.is_in_roots(path))
+                .filter(|(path, size, modified)| match library.paths.get(path) {
+                    Some(id) => library.entries[id].is_stale(*size, *modified),
+                    None => true,
+                })
+                .collect()
+        };
+        if stale.is_empty() {
+            return Vec::new();
+        }
+
+        let scanned = stale.into_iter()
+            .map(|(path, size, modified)| {
+                let info = probe(&path);
+                ScannedFile { path, size, modified, info }
+            })
+            .collect();
+        library.write().unwrap().apply_scanned(scanned)
+    }
+
+    /// Remove the file at `path` from the library. If `path` is a directory, all
+    /// entries beneath it are removed.
+    /// ### Returns
+    /// `Vec<LibraryEvent>` - The changes made to the library
+    pub fn remove_path(&mut self, path: &Path) -> Vec<LibraryEvent> {
+        let ids: Vec<u32> = self.entries
+            .values()
+            .filter(|entry| entry.path.starts_with(path))
+            .map(|entry| entry.id)
+            .collect();
+        
+        ids.into_iter().filter_map(|id| self.remove(id)).collect()
+    }
+
+    /// Move the file or directory at `from` to `to`. Entries keep their ids when
+    /// moved, unless they are moved out of the library or are renamed to 
+    /// something that isn't media, in which case they are removed.
+    /// Something that wasn't media, e.g. a partial download, may have been
+    /// renamed into media, so `to` should be passed to `update_path` afterwards.
+    /// ### Returns
+    /// `Vec<LibraryEvent>` - The changes made to the library
+    pub fn rename_path(&mut self, from: &Path, to: &Path) -> Vec<LibraryEvent> {
+        let moved: Vec<u32> = self.entries
+            .values()
+            .filter(|entry| entry.path.starts_with(from))
+            .map(|entry| entry.id)
+            .collect();
+
+        let mut events = Vec::new();
+        for id in moved {
+            let old_path = self.entries[&id].path.clone();
+            let new_path = match old_path.strip_prefix(from) {
+                Ok(rel) if rel.as_os_str().is_empty() => to.to_path_buf(),
+                Ok(rel) => to.join(rel),
+                Err(_) => continue,
+            };
+            if !is_media_file(&new_path) || !self.is_in_roots(&new_path) {
+                events.extend(self.remove(id));
+                continue;
+            }
+
+            let mut entry = self.entries.remove(&id).unwrap();
+            self.paths.remove(&old_path);
+            entry.set_path(new_path);
+            self.insert(entry.clone());
+            events.push(LibraryEvent::Renamed { from: old_path, entry });
+        }
+        events
+    }
+
+    /// Returns the entry with the matching id, if there is one.
+    pub fn get(&self, id: u32) -> Option<&LibraryEntry> {
+        self.entries.get(&id)
+    }
+
+    /// Returns all entries, ordered by id.
+    pub fn entries(&self) -> Vec<&LibraryEntry> {
+        self.entries.values().collect()
+    }
+
+    pub fn roots(&self) -> &Vec<PathBuf> {
+        &self.roots
+    }
+
+    fn is_in_roots(&self, path: &Path) -> bool {
+        self.roots.iter().any(|root| path.starts_with(root))
+    }
+
+    fn insert(&mut self, entry: LibraryEntry) {
+        self.paths.insert(entry.path.clone(), entry.id);
+        self.entries.insert(entry.id, entry);
+    }
+
+    fn remove(&mut self, id: u32) -> Option<LibraryEvent> {
+        let entry = self.entries.remove(&id)?;
+        self.paths.remove(&entry.path);
+        Some(LibraryEvent::Removed { id })
+    }
+}
+
+/// Check if a path has the extension of a playable media file.
+pub fn is_media_file(path: &Path) -> bool {
+    match path.extension() {
+        Some(ext) => {
+            let ext = ext.to_string_lossy().to_lowercase();
+            MEDIA_EXTENSIONS.contains(&ext.as_str())
+        }
+        None => false,
+    }
+}
+
+/// Guess the MIME type of a media file from its extension.
+pub fn content_type(path: &Path) -> &'static str {
+    let ext = path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    match ext.as_str() {
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "mov" => "video/quicktime",
+        "avi" => "video/x-msvideo",
+        "ts" | "m2ts" => "video/mp2t",
+        "mpg" => "video/mpeg",
+        _ => "video/mp4",
+    }
+}
+
+/// Build a display title from a file's name.
+fn title_from_path(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Probe a media file for its streams.
+/// ### Returns
+/// The streams of the file, `None` if the probe failed
+fn probe(path: &Path) -> Option<MediaInfo> {
+    match video_encoding::probe(path) {
+        Ok(info) => Some(info),
+        Err(err) => {
+            log::warn!("[Library] Failed to probe {:?}: {:?}", path, err);
+            None
+        }
+    }
+}
+
+/// Returns the size, in bytes, and modification time, in seconds since the 
+/// unix epoch, of a file.
+fn file_stats(path: &Path) -> Result<(u64, u64), Error> {
+    let meta = fs::metadata(path)?;
+    let modified = meta.modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0);
+
+    Ok((meta.len(), modified))
+}
+
+/// Recursively collect all media files beneath `dir` into `found`.
+/// Hidden files and directories are skipped.
+fn find_media(dir: &Path, found: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if hidden {
+            continue;
+        }
+
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            // An unreadable subdirectory shouldn't abort the whole scan
+            if let Err(err) = find_media(&path, found) {
+                log::warn!("[Library] Failed to scan {:?}: {:?}", path, err);
+            }
+        }
+        else if is_media_file(&path) {
+            found.push(path);
+        }
+    }
+
+    Ok(())
+}
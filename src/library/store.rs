@@ -0,0 +1,33 @@
+use super::{Error, LibraryEntry};
+
+use serde::{Serialize, Deserialize};
+use std::{fs, path::Path};
+
+/// The on-disk representation of the library. `next_id` is stored so ids of
+/// removed entries are never handed out again.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryIndex {
+    pub next_id: u32,
+    pub entries: Vec<LibraryEntry>,
+}
+
+/// Read the library index at `path`.
+/// ### Returns
+/// `None` if there is no index at `path` yet.
+pub fn load(path: &Path) -> Result<Option<LibraryIndex>, Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data = fs::read(path)?;
+    Ok(Some(serde_json::from_slice(&data)?))
+}
+
+/// Write the library index to `path`. The index is written to a temporary file
+/// first so a crash mid-write can't corrupt the existing index.
+pub fn save(path: &Path, index: &LibraryIndex) -> Result<(), Error> {
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, serde_json::to_vec(index)?)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use super::{Error, Library, LibraryEvent};
+use crate::api;
+
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use std::{sync::{mpsc, Arc, RwLock}, thread, time::Duration};
+use tokio::sync::broadcast;
+
+/// How long a file must go unchanged before the watcher acts on it. This stops
+/// a file that is still being copied in from being probed over and over.
+const DEBOUNCE_SECONDS: u64 = 2;
+
+/// Watches the library's roots for changes. Files are added, removed and renamed
+/// in the library as they change on disk and each change is pushed to `events_tx`.
+/// The watcher runs on its own thread for the lifetime of the program.
+pub fn watch(library: Arc<RwLock<Library>>, events_tx: broadcast::Sender<api::Event>) 
+    -> Result<(), Error> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::watcher(tx, Duration::from_secs(DEBOUNCE_SECONDS))?;
+    for root in library.read().unwrap().roots() {
+        watcher.watch(root, RecursiveMode::Recursive)?;
+        log::info!("[Library] Watching {:?}", root);
+    }
+
+    thread::spawn(move || {
+        // The watcher stops watching when dropped, so it must live on this thread
+        let _watcher = watcher;
+
+        for event in rx.iter() {
+            let events = match event {
+                // Events were missed, the only way to catch up is a full scan
+                DebouncedEvent::Rescan => Library::scan(&library).unwrap_or_else(|err| {
+                    log::error!("[Library] Rescan failed: {:?}", err);
+                    Vec::new()
+                }),
+                event => {
+                    let events = handle_event(&library, event);
+                    if !events.is_empty() {
+                        if let Err(err) = library.read().unwrap().save() {
+                            log::error!("[Library] Failed to save index: {:?}", err);
+                        }
+                    }
+                    events
+                }
+            };
+
+            for event in events {
+                log::info!("[Library] {:?}", &event);
+                // An error here just means no clients are listening
+                let _ = events_tx.send(api::Event::Library(event));
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Apply a filesystem event to the library. Files are probed without holding
+/// the library's lock, it is only written to once they have been.
+/// ### Returns
+/// `Vec<LibraryEvent>` - The changes made to the library
+fn handle_event(library: &RwLock<Library>, event: DebouncedEvent) -> Vec<LibraryEvent> {
+    match event {
+        DebouncedEvent::Create(path) | DebouncedEvent::Write(path) => Library::update_path(library, &path),
+        DebouncedEvent::Remove(path) => library.write().unwrap().remove_path(&path),
+        DebouncedEvent::Rename(from, to) => {
+            let mut events = library.write().unwrap().rename_path(&from, &to);
+            events.extend(Library::update_path(library, &to));
+            events
+        }
+        DebouncedEvent::Error(err, path) => {
+            log::error!("[Library] Watch error on {:?}: {:?}", path, err);
+            Vec::new()
+        }
+        _ => Vec::new(),
+    }
+}
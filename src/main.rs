@@ -1,6 +1,6 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::{Arc, Mutex, RwLock}};
 use tokio::runtime::Handle;
-use tokio::sync::oneshot;
+use tokio::sync::{broadcast, oneshot};
 
 extern crate ffmpeg_next as ffmpeg; 
 
@@ -8,8 +8,21 @@ mod cast;
 mod server;
 mod video_encoding;
 mod api;
+mod library;
+mod paths;
+mod queue;
+mod subtitles;
 
 use api::Api;
+use library::Library;
+use video_encoding::cache::{self, Cache};
+
+const API_PORT: u16 = 8008;
+const MEDIA_PORT: u16 = 8009;
+const EVENT_BUFFER_SIZE: usize = 256;
+/// Environment variable to set the size cap of the conversion cache in MiB,
+/// 0 disables the cache
+const CACHE_SIZE_VAR: &str = "MUCASTER_CACHE_SIZE";
 
 #[tokio::main]
 async fn main() {
@@ -17,6 +30,33 @@ async fn main() {
         .level(log::LevelFilter::Info)
         .chain(std::io::stdout())
         .apply().unwrap();
+
+    // Library roots are passed as arguments, defaulting to the working directory
+    let mut roots: Vec<PathBuf> = std::env::args().skip(1).map(PathBuf::from).collect();
+    if roots.is_empty() {
+        roots.push(PathBuf::from("."));
+    }
+    let library = match paths::data_dir() {
+        Ok(dir) => Library::open(roots.clone(), dir.join("library.json"))
+            .unwrap_or_else(|err| {
+                log::error!("[Library] Failed to load index, starting fresh: {:?}", err);
+                Library::new(roots)
+            }),
+        Err(err) => {
+            log::error!("[Library] No data directory, library won't be saved: {:?}", err);
+            Library::new(roots)
+        }
+    };
+    let library = Arc::new(RwLock::new(library));
+    if let Err(err) = Library::scan(&library) {
+        log::error!("[Library] Initial scan failed: {:?}", err);
+    }
+
+    // Events are pushed to API clients as they happen
+    let (events_tx, _) = broadcast::channel::<api::Event>(EVENT_BUFFER_SIZE);
+    if let Err(err) = library::watcher::watch(library.clone(), events_tx.clone()) {
+        log::error!("[Library] Failed to watch library, changes won't be seen until a rescan: {:?}", err);
+    }
     
     // Spawn the casting thread
     // this will be where the API is interfaced
@@ -24,14 +64,44 @@ async fn main() {
 
     // Spawn webapp/api server
     let handle = Handle::current();
+    let api_events_tx = events_tx.clone();
     std::thread::spawn(move || {
         handle.spawn( async move {
             let (_shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
-            server::host_api(8008, shutdown_rx, cast_tx).await;
+            server::host_api(API_PORT, shutdown_rx, cast_tx, api_events_tx).await;
+        });
+    });
+
+    // Converted media is kept between casts
+    let cache_size = std::env::var(CACHE_SIZE_VAR).ok()
+        .and_then(|size| size.parse::<u64>().ok())
+        .map_or(cache::DEFAULT_MAX_SIZE, |size| size * 1024 * 1024);
+    let cache = match paths::cache_dir() {
+        Ok(_) if cache_size == 0 => Cache::disabled(),
+        Ok(dir) => Cache::open(dir.join("media"), cache_size)
+            .unwrap_or_else(|err| {
+                log::error!("[Cache] Failed to open, conversions won't be kept: {:?}", err);
+                Cache::disabled()
+            }),
+        Err(err) => {
+            log::error!("[Cache] No cache directory, conversions won't be kept: {:?}", err);
+            Cache::disabled()
+        }
+    };
+    let cache = Arc::new(Mutex::new(cache));
+    let media_cache = cache.clone();
+
+    // Spawn the media server
+    let handle = Handle::current();
+    let media_library = library.clone();
+    std::thread::spawn( move || {
+        handle.spawn( async move {
+            let (_tx, rx) = tokio::sync::oneshot::channel::<()>();
+            server::host_media(MEDIA_PORT, rx, media_library, media_cache).await;
         });
     });
 
-    let mut api = Api::new();
+    let mut api = Api::new(library, cache, MEDIA_PORT, events_tx);
     api.discover_chromecasts().unwrap();
     let chromecasts = api.get_discovered_chromecasts().clone();
     if let Some(cast) = chromecasts.first() {
@@ -42,17 +112,6 @@ async fn main() {
         return;
     }
 
-    let handle = Handle::current();
-    std::thread::spawn( move || {
-        handle.spawn( async move {
-            let (_tx, rx) = tokio::sync::oneshot::channel::<()>();
-            let path = PathBuf::from("sample.mp4");
-            server::host_media(&path, 8009, rx).await;
-        });
-    });
-
-    api.caster.begin_cast(8009).unwrap();
-    
     // API loop
     loop {
         if let Some(request) = cast_rx.recv().await {
@@ -0,0 +1,50 @@
+use std::{env, fs, io, path::PathBuf};
+
+const APP_DIR_NAME: &'static str = "mucaster";
+
+/// Returns the directory persistent data is stored in, creating it if needed.
+/// * Linux - `$XDG_DATA_HOME/mucaster` or `~/.local/share/mucaster`
+/// * Windows - `%APPDATA%\mucaster`
+pub fn data_dir() -> Result<PathBuf, io::Error> {
+    let base = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    }
+    else {
+        env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .filter(|path| path.is_absolute())
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+    };
+
+    let dir = match base {
+        Some(base) => base.join(APP_DIR_NAME),
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, 
+                    "Could not determine the user's data directory.")),
+    };
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Returns the directory disposable data such as converted media is stored in,
+/// creating it if needed.
+/// * Linux - `$XDG_CACHE_HOME/mucaster` or `~/.cache/mucaster`
+/// * Windows - `%LOCALAPPDATA%\mucaster`
+pub fn cache_dir() -> Result<PathBuf, io::Error> {
+    let base = if cfg!(windows) {
+        env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    }
+    else {
+        env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .filter(|path| path.is_absolute())
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+    };
+
+    let dir = match base {
+        Some(base) => base.join(APP_DIR_NAME),
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, 
+                    "Could not determine the user's cache directory.")),
+    };
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
@@ -0,0 +1,29 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum QueueError {
+    /// The entry was never probed, so there is nothing to plan with
+    NotProbed(u32),
+    /// The entry already plays on the chromecast as is
+    AlreadyCompatible(u32),
+    /// The entry is already converted
+    AlreadyConverted(u32),
+    /// The entry is already queued to be converted
+    AlreadyQueued(u32),
+    /// There is no cache to keep the output in
+    CacheDisabled,
+    JobNotFound(u32),
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueueError::NotProbed(id) => write!(f, "Entry {} has not been probed", id),
+            QueueError::AlreadyCompatible(id) => write!(f, "Entry {} already plays on the chromecast", id),
+            QueueError::AlreadyConverted(id) => write!(f, "Entry {} is already converted", id),
+            QueueError::AlreadyQueued(id) => write!(f, "Entry {} is already queued", id),
+            QueueError::CacheDisabled => write!(f, "The cache is disabled"),
+            QueueError::JobNotFound(id) => write!(f, "No job {}", id),
+        }
+    }
+}
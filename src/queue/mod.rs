@@ -0,0 +1,298 @@
+pub mod error;
+
+use crate::{
+    api,
+    library::LibraryEntry,
+    video_encoding::{self, cache::{self, Cache}, compat, progress::Report, transcode::Plan,
+        Action, Chromecast, Progress},
+};
+use serde::{Serialize, Deserialize};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+};
+use tokio::sync::broadcast;
+
+pub type Error = error::QueueError;
+
+/// Number of conversions run at once unless set otherwise
+const DEFAULT_CONCURRENCY: usize = 1;
+
+/// A library entry waiting to be, or being, converted ahead of time.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    pub id: u32,
+    /// Id of the library entry being converted
+    pub entry_id: u32,
+    pub title: String,
+    pub chromecast: Chromecast,
+    pub action: Action,
+    /// Jobs with a higher priority are started first
+    pub priority: i32,
+    pub state: JobState,
+    /// How far along the conversion is, once it has started
+    pub progress: Option<Report>,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    duration: Option<f64>,
+    #[serde(skip)]
+    key: cache::Key,
+    #[serde(skip)]
+    plan: Plan,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Cancelled,
+    Failed(String),
+}
+
+impl JobState {
+    /// Check if the job is waiting or running, rather than over.
+    pub fn is_active(&self) -> bool {
+        matches!(self, JobState::Queued | JobState::Running)
+    }
+}
+
+/// Changes to the queue requested by a client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum QueueCommand {
+    /// Queue the library entry with the matching id for the selected chromecast
+    Add {
+        id: u32,
+        #[serde(default)]
+        priority: i32,
+    },
+    /// Stop a job, whether it is running or still waiting
+    Cancel(u32),
+    SetPriority { job: u32, priority: i32 },
+    /// Set how many jobs can run at once, at least one
+    SetConcurrency(usize),
+    /// Forget jobs that are over
+    ClearFinished,
+}
+
+/// A snapshot of the queue for clients.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueStatus {
+    pub concurrency: usize,
+    pub jobs: Vec<Job>,
+}
+
+/// Converts library entries into the cache in the background, so they are
+/// ready to play without waiting on a live transcode. Jobs run on their own
+/// threads, highest priority first, with at most `concurrency` at once.
+/// Every change of a job's state is pushed to `events_tx`.
+#[derive(Clone)]
+pub struct Queue {
+    inner: Arc<Mutex<Inner>>,
+    cache: Arc<Mutex<Cache>>,
+    events_tx: broadcast::Sender<api::Event>,
+}
+
+struct Inner {
+    jobs: Vec<Job>,
+    /// Progress of the running jobs, by job id
+    running: HashMap<u32, Arc<Progress>>,
+    concurrency: usize,
+    next_id: u32,
+}
+
+impl Queue {
+    /// Creates an empty queue that stores its outputs in `cache`.
+    pub fn new(cache: Arc<Mutex<Cache>>, events_tx: broadcast::Sender<api::Event>) -> Self {
+        let inner = Inner {
+            jobs: Vec::new(),
+            running: HashMap::new(),
+            concurrency: DEFAULT_CONCURRENCY,
+            next_id: 0,
+        };
+        Self { inner: Arc::new(Mutex::new(inner)), cache, events_tx }
+    }
+
+    /// Queue a library entry to be converted for a chromecast generation.
+    /// ### Arguments
+    /// - `entry` - The entry to convert
+    /// - `chromecast` - Generation to convert for
+    /// - `priority` - Jobs with a higher priority are started first
+    /// ### Returns
+    /// The queued job, or an error if there is nothing to do for the entry
+    pub fn add(&self, entry: &LibraryEntry, chromecast: Chromecast, priority: i32) -> Result<Job, Error> {
+        let info = entry.info.as_ref().ok_or(Error::NotProbed(entry.id))?;
+        let verdict = compat::check(info, chromecast);
+        let plan = Plan::new(info, &verdict);
+        let key = match verdict.action {
+            Action::Direct => return Err(Error::AlreadyCompatible(entry.id)),
+            Action::Remux => cache::Key::remux(&entry.path, entry.modified),
+            Action::Transcode => cache::Key::transcode(&entry.path, entry.modified, &plan),
+        };
+        {
+            let cache = self.cache.lock().unwrap();
+            if !cache.is_enabled() {
+                return Err(Error::CacheDisabled);
+            }
+            if cache.contains(&key) {
+                return Err(Error::AlreadyConverted(entry.id));
+            }
+        }
+
+        let job = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.jobs.iter().any(|job| job.state.is_active() && job.key == key) {
+                return Err(Error::AlreadyQueued(entry.id));
+            }
+            let job = Job {
+                id: inner.next_id,
+                entry_id: entry.id,
+                title: entry.title.clone(),
+                chromecast,
+                action: verdict.action,
+                priority,
+                state: JobState::Queued,
+                progress: None,
+                path: entry.path.clone(),
+                duration: info.duration,
+                key,
+                plan,
+            };
+            inner.next_id += 1;
+            inner.jobs.push(job.clone());
+            job
+        };
+
+        log::info!("[Queue] Queued {:?} to {:?} for {:?}", &job.path, job.action, chromecast);
+        let _ = self.events_tx.send(api::Event::Job(job.clone()));
+        self.schedule();
+        Ok(job)
+    }
+
+    /// Stop a job. A running job is cancelled once its conversion notices,
+    /// which happens within a packet.
+    pub fn cancel(&self, id: u32) -> Result<Job, Error> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(progress) = inner.running.get(&id) {
+            progress.cancel();
+        }
+        let job = inner.jobs.iter_mut()
+            .find(|job| job.id == id)
+            .ok_or(Error::JobNotFound(id))?;
+        if job.state == JobState::Queued {
+            job.state = JobState::Cancelled;
+            let _ = self.events_tx.send(api::Event::Job(job.clone()));
+        }
+        log::info!("[Queue] Cancelling job {} for {:?}", id, &job.path);
+        Ok(job.clone())
+    }
+
+    /// Change the priority of a job, which only matters until it starts.
+    pub fn set_priority(&self, id: u32, priority: i32) -> Result<Job, Error> {
+        let job = {
+            let mut inner = self.inner.lock().unwrap();
+            let job = inner.jobs.iter_mut()
+                .find(|job| job.id == id)
+                .ok_or(Error::JobNotFound(id))?;
+            job.priority = priority;
+            job.clone()
+        };
+        let _ = self.events_tx.send(api::Event::Job(job.clone()));
+        self.schedule();
+        Ok(job)
+    }
+
+    /// Forget the jobs that are over.
+    pub fn clear_finished(&self) {
+        self.inner.lock().unwrap().jobs.retain(|job| job.state.is_active());
+    }
+
+    /// Set how many jobs can run at once. Lowering it lets running jobs finish.
+    pub fn set_concurrency(&self, concurrency: usize) {
+        self.inner.lock().unwrap().concurrency = concurrency.max(1);
+        log::info!("[Queue] Running up to {} jobs at once", concurrency.max(1));
+        self.schedule();
+    }
+
+    /// Returns every job and how far along they are.
+    pub fn status(&self) -> QueueStatus {
+        let inner = self.inner.lock().unwrap();
+        QueueStatus { concurrency: inner.concurrency, jobs: inner.jobs.clone() }
+    }
+
+    /// Start the highest priority jobs until the concurrency limit is reached.
+    fn schedule(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        while inner.running.len() < inner.concurrency {
+            // Highest priority first, then oldest first
+            let next = inner.jobs.iter_mut()
+                .filter(|job| job.state == JobState::Queued)
+                .max_by_key(|job| (job.priority, std::cmp::Reverse(job.id)));
+            let job = match next {
+                Some(job) => job,
+                None => break,
+            };
+            job.state = JobState::Running;
+            let job = job.clone();
+
+            let (progress, reports) = Progress::with_reports(job.duration);
+            let progress = Arc::new(progress);
+            inner.running.insert(job.id, progress.clone());
+            let _ = self.events_tx.send(api::Event::Job(job.clone()));
+
+            // Reports stop once the job is over and its progress dropped
+            let queue = self.clone();
+            let id = job.id;
+            thread::spawn(move || {
+                for report in reports {
+                    queue.set_progress(id, report);
+                }
+            });
+
+            let queue = self.clone();
+            thread::spawn(move || {
+                log::info!("[Queue] Starting job {} for {:?}", job.id, &job.path);
+                let result = cache::store(&queue.cache, &job.key, |writer| match job.action {
+                    Action::Transcode => video_encoding::transcode::transcode(
+                        &job.path, &job.plan, writer, Some(&progress)),
+                    _ => video_encoding::remux(&job.path, writer, Some(&progress)),
+                });
+                queue.finish(job.id, result);
+            });
+        }
+    }
+
+    /// Record a progress report of a running job, pushing it to clients.
+    fn set_progress(&self, id: u32, report: Report) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(job) = inner.jobs.iter_mut().find(|job| job.id == id && job.state == JobState::Running) {
+            job.progress = Some(report);
+            let _ = self.events_tx.send(api::Event::Job(job.clone()));
+        }
+    }
+
+    /// Record the outcome of a job and start the next one.
+    fn finish(&self, id: u32, result: Result<(), video_encoding::Error>) {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let progress = inner.running.remove(&id);
+            if let Some(job) = inner.jobs.iter_mut().find(|job| job.id == id) {
+                job.progress = progress.map(|progress| progress.report());
+                job.state = match result {
+                    Ok(()) => JobState::Done,
+                    Err(video_encoding::Error::Cancelled) => JobState::Cancelled,
+                    Err(err) => JobState::Failed(err.to_string()),
+                };
+                log::info!("[Queue] Job {} for {:?} ended: {:?}", id, &job.path, &job.state);
+                let _ = self.events_tx.send(api::Event::Job(job.clone()));
+            }
+        }
+        self.schedule();
+    }
+}
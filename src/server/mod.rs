@@ -1,19 +1,99 @@
-use crate::api;
+use crate::{api, cast::device::TextTrackStyle, library::{details, Library, LibraryEntry}, queue::QueueCommand, subtitles, video_encoding::{self, cache::{self, Cache}, hls, transcode::{self, TranscodeQuery}, Chromecast}};
 
-use std::path::Path;
-use tokio::sync::{ oneshot, mpsc };
-use warp::{reply::Response, Filter};
+use futures_util::{future, stream, StreamExt};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque}, convert::Infallible, io::{SeekFrom, Write}, path::Path,
+    sync::{mpsc::Receiver, Arc, Mutex, RwLock},
+};
+use tokio::{io::AsyncReadExt, sync::{ broadcast, oneshot, mpsc }};
+use warp::{http::{header, HeaderValue, StatusCode}, hyper::{body::Bytes, Body}, reply::Response, Filter};
+
+/// Size of the chunks read from disk when streaming a file
+const CHUNK_SIZE: u64 = 64 * 1024;
+/// Number of HLS playlists kept, the least recently used are dropped past it
+const MAX_PLAYLISTS: usize = 32;
+
+/// Query of a subtitles request, e.g. `?offset=-1.5`
+#[derive(Debug, Deserialize)]
+struct SubtitlesQuery {
+    /// Seconds to show the cues later by, negative to show them earlier
+    #[serde(default)]
+    offset: f64,
+}
+
+/// Identifies a playlist by entry id, modification time and plan profile
+type PlaylistKey = (u32, u64, String);
+
+/// Playlists already split for an entry, as finding the cuts of copied video
+/// means reading the file. Only the `MAX_PLAYLISTS` most recently used are kept.
+#[derive(Default)]
+struct PlaylistCache {
+    playlists: HashMap<PlaylistKey, Arc<hls::Playlist>>,
+    /// Keys of the playlists, least recently used first
+    recent: VecDeque<PlaylistKey>,
+}
+
+impl PlaylistCache {
+    /// Look up a playlist, marking it as used.
+    fn get(&mut self, key: &PlaylistKey) -> Option<Arc<hls::Playlist>> {
+        let playlist = self.playlists.get(key)?.clone();
+        self.touch(key);
+        Some(playlist)
+    }
+
+    /// Keep a playlist, dropping the least recently used past `MAX_PLAYLISTS`.
+    fn insert(&mut self, key: PlaylistKey, playlist: Arc<hls::Playlist>) {
+        if self.playlists.insert(key.clone(), playlist).is_some() {
+            self.touch(&key);
+        } else {
+            self.recent.push_back(key);
+        }
+        while self.recent.len() > MAX_PLAYLISTS {
+            if let Some(oldest) = self.recent.pop_front() {
+                self.playlists.remove(&oldest);
+            }
+        }
+    }
+
+    /// Move a key to the back of the use order.
+    fn touch(&mut self, key: &PlaylistKey) {
+        if let Some(position) = self.recent.iter().position(|recent| recent == key) {
+            if let Some(key) = self.recent.remove(position) {
+                self.recent.push_back(key);
+            }
+        }
+    }
+}
+
+type Playlists = Arc<Mutex<PlaylistCache>>;
 
 /// Convert a json input into a CastSignal
 fn json_to_signal() -> impl Filter<Extract = (api::CastSignal,), Error = warp::Rejection> + Clone {
     warp::body::content_length_limit(1024).and(warp::body::json())
 }
 
+/// Convert a json input into a QueueCommand
+fn json_to_queue_command() -> impl Filter<Extract = (QueueCommand,), Error = warp::Rejection> + Clone {
+    warp::body::content_length_limit(1024).and(warp::body::json())
+}
+
+/// Convert a json input into a TextTrackStyle
+fn json_to_text_track_style() -> impl Filter<Extract = (TextTrackStyle,), Error = warp::Rejection> + Clone {
+    warp::body::content_length_limit(1024).and(warp::body::json())
+}
+
+/// Convert a json input into a chromecast generation
+fn json_to_generation() -> impl Filter<Extract = (Chromecast,), Error = warp::Rejection> + Clone {
+    warp::body::content_length_limit(1024).and(warp::body::json())
+}
+
 /// Launches a warp server to host the web interface. This includes the webapp
 /// and the api.
 pub async fn host_api(port: u16, 
     shutdown_rx: oneshot::Receiver<()>,
-    api_tx: mpsc::Sender<api::Request>) {
+    api_tx: mpsc::Sender<api::Request>,
+    events_tx: broadcast::Sender<api::Event>) {
     
     let webapp = warp::get().and(
         warp::fs::dir("webapp/dist/mucast-frontend")  
@@ -29,6 +109,15 @@ pub async fn host_api(port: u16,
         .and(tx_filter.clone())
         .and_then(put_cast_signal);
 
+    let put_generation = warp::put()
+        .and(warp::path("api"))
+        .and(warp::path("chromecast"))
+        .and(warp::path("generation"))
+        .and(warp::path::end())
+        .and(json_to_generation())
+        .and(tx_filter.clone())
+        .and_then(put_chromecast_generation);
+
     let get_media_status = warp::get()
         .and(warp::path("api"))
         .and(warp::path("media-status"))
@@ -36,10 +125,98 @@ pub async fn host_api(port: u16,
         .and(tx_filter.clone())
         .and_then(get_media_status);
 
+    let get_connection = warp::get()
+        .and(warp::path("api"))
+        .and(warp::path("connection"))
+        .and(warp::path::end())
+        .and(tx_filter.clone())
+        .and_then(get_connection);
+
+    let get_device_status = warp::get()
+        .and(warp::path("api"))
+        .and(warp::path("device"))
+        .and(warp::path::end())
+        .and(tx_filter.clone())
+        .and_then(get_device_status);
+
+    let get_library = warp::get()
+        .and(warp::path("api"))
+        .and(warp::path("library"))
+        .and(warp::path::end())
+        .and(tx_filter.clone())
+        .and_then(get_library);
+
+    let get_tracks = warp::get()
+        .and(warp::path("api"))
+        .and(warp::path("library"))
+        .and(warp::path::param::<u32>())
+        .and(warp::path("tracks"))
+        .and(warp::path::end())
+        .and(tx_filter.clone())
+        .and_then(get_tracks);
+
+    let put_scan_library = warp::put()
+        .and(warp::path("api"))
+        .and(warp::path("library"))
+        .and(warp::path("scan"))
+        .and(warp::path::end())
+        .and(tx_filter.clone())
+        .and_then(put_scan_library);
+
+    let get_queue = warp::get()
+        .and(warp::path("api"))
+        .and(warp::path("queue"))
+        .and(warp::path::end())
+        .and(tx_filter.clone())
+        .and_then(get_queue);
+
+    let put_queue = warp::put()
+        .and(warp::path("api"))
+        .and(warp::path("queue"))
+        .and(warp::path::end())
+        .and(json_to_queue_command())
+        .and(tx_filter.clone())
+        .and_then(put_queue);
+
+    let get_subtitles = warp::get()
+        .and(warp::path("api"))
+        .and(warp::path("subtitles"))
+        .and(warp::path::end())
+        .and(tx_filter.clone())
+        .and_then(get_subtitle_settings);
+
+    let put_subtitle_style = warp::put()
+        .and(warp::path("api"))
+        .and(warp::path("subtitles"))
+        .and(warp::path("style"))
+        .and(warp::path::end())
+        .and(json_to_text_track_style())
+        .and(tx_filter.clone())
+        .and_then(put_subtitle_style);
+
+    let events_filter = warp::any().map(move || events_tx.subscribe());
+    let get_events = warp::get()
+        .and(warp::path("api"))
+        .and(warp::path("events"))
+        .and(warp::path::end())
+        .and(events_filter)
+        .map(get_events);
+
     let route = warp::any().and(
         webapp
             .or(put_signals)
+            .or(put_generation)
             .or(get_media_status)
+            .or(get_connection)
+            .or(get_device_status)
+            .or(get_library)
+            .or(get_tracks)
+            .or(put_scan_library)
+            .or(get_queue)
+            .or(put_queue)
+            .or(get_subtitles)
+            .or(put_subtitle_style)
+            .or(get_events)
     );
 
     let addr = ([0,0,0,0], port);
@@ -67,6 +244,162 @@ async fn get_media_status(mut api_tx: mpsc::Sender<api::Request>)
     }
 }
 
+/// Get request function to reply with the state of the connection to the chromecast
+async fn get_connection(mut api_tx: mpsc::Sender<api::Request>)
+    -> Result<impl warp::Reply, warp::Rejection> {
+
+    let (req_tx, req_rx) = oneshot::channel::<String>();
+    let request = api::Request::Get(api::GetType::Connection, req_tx);
+    api_tx.send( request ).await.unwrap();
+
+    match await_api_response(req_rx) {
+        Ok(resp) => Ok(Response::new(resp.into())),
+        Err(_) => Err(warp::reject::reject()),
+    }
+}
+
+/// Get request function to reply with what the chromecast is doing
+async fn get_device_status(mut api_tx: mpsc::Sender<api::Request>)
+    -> Result<impl warp::Reply, warp::Rejection> {
+
+    let (req_tx, req_rx) = oneshot::channel::<String>();
+    let request = api::Request::Get(api::GetType::Device, req_tx);
+    api_tx.send( request ).await.unwrap();
+
+    match await_api_response(req_rx) {
+        Ok(resp) => Ok(Response::new(resp.into())),
+        Err(_) => Err(warp::reject::reject()),
+    }
+}
+
+async fn get_library(mut api_tx: mpsc::Sender<api::Request>)
+    -> Result<impl warp::Reply, warp::Rejection> {
+
+    let (req_tx, req_rx) = oneshot::channel::<String>();
+    let request = api::Request::Get(api::GetType::Library, req_tx);
+    api_tx.send( request ).await.unwrap();
+
+    match await_api_response(req_rx) {
+        Ok(resp) => Ok(Response::new(resp.into())),
+        Err(_) => Err(warp::reject::reject()),
+    }
+}
+
+async fn get_queue(mut api_tx: mpsc::Sender<api::Request>)
+    -> Result<impl warp::Reply, warp::Rejection> {
+
+    let (req_tx, req_rx) = oneshot::channel::<String>();
+    let request = api::Request::Get(api::GetType::Queue, req_tx);
+    api_tx.send( request ).await.unwrap();
+
+    match await_api_response(req_rx) {
+        Ok(resp) => Ok(Response::new(resp.into())),
+        Err(_) => Err(warp::reject::reject()),
+    }
+}
+
+/// Put request function to add, cancel or reprioritize pre-conversion jobs
+async fn put_queue(
+    command: QueueCommand,
+    mut api_tx: mpsc::Sender<api::Request>)
+    -> Result<impl warp::Reply, warp::Rejection> {
+
+    let (req_tx, req_rx) = oneshot::channel::<String>();
+    let request = api::Request::Put(api::PutType::Queue(command), req_tx);
+    api_tx.send( request ).await.unwrap();
+
+    match await_api_response(req_rx) {
+        Ok(resp) => Ok(warp::reply::with_status( resp, StatusCode::OK )),
+        Err(_) => Err(warp::reject::reject()),
+    }
+}
+
+/// Get request function to reply with the offset and style subtitles are shown with
+async fn get_subtitle_settings(mut api_tx: mpsc::Sender<api::Request>)
+    -> Result<impl warp::Reply, warp::Rejection> {
+
+    let (req_tx, req_rx) = oneshot::channel::<String>();
+    let request = api::Request::Get(api::GetType::Subtitles, req_tx);
+    api_tx.send( request ).await.unwrap();
+
+    match await_api_response(req_rx) {
+        Ok(resp) => Ok(Response::new(resp.into())),
+        Err(_) => Err(warp::reject::reject()),
+    }
+}
+
+/// Put request function to change how subtitles are drawn
+async fn put_subtitle_style(
+    style: TextTrackStyle,
+    mut api_tx: mpsc::Sender<api::Request>)
+    -> Result<impl warp::Reply, warp::Rejection> {
+
+    let (req_tx, req_rx) = oneshot::channel::<String>();
+    let request = api::Request::Put(api::PutType::SubtitleStyle(style), req_tx);
+    api_tx.send( request ).await.unwrap();
+
+    match await_api_response(req_rx) {
+        Ok(resp) => Ok(warp::reply::with_status( resp, StatusCode::OK )),
+        Err(_) => Err(warp::reject::reject()),
+    }
+}
+
+/// Get request function to list the tracks of a library entry that can be
+/// chosen from when casting it
+async fn get_tracks(id: u32, mut api_tx: mpsc::Sender<api::Request>)
+    -> Result<impl warp::Reply, warp::Rejection> {
+
+    let (req_tx, req_rx) = oneshot::channel::<String>();
+    let request = api::Request::Get(api::GetType::Tracks(id), req_tx);
+    api_tx.send( request ).await.unwrap();
+
+    match await_api_response(req_rx) {
+        Ok(resp) => Ok(Response::new(resp.into())),
+        Err(_) => Err(warp::reject::reject()),
+    }
+}
+
+/// Get request function that opens a server-sent event stream. Every `api::Event`
+/// is sent to the client as JSON until they disconnect.
+fn get_events(events_rx: broadcast::Receiver<api::Event>) -> impl warp::Reply {
+    // Events missed by a slow client are skipped rather than ending the stream
+    let events = events_rx.filter_map(|event| future::ready(match event {
+        Ok(event) => Some(Ok::<_, Infallible>(warp::sse::json(event))),
+        Err(_) => None,
+    }));
+
+    warp::sse::reply(warp::sse::keep_alive().stream(events))
+}
+
+/// Put request function to ask the API to rescan the library
+async fn put_scan_library(mut api_tx: mpsc::Sender<api::Request>)
+    -> Result<impl warp::Reply, warp::Rejection> {
+
+    let (req_tx, req_rx) = oneshot::channel::<String>();
+    let request = api::Request::Put(api::PutType::ScanLibrary, req_tx);
+    api_tx.send( request ).await.unwrap();
+
+    match await_api_response(req_rx) {
+        Ok(resp) => Ok(warp::reply::with_status( resp, StatusCode::OK )),
+        Err(_) => Err(warp::reject::reject()),
+    }
+}
+
+/// Put request function to set the generation of the selected chromecast
+async fn put_chromecast_generation(
+    generation: Chromecast,
+    mut api_tx: mpsc::Sender<api::Request>)
+    -> Result<impl warp::Reply, warp::Rejection> {
+
+    let (req_tx, req_rx) = oneshot::channel::<String>();
+    let request = api::Request::Put(api::PutType::ChromecastGeneration(generation), req_tx);
+    api_tx.send( request ).await.unwrap();
+
+    match await_api_response(req_rx) {
+        Ok(resp) => Ok(warp::reply::with_status( resp, StatusCode::OK )),
+        Err(_) => Err(warp::reject::reject()),
+    }
+}
 
 /// Put request function to send a CastSignal request to the API
 async fn put_cast_signal(
@@ -106,11 +439,107 @@ pub fn await_api_response(mut rx: oneshot::Receiver<String>) -> Result<String, S
 }
 
 
-/// Opens a warp server to host a media file at the specified path and port.
+/// Opens a warp server to host the library's media files on the specified port.
+/// Each entry is served at `/media/<id>`, where id is its library id, remuxed
+/// into MP4 at `/media/<id>/remux` and converted for a chromecast generation
+/// at `/media/<id>/transcode?chromecast=<generation>`. The converted media is also
+/// served as HLS at `/media/<id>/hls/playlist.m3u8?chromecast=<generation>`.
+/// Both conversions take `&audio=<index>` to play an audio track other than the default,
+/// and `&burn_in=<index>` to draw a bitmap subtitle stream onto the video.
+/// Subtitle tracks are served as WebVTT at `/media/<id>/subtitles/<track>.vtt`,
+/// shifted by `?offset=<seconds>` when they are out of sync, and a poster found
+/// next to the file at `/media/<id>/poster`.
+/// Converted media is kept in `cache`, and served from there when it has
+/// been converted before.
 /// A shutdown reciever is used to close the media server gracefully when requested.
-#[allow(dead_code)]
-pub async fn host_media(file: &Path, port: u16, shutdown_rx: oneshot::Receiver<()>) {
-    let route = warp::fs::file(file.to_path_buf());
+pub async fn host_media(port: u16,
+    shutdown_rx: oneshot::Receiver<()>,
+    library: Arc<RwLock<Library>>,
+    cache: Arc<Mutex<Cache>>) {
+
+    let library_filter = warp::any().map(move || library.clone());
+    let cache_filter = warp::any().map(move || cache.clone());
+    let get_media = warp::get()
+        .and(warp::path("media"))
+        .and(warp::path::param::<u32>())
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("range"))
+        .and(library_filter.clone())
+        .and_then(get_media);
+
+    let get_remuxed_media = warp::get()
+        .and(warp::path("media"))
+        .and(warp::path::param::<u32>())
+        .and(warp::path("remux"))
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("range"))
+        .and(library_filter.clone())
+        .and(cache_filter.clone())
+        .and_then(get_remuxed_media);
+
+    let get_transcoded_media = warp::get()
+        .and(warp::path("media"))
+        .and(warp::path::param::<u32>())
+        .and(warp::path("transcode"))
+        .and(warp::path::end())
+        .and(warp::query::<TranscodeQuery>())
+        .and(warp::header::optional::<String>("range"))
+        .and(library_filter.clone())
+        .and(cache_filter.clone())
+        .and_then(get_transcoded_media);
+
+    let playlists: Playlists = Arc::new(Mutex::new(PlaylistCache::default()));
+    let playlists_filter = warp::any().map(move || playlists.clone());
+    let get_hls_playlist = warp::get()
+        .and(warp::path("media"))
+        .and(warp::path::param::<u32>())
+        .and(warp::path("hls"))
+        .and(warp::path("playlist.m3u8"))
+        .and(warp::path::end())
+        .and(warp::query::<TranscodeQuery>())
+        .and(library_filter.clone())
+        .and(playlists_filter.clone())
+        .and_then(get_hls_playlist);
+
+    let get_hls_segment = warp::get()
+        .and(warp::path("media"))
+        .and(warp::path::param::<u32>())
+        .and(warp::path("hls"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::query::<TranscodeQuery>())
+        .and(library_filter.clone())
+        .and(playlists_filter)
+        .and(cache_filter.clone())
+        .and_then(get_hls_segment);
+
+    let get_subtitles = warp::get()
+        .and(warp::path("media"))
+        .and(warp::path::param::<u32>())
+        .and(warp::path("subtitles"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::query::<SubtitlesQuery>())
+        .and(library_filter.clone())
+        .and(cache_filter)
+        .and_then(get_subtitles);
+
+    let get_poster = warp::get()
+        .and(warp::path("media"))
+        .and(warp::path::param::<u32>())
+        .and(warp::path("poster"))
+        .and(warp::path::end())
+        .and(library_filter)
+        .and_then(get_poster);
+
+    let route = get_media
+        .or(get_remuxed_media)
+        .or(get_transcoded_media)
+        .or(get_hls_playlist)
+        .or(get_hls_segment)
+        .or(get_subtitles)
+        .or(get_poster);
+
     let addr = ([0,0,0,0], port);
     let (_, server) = warp::serve(route)
         .bind_with_graceful_shutdown(addr, async {
@@ -119,3 +548,460 @@ pub async fn host_media(file: &Path, port: u16, shutdown_rx: oneshot::Receiver<(
     
     server.await;
 }
+
+/// Get request function to stream a library entry's file to the client.
+async fn get_media(id: u32, range: Option<String>, library: Arc<RwLock<Library>>)
+    -> Result<Response, warp::Rejection> {
+
+    let entry = match library.read().unwrap().get(id) {
+        Some(entry) => entry.clone(),
+        None => return Err(warp::reject::not_found()),
+    };
+    
+    serve_file(&entry.path, range, entry.content_type()).await
+}
+
+/// Get request function to serve the poster of a library entry.
+async fn get_poster(id: u32, library: Arc<RwLock<Library>>)
+    -> Result<Response, warp::Rejection> {
+
+    let entry = match library.read().unwrap().get(id) {
+        Some(entry) => entry.clone(),
+        None => return Err(warp::reject::not_found()),
+    };
+
+    match entry.poster() {
+        Some(path) => serve_file(&path, None, details::poster_content_type(&path)).await,
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+/// Get request function to stream a library entry remuxed into fragmented MP4
+/// on the fly. The output is produced as it is sent, so ranges are only
+/// supported once it has been cached.
+async fn get_remuxed_media(id: u32, range: Option<String>,
+    library: Arc<RwLock<Library>>, cache: Arc<Mutex<Cache>>)
+    -> Result<Response, warp::Rejection> {
+
+    let entry = match library.read().unwrap().get(id) {
+        Some(entry) => entry.clone(),
+        None => return Err(warp::reject::not_found()),
+    };
+
+    let key = cache::Key::remux(&entry.path, entry.modified);
+    let cached = cache.lock().unwrap().get(&key);
+    if let Some(path) = cached {
+        return serve_file(&path, range, "video/mp4").await;
+    }
+
+    let name = format!("remux of {:?}", &entry.path);
+    let chunks = cache::spawn(cache, key, name, move |writer| video_encoding::remux(&entry.path, writer, None));
+
+    let mut resp = Response::new(channel_body(chunks));
+    resp.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("video/mp4"));
+    Ok(resp)
+}
+
+/// Get request function to stream a library entry converted on the fly for
+/// a chromecast generation. Incompatible streams are transcoded, the rest are
+/// copied. The output is produced as it is sent, so ranges are only supported
+/// once it has been cached.
+async fn get_transcoded_media(id: u32, query: TranscodeQuery, range: Option<String>,
+    library: Arc<RwLock<Library>>, cache: Arc<Mutex<Cache>>)
+    -> Result<Response, warp::Rejection> {
+
+    let entry = match library.read().unwrap().get(id) {
+        Some(entry) => entry.clone(),
+        None => return Err(warp::reject::not_found()),
+    };
+    let info = match &entry.info {
+        Some(info) => info,
+        None => {
+            log::warn!("[Server] Can't transcode {:?}, it was never probed", &entry.path);
+            return Ok(encoding_error_reply(video_encoding::Error::NotProbed));
+        }
+    };
+
+    let plan = match query.plan(info) {
+        Ok(plan) => plan,
+        Err(err) => return Ok(encoding_error_reply(err)),
+    };
+
+    let key = cache::Key::transcode(&entry.path, entry.modified, &plan);
+    let cached = cache.lock().unwrap().get(&key);
+    if let Some(path) = cached {
+        return serve_file(&path, range, "video/mp4").await;
+    }
+
+    let name = format!("transcode of {:?} with {:?}", &entry.path, &plan);
+    let chunks = cache::spawn(cache, key, name, move |writer| transcode::transcode(&entry.path, &plan, writer, None));
+
+    let mut resp = Response::new(channel_body(chunks));
+    resp.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("video/mp4"));
+    Ok(resp)
+}
+
+/// Get request function to reply with the HLS playlist of a library entry
+/// converted for a chromecast generation. Segments are listed relative to
+/// the playlist and keep its query.
+async fn get_hls_playlist(id: u32, query: TranscodeQuery,
+    library: Arc<RwLock<Library>>, playlists: Playlists)
+    -> Result<Response, warp::Rejection> {
+
+    let entry = match library.read().unwrap().get(id) {
+        Some(entry) => entry.clone(),
+        None => return Err(warp::reject::not_found()),
+    };
+
+    let query_string = query.to_query_string();
+    let playlist = tokio::task::spawn_blocking(move || hls_playlist(&entry, &query, &playlists)).await;
+    let playlist = match playlist {
+        Ok(Ok((_, playlist))) => playlist,
+        Ok(Err(err)) => return Ok(encoding_error_reply(err)),
+        Err(err) => return Ok(error_reply(format!("{}", err).into(), StatusCode::INTERNAL_SERVER_ERROR)),
+    };
+
+    let m3u8 = playlist.to_m3u8(|index| format!("{}.ts?{}", index, &query_string));
+    let mut resp = Response::new(m3u8.into());
+    resp.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(hls::CONTENT_TYPE));
+    Ok(resp)
+}
+
+/// Get request function to stream one HLS segment of a library entry, named
+/// `<index>.ts`. Segments are converted when they are requested, so the
+/// receiver can seek anywhere in the playlist.
+async fn get_hls_segment(id: u32, name: String, query: TranscodeQuery,
+    library: Arc<RwLock<Library>>, playlists: Playlists, cache: Arc<Mutex<Cache>>)
+    -> Result<Response, warp::Rejection> {
+
+    let index: usize = match name.strip_suffix(".ts").and_then(|index| index.parse().ok()) {
+        Some(index) => index,
+        None => return Err(warp::reject::not_found()),
+    };
+    let entry = match library.read().unwrap().get(id) {
+        Some(entry) => entry.clone(),
+        None => return Err(warp::reject::not_found()),
+    };
+
+    let playlist_entry = entry.clone();
+    let playlist = tokio::task::spawn_blocking(move || hls_playlist(&playlist_entry, &query, &playlists)).await;
+    let (plan, playlist) = match playlist {
+        Ok(Ok(playlist)) => playlist,
+        Ok(Err(err)) => return Ok(encoding_error_reply(err)),
+        Err(err) => return Ok(error_reply(format!("{}", err).into(), StatusCode::INTERNAL_SERVER_ERROR)),
+    };
+    let segment = match playlist.segments.get(index) {
+        Some(segment) => *segment,
+        None => return Err(warp::reject::not_found()),
+    };
+
+    let key = cache::Key::segment(&entry.path, entry.modified, &plan, index);
+    let cached = cache.lock().unwrap().get(&key);
+    if let Some(path) = cached {
+        return serve_file(&path, None, hls::SEGMENT_CONTENT_TYPE).await;
+    }
+
+    let name = format!("segment {} of {:?}", index, &entry.path);
+    let chunks = cache::spawn(cache, key, name, move |writer| hls::segment(&entry.path, &plan, &segment, writer));
+
+    let mut resp = Response::new(channel_body(chunks));
+    resp.headers_mut().insert(header::CONTENT_TYPE, 
+        HeaderValue::from_static(hls::SEGMENT_CONTENT_TYPE));
+    Ok(resp)
+}
+
+/// Get request function to reply with a subtitle track of a library entry
+/// converted to WebVTT, named `<track>.vtt`. The receiver fetches text tracks
+/// from its own origin, so they are served to any origin.
+async fn get_subtitles(id: u32, name: String, query: SubtitlesQuery,
+    library: Arc<RwLock<Library>>, cache: Arc<Mutex<Cache>>)
+    -> Result<Response, warp::Rejection> {
+
+    let track_id: usize = match name.strip_suffix(".vtt").and_then(|id| id.parse().ok()) {
+        Some(track_id) => track_id,
+        None => return Err(warp::reject::not_found()),
+    };
+    let entry = match library.read().unwrap().get(id) {
+        Some(entry) => entry.clone(),
+        None => return Err(warp::reject::not_found()),
+    };
+
+    let vtt = tokio::task::spawn_blocking(move || subtitles_webvtt(&entry, track_id, &cache)).await;
+    let vtt = match vtt {
+        Ok(Ok(vtt)) => vtt,
+        Ok(Err(err)) => {
+            log::warn!("[Server] Failed to serve subtitles {} of entry {}: {}", track_id, id, err);
+            let status = match &err {
+                subtitles::Error::TrackNotFound(_) => StatusCode::NOT_FOUND,
+                subtitles::Error::EncodingError(video_encoding::Error::UnsupportedCodec(_)) => {
+                    StatusCode::UNPROCESSABLE_ENTITY
+                }
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            return Ok(error_reply(err.into(), status));
+        }
+        Err(err) => return Ok(error_reply(format!("{}", err).into(), StatusCode::INTERNAL_SERVER_ERROR)),
+    };
+
+    let vtt = match query.offset {
+        offset if offset != 0.0 => subtitles::shift_webvtt(&vtt, offset),
+        _ => vtt,
+    };
+
+    let mut resp = Response::new(vtt.into());
+    let headers = resp.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(subtitles::CONTENT_TYPE));
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+    Ok(resp)
+}
+
+/// Convert a subtitle track of an entry into WebVTT. Embedded tracks are
+/// decoded from the whole file, so they are kept in the cache.
+/// This reads the file, so it blocks.
+fn subtitles_webvtt(entry: &LibraryEntry, track_id: usize, cache: &Mutex<Cache>)
+    -> Result<String, subtitles::Error> {
+
+    let track = subtitles::find(&entry.path, entry.info.as_ref())
+        .into_iter()
+        .find(|track| track.id == track_id)
+        .ok_or(subtitles::Error::TrackNotFound(track_id))?;
+    let key = match &track.source {
+        subtitles::Source::Embedded { index, .. } => cache::Key::subtitles(&entry.path, entry.modified, *index),
+        subtitles::Source::Sidecar { .. } => return subtitles::to_webvtt(&entry.path, &track),
+    };
+
+    let cached = cache.lock().unwrap().get(&key);
+    if let Some(path) = cached {
+        return Ok(std::fs::read_to_string(path)?);
+    }
+    let vtt = subtitles::to_webvtt(&entry.path, &track)?;
+    cache::store(cache, &key, |mut writer| Ok(writer.write_all(vtt.as_bytes())?))?;
+    Ok(vtt)
+}
+
+/// Plan the conversion of an entry asked for by `query` and split it into
+/// HLS segments, reusing the segments while the file is unchanged.
+/// This reads the file, so it blocks.
+fn hls_playlist(entry: &LibraryEntry, query: &TranscodeQuery, playlists: &Playlists)
+    -> Result<(transcode::Plan, Arc<hls::Playlist>), video_encoding::Error> {
+
+    let info = match &entry.info {
+        Some(info) => info,
+        None => {
+            log::warn!("[Server] Can't transcode {:?}, it was never probed", &entry.path);
+            return Err(video_encoding::Error::NotProbed);
+        }
+    };
+    let plan = query.plan(info)?;
+
+    let key = (entry.id, entry.modified, plan.profile());
+    if let Some(playlist) = playlists.lock().unwrap().get(&key) {
+        return Ok((plan, playlist));
+    }
+
+    let playlist = match hls::Playlist::new(&entry.path, info, &plan) {
+        Ok(playlist) => Arc::new(playlist),
+        Err(err) => {
+            log::warn!("[Server] Failed to split {:?} into segments: {}", &entry.path, err);
+            return Err(err);
+        }
+    };
+    playlists.lock().unwrap().insert(key, playlist.clone());
+    Ok((plan, playlist))
+}
+
+/// Reply to a client with an error as JSON.
+fn error_reply(err: api::Error, status: StatusCode) -> Response {
+    let mut resp = Response::new(err.to_json().into());
+    *resp.status_mut() = status;
+    resp.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    resp
+}
+
+/// Reply to a client with an error that stopped media from being converted.
+fn encoding_error_reply(err: video_encoding::Error) -> Response {
+    let status = match &err {
+        video_encoding::Error::StreamNotFound(_) => StatusCode::NOT_FOUND,
+        video_encoding::Error::NotProbed
+        | video_encoding::Error::UnknownDuration
+        | video_encoding::Error::UnsupportedCodec(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    error_reply(err.into(), status)
+}
+
+/// Wrap a channel of chunks produced on another thread in a response body.
+/// The producer is stopped by the channel closing once the client disconnects.
+fn channel_body(chunks: Receiver<Vec<u8>>) -> Body {
+    let body = stream::unfold(chunks, |chunks| async move {
+        // Receiving blocks, so wait for each chunk off the async threads
+        let (chunk, chunks) = tokio::task::spawn_blocking(move || (chunks.recv(), chunks))
+            .await
+            .ok()?;
+        chunk.ok().map(|chunk| (Ok::<_, Infallible>(Bytes::from(chunk)), chunks))
+    });
+    Body::wrap_stream(body)
+}
+
+/// Reply with the contents of a file. A single `Range: bytes=` request is
+/// honoured, as the Chromecast relies on them for seeking.
+pub async fn serve_file(path: &Path, range: Option<String>, content_type: &str)
+    -> Result<Response, warp::Rejection> {
+
+    let mut file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(err) => {
+            log::warn!("[Server] Failed to open {:?}: {:?}", path, err);
+            return Err(warp::reject::not_found());
+        }
+    };
+    let len = match file.metadata().await {
+        Ok(meta) => meta.len(),
+        Err(_) => return Err(warp::reject::not_found()),
+    };
+
+    // Determine the inclusive byte range to send
+    let requested = range.as_ref().and_then(|range| parse_range(range, len));
+    if range.is_some() && requested.is_none() {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+        resp.headers_mut().insert(header::CONTENT_RANGE, 
+            HeaderValue::from_str(&format!("bytes */{}", len)).unwrap());
+        return Ok(resp);
+    }
+    let (start, end) = requested.unwrap_or((0, len.saturating_sub(1)));
+    
+    if start > 0 && file.seek(SeekFrom::Start(start)).await.is_err() {
+        return Err(warp::reject::not_found());
+    }
+
+    // Stream the file from disk in chunks
+    let remaining = if len == 0 { 0 } else { end - start + 1 };
+    let body = stream::unfold((file, remaining), |(mut file, remaining)| async move {
+        if remaining == 0 {
+            return None;
+        }
+        let mut buf = vec![0u8; remaining.min(CHUNK_SIZE) as usize];
+        match file.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(Bytes::from(buf)), (file, remaining - n as u64)))
+            },
+            Err(err) => Some((Err(err), (file, 0))),
+        }
+    });
+
+    let mut resp = Response::new(Body::wrap_stream(body));
+    let headers = resp.headers_mut();
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(remaining));
+    headers.insert(header::CONTENT_TYPE, 
+        HeaderValue::from_str(content_type).unwrap());
+    if requested.is_some() {
+        headers.insert(header::CONTENT_RANGE, 
+            HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, len)).unwrap());
+        *resp.status_mut() = StatusCode::PARTIAL_CONTENT;
+    }
+
+    Ok(resp)
+}
+
+/// Parse a `Range` header value of the form `bytes=start-end`, `bytes=start-`
+/// or `bytes=-suffix` into an inclusive (start, end) pair.
+/// # Returns
+/// `None` if the header is malformed or the range can't be satisfied.
+fn parse_range(range: &str, len: u64) -> Option<(u64, u64)> {
+    let range = range.trim().strip_prefix("bytes=")?;
+    // Only the first range of a multi-range request is served
+    let range = range.split(',').next()?.trim();
+    let (start, end) = range.split_at(range.find('-')?);
+    let end = &end[1..];
+
+    let (start, end) = match (start.is_empty(), end.is_empty()) {
+        // bytes=-suffix
+        (true, false) => {
+            let suffix: u64 = end.parse().ok()?;
+            (len.saturating_sub(suffix), len.checked_sub(1)?)
+        }
+        // bytes=start-
+        (false, true) => (start.parse().ok()?, len.checked_sub(1)?),
+        // bytes=start-end
+        (false, false) => {
+            let end: u64 = end.parse().ok()?;
+            (start.parse().ok()?, end.min(len.checked_sub(1)?))
+        }
+        (true, true) => return None,
+    };
+
+    if start > end || start >= len {
+        return None;
+    }
+    Some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_range_bounded() {
+        assert_eq!(parse_range("bytes=0-499", 1000), Some((0, 499)));
+        assert_eq!(parse_range(" bytes=500-999 ", 1000), Some((500, 999)));
+        // Ends past the file are cut to its last byte
+        assert_eq!(parse_range("bytes=500-5000", 1000), Some((500, 999)));
+    }
+
+    #[test]
+    fn parse_range_open_ended() {
+        assert_eq!(parse_range("bytes=0-", 1000), Some((0, 999)));
+        assert_eq!(parse_range("bytes=999-", 1000), Some((999, 999)));
+    }
+
+    #[test]
+    fn parse_range_suffix() {
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 999)));
+        // A suffix longer than the file is the whole file
+        assert_eq!(parse_range("bytes=-5000", 1000), Some((0, 999)));
+        assert_eq!(parse_range("bytes=-0", 1000), None);
+    }
+
+    #[test]
+    fn parse_range_first_of_many() {
+        assert_eq!(parse_range("bytes=0-99, 200-299", 1000), Some((0, 99)));
+    }
+
+    #[test]
+    fn parse_range_out_of_bounds() {
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=1000-1100", 1000), None);
+        assert_eq!(parse_range("bytes=500-100", 1000), None);
+        assert_eq!(parse_range("bytes=0-", 0), None);
+        assert_eq!(parse_range("bytes=-100", 0), None);
+    }
+
+    #[test]
+    fn parse_range_malformed() {
+        assert_eq!(parse_range("bytes=-", 1000), None);
+        assert_eq!(parse_range("bytes=a-b", 1000), None);
+        assert_eq!(parse_range("bytes=100", 1000), None);
+        assert_eq!(parse_range("items=0-99", 1000), None);
+    }
+
+    #[test]
+    fn playlist_cache_drops_least_recently_used() {
+        let key = |id: u32| (id, 0, "FirstAndSecond".to_string());
+        let playlist = Arc::new(hls::Playlist { segments: Vec::new() });
+        let mut playlists = PlaylistCache::default();
+        for id in 0..MAX_PLAYLISTS as u32 {
+            playlists.insert(key(id), playlist.clone());
+        }
+        assert!(playlists.get(&key(0)).is_some());
+
+        playlists.insert(key(100), playlist);
+        assert_eq!(playlists.playlists.len(), MAX_PLAYLISTS);
+        assert!(playlists.get(&key(0)).is_some());
+        assert!(playlists.get(&key(1)).is_none());
+        assert!(playlists.get(&key(100)).is_some());
+    }
+}
@@ -0,0 +1,176 @@
+use super::{sanitize, Cue};
+
+/// Fields of a dialogue line when the events have no format line
+const DEFAULT_FORMAT: [&str; 10] = [
+    "layer", "start", "end", "style", "name", "marginl", "marginr", "marginv", "effect", "text",
+];
+
+/// Parse ASS or SSA subtitles into cues. Only the dialogue of the events
+/// section is read, styling is reduced to italic, bold and underline.
+/// Dialogue that can't be read is skipped.
+pub fn parse(text: &str) -> Vec<Cue> {
+    let mut cues = Vec::new();
+    let mut in_events = false;
+    let mut format: Vec<String> = DEFAULT_FORMAT.iter().map(|field| field.to_string()).collect();
+
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_events = line.eq_ignore_ascii_case("[events]");
+            continue;
+        }
+        if !in_events {
+            continue;
+        }
+        let (kind, value) = match line.split_once(':') {
+            Some(pair) => pair,
+            None => continue,
+        };
+
+        match kind.trim() {
+            "Format" => {
+                format = value.split(',').map(|field| field.trim().to_lowercase()).collect();
+            }
+            "Dialogue" => {
+                // The text is last and may itself contain commas
+                let fields: Vec<&str> = value.trim_start().splitn(format.len(), ',').collect();
+                let field = |name: &str| format.iter()
+                    .position(|field| field == name)
+                    .and_then(|index| fields.get(index));
+
+                let times = field("start").and_then(|start| parse_timestamp(start))
+                    .zip(field("end").and_then(|end| parse_timestamp(end)));
+                let (start, end) = match times {
+                    Some(times) => times,
+                    None => continue,
+                };
+                let text = sanitize(&convert_text(field("text").unwrap_or(&"")));
+                if !text.is_empty() {
+                    cues.push(Cue { start, end, text });
+                }
+            }
+            _ => {}
+        }
+    }
+    // Events aren't required to be in order
+    cues.sort_by(|a, b| a.start.total_cmp(&b.start));
+    cues
+}
+
+/// Parse a timestamp of the form `h:mm:ss.cc`.
+fn parse_timestamp(timestamp: &str) -> Option<f64> {
+    let mut seconds = 0.0;
+    for part in timestamp.trim().split(':') {
+        let part: f64 = part.parse().ok()?;
+        seconds = seconds * 60.0 + part;
+    }
+    Some(seconds)
+}
+
+/// Convert the text of a dialogue line into tagged text. Override blocks,
+/// e.g. `{\i1}`, are dropped other than turning italic, bold and underline
+/// on and off. Text drawn as vector shapes is dropped.
+pub(super) fn convert_text(text: &str) -> String {
+    let mut converted = String::with_capacity(text.len());
+    // Whether italic, bold and underline are on, in that order
+    let mut styles = [false; 3];
+    let mut drawing = false;
+
+    let mut rest = text;
+    while !rest.is_empty() {
+        if let (true, Some(end)) = (rest.starts_with('{'), rest.find('}')) {
+            for tag in rest[1..end].split('\\').map(str::trim) {
+                if let Some(scale) = tag.strip_prefix('p').and_then(|scale| scale.parse::<u32>().ok()) {
+                    drawing = scale > 0;
+                    continue;
+                }
+                let (style, on) = match tag {
+                    "i1" => (0, true),
+                    "i0" => (0, false),
+                    "b1" => (1, true),
+                    "b0" => (1, false),
+                    "u1" => (2, true),
+                    "u0" => (2, false),
+                    _ => continue,
+                };
+                if styles[style] != on {
+                    styles[style] = on;
+                    let name = ["i", "b", "u"][style];
+                    converted.push_str(&if on { format!("<{}>", name) } else { format!("</{}>", name) });
+                }
+            }
+            rest = &rest[end + 1..];
+            continue;
+        }
+
+        let c = rest.chars().next().unwrap_or_default();
+        if drawing {
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        if rest.starts_with("\\N") || rest.starts_with("\\n") {
+            converted.push('\n');
+            rest = &rest[2..];
+        }
+        else if rest.starts_with("\\h") {
+            converted.push(' ');
+            rest = &rest[2..];
+        }
+        else {
+            converted.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    // Close whatever is still on
+    for (style, name) in ["i", "b", "u"].iter().enumerate().rev() {
+        if styles[style] {
+            converted.push_str(&format!("</{}>", name));
+        }
+    }
+    converted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_dialogue() {
+        let ass = "[Script Info]\nTitle: Test\n\n\
+            [V4+ Styles]\nFormat: Name, Fontname\nStyle: Default,Arial\n\n\
+            [Events]\n\
+            Format: Layer, Start, End, Style, Text\n\
+            Comment: 0,0:00:00.00,0:00:01.00,Default,Not shown\n\
+            Dialogue: 0,0:00:03.00,0:00:04.00,Default,Later\n\
+            Dialogue: 0,0:00:01.50,0:00:02.25,Default,Hello, {\\i1}world\n\
+            Dialogue: 0,soon,0:00:05.00,Default,Broken\n";
+        assert_eq!(parse(ass), vec![
+            Cue { start: 1.5, end: 2.25, text: "Hello, <i>world</i>".into() },
+            Cue { start: 3.0, end: 4.0, text: "Later".into() },
+        ]);
+    }
+
+    #[test]
+    fn parses_without_format_line() {
+        let ssa = "[Events]\nDialogue: Marked=0,1:00:00.00,1:00:01.00,Default,,0,0,0,,Hi\n";
+        assert_eq!(parse(ssa), vec![Cue { start: 3600.0, end: 3601.0, text: "Hi".into() }]);
+    }
+
+    #[test]
+    fn converts_styles() {
+        assert_eq!(convert_text("{\\i1}Hello{\\i0} world"), "<i>Hello</i> world");
+        assert_eq!(convert_text("{\\i1\\b1}Both"), "<i><b>Both</b></i>");
+        assert_eq!(convert_text("{\\b1}{\\b1}Bold{\\u0}"), "<b>Bold</b>");
+    }
+
+    #[test]
+    fn converts_breaks() {
+        assert_eq!(convert_text("One\\NTwo\\nThree\\hFour"), "One\nTwo\nThree Four");
+    }
+
+    #[test]
+    fn drops_overrides_and_drawings() {
+        assert_eq!(convert_text("{\\pos(10,20)\\c&H0000FF&}Hi"), "Hi");
+        assert_eq!(convert_text("{\\p1}m 0 0 l 100 0{\\p0}Text"), "Text");
+    }
+}
@@ -0,0 +1,30 @@
+use crate::video_encoding;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum SubtitleError {
+    IoError(std::io::Error),
+    /// Embedded subtitles couldn't be read from the file
+    EncodingError(video_encoding::Error),
+    /// The entry has no subtitle track with this id
+    TrackNotFound(usize),
+}
+impl From<std::io::Error> for SubtitleError {
+    fn from(err: std::io::Error) -> Self {
+        SubtitleError::IoError(err)
+    }
+}
+impl From<video_encoding::Error> for SubtitleError {
+    fn from(err: video_encoding::Error) -> Self {
+        SubtitleError::EncodingError(err)
+    }
+}
+impl fmt::Display for SubtitleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubtitleError::IoError(err) => write!(f, "I/O failed: {}", err),
+            SubtitleError::EncodingError(err) => write!(f, "{}", err),
+            SubtitleError::TrackNotFound(id) => write!(f, "No subtitle track {}", id),
+        }
+    }
+}
@@ -0,0 +1,359 @@
+pub mod error;
+mod ass;
+mod srt;
+
+use crate::video_encoding::{self, MediaInfo};
+use serde::Serialize;
+use std::{fmt::Write as _, fs, path::{Path, PathBuf}};
+
+pub type Error = error::SubtitleError;
+
+/// Content type subtitles are served as, the only text track format the
+/// default receiver reads
+pub const CONTENT_TYPE: &str = "text/vtt";
+/// Tags of sidecar file names that describe the track rather than its language
+const NON_LANGUAGE_TAGS: [&str; 2] = ["sdh", "cc"];
+
+/// Formats of subtitle files that can be converted to WebVTT.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Format {
+    SubRip,
+    /// Advanced SubStation Alpha, also read for the older SSA
+    Ass,
+    WebVtt,
+}
+
+impl Format {
+    /// Match a file extension, e.g. `srt`, to its format.
+    fn from_extension(ext: &str) -> Option<Self> {
+        match ext.to_lowercase().as_str() {
+            "srt" => Some(Format::SubRip),
+            "ass" | "ssa" => Some(Format::Ass),
+            "vtt" => Some(Format::WebVtt),
+            _ => None,
+        }
+    }
+}
+
+/// Where a subtitle track is read from.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Source {
+    /// A file next to the media, e.g. `Movie.en.srt` for `Movie.mkv`
+    Sidecar { path: PathBuf, format: Format },
+    /// A text subtitle stream of the media file itself
+    Embedded {
+        /// Container index of the stream
+        index: usize,
+        /// ffmpeg's short name for the codec, e.g. `subrip` or `mov_text`
+        codec: String,
+    },
+}
+
+/// A subtitle track of a library entry that can be shown while casting it.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Track {
+    /// Identifies the track among the entry's subtitles
+    pub id: usize,
+    /// Label shown on the receiver, e.g. `en forced`
+    pub name: String,
+    /// Language tag, e.g. `en` or `pt-BR`, if one could be found
+    pub language: Option<String>,
+    pub source: Source,
+}
+
+/// A piece of subtitle text and when it is on screen, in seconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cue {
+    pub start: f64,
+    pub end: f64,
+    /// The text, with WebVTT's `<i>`, `<b>` and `<u>` the only markup
+    pub text: String,
+}
+
+/// Find the subtitle tracks of a media file, sidecar files first and then
+/// the text subtitle streams within the file.
+/// ### Arguments
+/// - `media` - Path of the media file
+/// - `info` - Probed info of the file, if it was probed
+/// ### Returns
+/// The tracks, with ids counting up from 0
+pub fn find(media: &Path, info: Option<&MediaInfo>) -> Vec<Track> {
+    let mut tracks = find_sidecars(media);
+    // Bitmap subtitles have no text to convert
+    let embedded = info.into_iter()
+        .flat_map(|info| info.subtitles.iter())
+        .filter(|stream| !stream.bitmap);
+    for stream in embedded {
+        let mut name = stream.title.clone()
+            .or_else(|| stream.language.clone())
+            .unwrap_or_else(|| format!("Track {}", stream.index));
+        if stream.forced {
+            name.push_str(" (forced)");
+        }
+        tracks.push(Track {
+            id: tracks.len(),
+            name,
+            language: stream.language.clone(),
+            source: Source::Embedded { index: stream.index, codec: stream.codec.clone() },
+        });
+    }
+    tracks
+}
+
+/// Find the sidecar subtitle files of a media file. They are looked for in
+/// the same directory, named after the media file followed by optional tags,
+/// e.g. `Movie.en.forced.srt` for `Movie.mkv`.
+/// ### Returns
+/// The tracks ordered by file name, with ids counting up from 0
+fn find_sidecars(media: &Path) -> Vec<Track> {
+    let (dir, stem) = match (media.parent(), media.file_stem()) {
+        (Some(dir), Some(stem)) => (dir, stem.to_string_lossy().to_string()),
+        _ => return Vec::new(),
+    };
+    let files = match fs::read_dir(dir) {
+        Ok(files) => files,
+        Err(err) => {
+            log::warn!("[Subtitles] Failed to list {:?}: {:?}", dir, err);
+            return Vec::new();
+        }
+    };
+
+    let mut sidecars: Vec<(PathBuf, Format)> = files
+        .filter_map(|file| file.ok())
+        .map(|file| file.path())
+        .filter(|path| path.file_name().map_or(false, |name| {
+            name.to_string_lossy().starts_with(&format!("{}.", stem))
+        }))
+        .filter_map(|path| {
+            let format = Format::from_extension(&path.extension()?.to_string_lossy())?;
+            Some((path, format))
+        })
+        .collect();
+    sidecars.sort_by(|a, b| a.0.cmp(&b.0));
+
+    sidecars.into_iter()
+        .enumerate()
+        .map(|(id, (path, format))| {
+            // Whatever is between the media's name and the extension
+            let name = path.file_stem()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let tags: Vec<&str> = name[stem.len()..]
+                .split('.')
+                .filter(|tag| !tag.is_empty())
+                .collect();
+            let language = tags.iter()
+                .find(|tag| is_language_tag(tag))
+                .map(|tag| tag.to_string());
+            let name = match tags.is_empty() {
+                true => path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+                false => tags.join(" "),
+            };
+
+            Track { id, name, language, source: Source::Sidecar { path, format } }
+        })
+        .collect()
+}
+
+/// Read a subtitle track and convert it into WebVTT. Embedded tracks are
+/// decoded from the whole file, so this may take a while.
+/// ### Arguments
+/// - `media` - Path of the media file the track belongs to
+/// - `track` - The track, as found by `find`
+pub fn to_webvtt(media: &Path, track: &Track) -> Result<String, Error> {
+    let cues = match &track.source {
+        Source::Sidecar { path, format } => {
+            let text = read_text(path)?;
+            match format {
+                // WebVTT cues are laid out like SubRip's, settings aside
+                Format::SubRip | Format::WebVtt => srt::parse(&text),
+                Format::Ass => ass::parse(&text),
+            }
+        }
+        Source::Embedded { index, .. } => {
+            video_encoding::extract::text_subtitles(media, *index)?
+                .into_iter()
+                .map(|event| Cue {
+                    start: event.start,
+                    end: event.end,
+                    text: sanitize(&ass::convert_text(&event.text)),
+                })
+                .filter(|cue| !cue.text.is_empty())
+                .collect()
+        }
+    };
+    log::info!("[Subtitles] Converted {} cues of {:?}", cues.len(), &track.name);
+    Ok(write_webvtt(&cues))
+}
+
+/// Shift the cues of a WebVTT file, e.g. one written by `to_webvtt`, to
+/// make up for subtitles that are out of sync. Cues shifted to before the
+/// start of the media are dropped.
+/// ### Arguments
+/// - `vtt` - The WebVTT file
+/// - `offset` - Seconds to show the cues later by, negative to show them earlier
+pub fn shift_webvtt(vtt: &str, offset: f64) -> String {
+    let cues: Vec<Cue> = srt::parse(vtt)
+        .into_iter()
+        .map(|cue| Cue { start: (cue.start + offset).max(0.0), end: cue.end + offset, ..cue })
+        .filter(|cue| cue.end > 0.0)
+        .collect();
+    write_webvtt(&cues)
+}
+
+/// Write cues out as a WebVTT file.
+pub fn write_webvtt(cues: &[Cue]) -> String {
+    let mut vtt = String::from("WEBVTT\n\n");
+    for cue in cues {
+        // Writing to a String can't fail
+        let _ = writeln!(vtt, "{} --> {}", timestamp(cue.start), timestamp(cue.end));
+        let _ = writeln!(vtt, "{}\n", cue.text);
+    }
+    vtt
+}
+
+/// Format seconds as a WebVTT timestamp, e.g. `01:02:03.456`.
+fn timestamp(seconds: f64) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!("{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000, millis / 60_000 % 60, millis / 1000 % 60, millis % 1000)
+}
+
+/// Read a subtitle file into text. Files that aren't UTF-8 are usually in
+/// an older single byte encoding, which are read as Latin-1.
+fn read_text(path: &Path) -> Result<String, Error> {
+    let bytes = fs::read(path)?;
+    let text = match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(err) => err.into_bytes().iter().map(|byte| *byte as char).collect(),
+    };
+    Ok(text.trim_start_matches('\u{feff}').replace("\r\n", "\n"))
+}
+
+/// Clean up cue text for WebVTT. `<i>`, `<b>` and `<u>` tags are kept, any
+/// other tags (such as SubRip's `<font>`) and ASS override blocks left in
+/// SubRip files are dropped, and characters WebVTT treats as markup are escaped
+/// unless already part of an entity. Blank lines, which would end the cue, are removed.
+fn sanitize(text: &str) -> String {
+    let mut clean = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            '<' => match rest.find('>') {
+                Some(end) => {
+                    let tag = rest[1..end].trim().to_lowercase();
+                    if ["i", "b", "u", "/i", "/b", "/u"].contains(&tag.as_str()) {
+                        let _ = write!(clean, "<{}>", tag);
+                    }
+                    rest = &rest[end + 1..];
+                    continue;
+                }
+                None => clean.push_str("&lt;"),
+            },
+            '{' if rest.starts_with("{\\") && rest.contains('}') => {
+                rest = &rest[rest.find('}').unwrap_or_default() + 1..];
+                continue;
+            }
+            '>' => clean.push_str("&gt;"),
+            '&' if !is_entity(rest) => clean.push_str("&amp;"),
+            c => clean.push(c),
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+
+    clean.lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Check if text starts with a character reference, e.g. `&amp;` or `&#39;`.
+fn is_entity(text: &str) -> bool {
+    match text[1..].find(';') {
+        Some(end) => (1..=8).contains(&end)
+            && text[1..=end].chars().enumerate().all(|(i, c)| c.is_ascii_alphanumeric() || (i == 0 && c == '#')),
+        None => false,
+    }
+}
+
+/// Check if a tag of a sidecar's name looks like a language, e.g. `en`, `eng` or `pt-BR`.
+fn is_language_tag(tag: &str) -> bool {
+    let primary = tag.split('-').next().unwrap_or_default();
+    (2..=3).contains(&primary.len())
+        && primary.chars().all(|c| c.is_ascii_alphabetic())
+        && !NON_LANGUAGE_TAGS.contains(&primary.to_lowercase().as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_keeps_basic_tags() {
+        assert_eq!(sanitize("<I>Hi</I> <font color=\"red\">there</font>"), "<i>Hi</i> there");
+        assert_eq!(sanitize("{\\an8}Top"), "Top");
+    }
+
+    #[test]
+    fn sanitize_escapes_markup() {
+        assert_eq!(sanitize("2 > 1 < 3"), "2 &gt; 1 &lt; 3");
+        assert_eq!(sanitize("Tom & Jerry &amp; &#39;friends&#39;"), "Tom &amp; Jerry &amp; &#39;friends&#39;");
+        assert_eq!(sanitize("Rock &"), "Rock &amp;");
+    }
+
+    #[test]
+    fn sanitize_removes_blank_lines() {
+        assert_eq!(sanitize("One  \n\n<font>  </font>\nTwo"), "One\nTwo");
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(timestamp(0.0), "00:00:00.000");
+        assert_eq!(timestamp(3723.4567), "01:02:03.457");
+        assert_eq!(timestamp(-1.0), "00:00:00.000");
+    }
+
+    #[test]
+    fn writes_webvtt() {
+        let cues = [
+            Cue { start: 1.0, end: 2.5, text: "Hello\nworld".into() },
+            Cue { start: 61.0, end: 62.0, text: "Bye".into() },
+        ];
+        assert_eq!(write_webvtt(&cues),
+            "WEBVTT\n\n00:00:01.000 --> 00:00:02.500\nHello\nworld\n\n00:01:01.000 --> 00:01:02.000\nBye\n\n");
+    }
+
+    #[test]
+    fn language_tags() {
+        assert!(is_language_tag("en"));
+        assert!(is_language_tag("eng"));
+        assert!(is_language_tag("pt-BR"));
+        assert!(!is_language_tag("sdh"));
+        assert!(!is_language_tag("forced"));
+    }
+
+    const VTT: &str = "WEBVTT\n\n00:00:01.000 --> 00:00:02.000\nFirst\n\n00:59:59.500 --> 01:00:00.500\nLast\n\n";
+
+    #[test]
+    fn shifts_later_across_the_hour() {
+        assert_eq!(shift_webvtt(VTT, 1.25),
+            "WEBVTT\n\n00:00:02.250 --> 00:00:03.250\nFirst\n\n01:00:00.750 --> 01:00:01.750\nLast\n\n");
+    }
+
+    #[test]
+    fn shifts_earlier_dropping_cues_before_the_start() {
+        // A cue partly before the start is cut short, one wholly before it is dropped
+        assert_eq!(shift_webvtt(VTT, -1.5),
+            "WEBVTT\n\n00:00:00.000 --> 00:00:00.500\nFirst\n\n00:59:58.000 --> 00:59:59.000\nLast\n\n");
+        assert_eq!(shift_webvtt(VTT, -2.0), "WEBVTT\n\n00:59:57.500 --> 00:59:58.500\nLast\n\n");
+    }
+
+    #[test]
+    fn shift_keeps_cue_text() {
+        let vtt = "WEBVTT\n\n00:00:01.000 --> 00:00:02.000\n<i>Tom &amp; Jerry</i>\nsecond line\n\n";
+        assert_eq!(shift_webvtt(vtt, 0.0), vtt);
+    }
+}
@@ -0,0 +1,86 @@
+use super::{sanitize, Cue};
+
+/// Parse SubRip subtitles into cues. WebVTT is read the same way, its header,
+/// notes and styles have no timing line so are skipped, and cue settings are
+/// dropped. Cues that can't be read are skipped.
+pub fn parse(text: &str) -> Vec<Cue> {
+    let mut cues = Vec::new();
+    let mut lines = text.lines().peekable();
+    while lines.peek().is_some() {
+        // Cues are separated by blank lines
+        let block: Vec<&str> = lines.by_ref()
+            .skip_while(|line| line.trim().is_empty())
+            .take_while(|line| !line.trim().is_empty())
+            .collect();
+
+        // The timing line comes after an optional cue number or identifier
+        let timing = match block.iter().position(|line| line.contains("-->")) {
+            Some(timing) => timing,
+            None => continue,
+        };
+        let (start, end) = match parse_timing(block[timing]) {
+            Some(times) => times,
+            None => continue,
+        };
+        let text = sanitize(&block[timing + 1..].join("\n"));
+        if !text.is_empty() {
+            cues.push(Cue { start, end, text });
+        }
+    }
+    cues.sort_by(|a, b| a.start.total_cmp(&b.start));
+    cues
+}
+
+/// Parse a timing line, e.g. `00:01:02,500 --> 00:01:04,000`.
+/// ### Returns
+/// The start and end in seconds
+fn parse_timing(line: &str) -> Option<(f64, f64)> {
+    let (start, end) = line.split_once("-->")?;
+    // Anything after the end time is positioning or cue settings
+    let end = end.split_whitespace().next()?;
+    Some((parse_timestamp(start.trim())?, parse_timestamp(end)?))
+}
+
+/// Parse a timestamp of the form `hh:mm:ss,mmm`. A dot is also accepted
+/// before the milliseconds, and the hours may be left out.
+fn parse_timestamp(timestamp: &str) -> Option<f64> {
+    let mut seconds = 0.0;
+    for part in timestamp.split(':') {
+        let part: f64 = part.trim().replace(',', ".").parse().ok()?;
+        seconds = seconds * 60.0 + part;
+    }
+    Some(seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cue(start: f64, end: f64, text: &str) -> Cue {
+        Cue { start, end, text: text.into() }
+    }
+
+    #[test]
+    fn parses_subrip() {
+        let srt = "1\n00:00:01,000 --> 00:00:02,500\nHello\nworld\n\n2\n01:02:03,456 --> 01:02:04,000\n<i>Bye</i>\n";
+        assert_eq!(parse(srt), vec![cue(1.0, 2.5, "Hello\nworld"), cue(3723.456, 3724.0, "<i>Bye</i>")]);
+    }
+
+    #[test]
+    fn parses_webvtt() {
+        let vtt = "WEBVTT\n\nNOTE a comment\n\nintro\n00:01.000 --> 00:02.000 align:start line:0\nHello\n";
+        assert_eq!(parse(vtt), vec![cue(1.0, 2.0, "Hello")]);
+    }
+
+    #[test]
+    fn skips_broken_cues() {
+        let srt = "1\n00:00:01,000 --> soon\nBroken\n\n2\n00:00:03,000 --> 00:00:04,000\n\n\n3\n00:00:05,000 --> 00:00:06,000\nFine\n";
+        assert_eq!(parse(srt), vec![cue(5.0, 6.0, "Fine")]);
+    }
+
+    #[test]
+    fn sorts_cues() {
+        let srt = "2\n00:00:05,000 --> 00:00:06,000\nSecond\n\n\n\n1\n00:00:01,000 --> 00:00:02,000\nFirst";
+        assert_eq!(parse(srt), vec![cue(1.0, 2.0, "First"), cue(5.0, 6.0, "Second")]);
+    }
+}
@@ -0,0 +1,248 @@
+use super::{probe, Error};
+use ffmpeg::{codec, ffi, subtitle::Rect, Frame, Packet, Rational, Stream, Subtitle};
+use std::collections::VecDeque;
+
+/// Video at least this tall is taken to use BT.709 colours, anything smaller BT.601
+const HD_HEIGHT: u32 = 720;
+
+/// Draws a bitmap subtitle stream (PGS, VobSub...) onto the frames of a
+/// transcoded video. Subtitles are decoded as their packets are read, which
+/// in a muxed file is shortly before they are due on screen.
+///
+/// Only subtitles read since the transcode began are drawn, so a window of
+/// the file misses one that went up before the window started.
+pub(super) struct BurnIn {
+    decoder: codec::decoder::Subtitle,
+    /// Time base of the subtitle stream
+    time_base: Rational,
+    /// Size of the video the subtitles were authored against, used when the
+    /// stream doesn't say
+    video_size: (u32, u32),
+    /// (Kr, Kb) of the video's YCbCr colours
+    matrix: (f32, f32),
+    /// Decoded subtitles waiting for or being shown, in order
+    events: VecDeque<Event>,
+}
+
+/// A decoded subtitle. Each one replaces the one before it on screen.
+struct Event {
+    /// Seconds the subtitle goes up at
+    start: f64,
+    /// Seconds the subtitle comes down at, `None` when it stays up until the next
+    end: Option<f64>,
+    /// Empty when the subtitle clears the screen
+    bitmaps: Vec<Bitmap>,
+}
+
+/// A paletted picture of a subtitle, placed on the video it was authored for.
+struct Bitmap {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    /// A palette index for each pixel, row by row
+    indices: Vec<u8>,
+    /// Colours as Y, Cb, Cr and alpha
+    palette: Vec<[u8; 4]>,
+}
+
+impl BurnIn {
+    /// Set up decoding of a bitmap subtitle stream to draw onto a video stream.
+    /// ### Arguments
+    /// - `ist` - The subtitle stream
+    /// - `video` - The video stream the subtitles are drawn onto
+    /// ### Returns
+    /// `Error::UnsupportedCodec` if the stream holds text subtitles or can't be decoded
+    pub fn new(ist: &Stream, video: &Stream) -> Result<Self, Error> {
+        let codec = ist.codec();
+        let id = codec.id();
+        if codec.medium() != ffmpeg::media::Type::Subtitle {
+            return Err(Error::StreamNotFound(ist.index()));
+        }
+        if !probe::is_bitmap_subtitle(id) {
+            return Err(Error::UnsupportedCodec(id.name().to_string()));
+        }
+        let decoder = codec.decoder().subtitle().map_err(|err| match err {
+            ffmpeg::Error::DecoderNotFound => Error::UnsupportedCodec(id.name().to_string()),
+            err => err.into(),
+        })?;
+
+        let video_size = unsafe {
+            let parameters = video.parameters().as_ptr();
+            ((*parameters).width.max(0) as u32, (*parameters).height.max(0) as u32)
+        };
+        let matrix = if video_size.1 >= HD_HEIGHT { (0.2126, 0.0722) } else { (0.299, 0.114) };
+
+        Ok(Self {
+            decoder,
+            time_base: ist.time_base(),
+            video_size,
+            matrix,
+            events: VecDeque::new(),
+        })
+    }
+
+    /// Decode a packet of the subtitle stream. Packets that fail to decode are skipped.
+    pub fn send_packet(&mut self, packet: &Packet) {
+        let mut subtitle = Subtitle::new();
+        match self.decoder.decode(packet, &mut subtitle) {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(err) => {
+                log::warn!("[BurnIn] Skipping undecodable subtitle: {:?}", err);
+                return;
+            }
+        }
+
+        // Display times are in milliseconds from the subtitle's timestamp
+        let base = match subtitle.pts() {
+            Some(pts) => pts as f64 * f64::from(ffmpeg::rescale::TIME_BASE),
+            None => match packet.pts().or(packet.dts()) {
+                Some(pts) => pts as f64 * f64::from(self.time_base),
+                None => return,
+            },
+        };
+        let start = base + f64::from(subtitle.start()) / 1000.0;
+        let end = match subtitle.end() {
+            end if end > subtitle.start() && end != u32::MAX => Some(base + f64::from(end) / 1000.0),
+            _ => None,
+        };
+        let bitmaps = subtitle.rects()
+            .filter_map(|rect| match rect {
+                Rect::Bitmap(bitmap) => unsafe { self.copy_bitmap(&*bitmap.as_ptr()) },
+                _ => None,
+            })
+            .collect();
+
+        // Subtitles arrive in order, apart from a display set decoded late
+        let position = self.events.iter()
+            .rposition(|event| event.start <= start)
+            .map_or(0, |position| position + 1);
+        self.events.insert(position, Event { start, end, bitmaps });
+    }
+
+    /// Draw whatever subtitle is on screen at a time onto a frame.
+    /// ### Arguments
+    /// - `frame` - A yuv420p frame, as coming out of the video filter graph
+    /// - `time` - Seconds the frame is shown at
+    pub fn draw(&mut self, frame: &mut Frame, time: f64) {
+        // Subtitles are taken down by the one after them
+        while self.events.len() > 1 && self.events[1].start <= time {
+            self.events.pop_front();
+        }
+        let event = match self.events.front() {
+            Some(event) if event.start <= time => event,
+            _ => return,
+        };
+        if event.end.map_or(false, |end| end <= time) {
+            self.events.pop_front();
+            return;
+        }
+        if event.bitmaps.is_empty() {
+            return;
+        }
+
+        // Bitmaps are placed on the canvas the subtitles were authored for
+        let canvas = unsafe {
+            let context = self.decoder.as_ptr();
+            match ((*context).width, (*context).height) {
+                (width, height) if width > 0 && height > 0 => (width as usize, height as usize),
+                _ => (self.video_size.0 as usize, self.video_size.1 as usize),
+            }
+        };
+        if canvas.0 == 0 || canvas.1 == 0 {
+            return;
+        }
+        let frame = unsafe { &mut *frame.as_mut_ptr() };
+        for bitmap in &event.bitmaps {
+            unsafe { blend(frame, canvas, bitmap) };
+        }
+    }
+
+    /// Copy a decoded bitmap out of ffmpeg's subtitle, converting its palette
+    /// into the video's colours.
+    /// ### Returns
+    /// `None` if the rect holds no picture
+    unsafe fn copy_bitmap(&self, rect: &ffi::AVSubtitleRect) -> Option<Bitmap> {
+        if rect.w <= 0 || rect.h <= 0 || rect.data[0].is_null() || rect.data[1].is_null() {
+            return None;
+        }
+        let (width, height) = (rect.w as usize, rect.h as usize);
+        let stride = rect.linesize[0] as usize;
+
+        let mut indices = Vec::with_capacity(width * height);
+        for row in 0..height {
+            indices.extend_from_slice(std::slice::from_raw_parts(rect.data[0].add(row * stride), width));
+        }
+        // The palette holds native endian 0xAARRGGBB colours
+        let palette = std::slice::from_raw_parts(rect.data[1] as *const u32, rect.nb_colors.max(0) as usize)
+            .iter()
+            .map(|color| self.to_ycbcr(*color))
+            .collect();
+
+        Some(Bitmap {
+            x: rect.x.max(0) as usize,
+            y: rect.y.max(0) as usize,
+            width,
+            height,
+            indices,
+            palette,
+        })
+    }
+
+    /// Convert an ARGB colour into limited range Y, Cb, Cr and alpha.
+    fn to_ycbcr(&self, argb: u32) -> [u8; 4] {
+        let (kr, kb) = self.matrix;
+        let channel = |shift: u32| ((argb >> shift) & 0xff) as f32 / 255.0;
+        let (r, g, b) = (channel(16), channel(8), channel(0));
+
+        let y = kr * r + (1.0 - kr - kb) * g + kb * b;
+        let cb = (b - y) / (2.0 * (1.0 - kb));
+        let cr = (r - y) / (2.0 * (1.0 - kr));
+        [
+            (16.0 + 219.0 * y).round() as u8,
+            (128.0 + 224.0 * cb).round() as u8,
+            (128.0 + 224.0 * cr).round() as u8,
+            (argb >> 24) as u8,
+        ]
+    }
+}
+
+/// Alpha blend a bitmap onto a yuv420p frame, scaling it from the canvas to
+/// the frame's size by nearest neighbour.
+unsafe fn blend(frame: &mut ffi::AVFrame, canvas: (usize, usize), bitmap: &Bitmap) {
+    let (width, height) = (frame.width.max(0) as usize, frame.height.max(0) as usize);
+    // Frame pixels covered by the bitmap
+    let columns = (bitmap.x * width / canvas.0)..((bitmap.x + bitmap.width) * width / canvas.0).min(width);
+    let rows = (bitmap.y * height / canvas.1)..((bitmap.y + bitmap.height) * height / canvas.1).min(height);
+    // Returns the colour of the bitmap at a frame pixel
+    let sample = |x: usize, y: usize| {
+        let column = (x * canvas.0 / width).saturating_sub(bitmap.x).min(bitmap.width - 1);
+        let row = (y * canvas.1 / height).saturating_sub(bitmap.y).min(bitmap.height - 1);
+        bitmap.palette.get(bitmap.indices[row * bitmap.width + column] as usize)
+    };
+    let mix = |dst: &mut u8, src: u8, alpha: u8| {
+        let alpha = u32::from(alpha);
+        *dst = ((u32::from(src) * alpha + u32::from(*dst) * (255 - alpha) + 127) / 255) as u8;
+    };
+
+    for y in rows {
+        let luma = frame.data[0].add(y * frame.linesize[0] as usize);
+        // Chroma is shared by 2x2 pixels, and taken from the top left one
+        let chroma = (y % 2 == 0).then(|| (
+            frame.data[1].add(y / 2 * frame.linesize[1] as usize),
+            frame.data[2].add(y / 2 * frame.linesize[2] as usize),
+        ));
+        for x in columns.clone() {
+            let color = match sample(x, y) {
+                Some(color) if color[3] > 0 => color,
+                _ => continue,
+            };
+            mix(&mut *luma.add(x), color[0], color[3]);
+            if let (Some((cb, cr)), 0) = (chroma, x % 2) {
+                mix(&mut *cb.add(x / 2), color[1], color[3]);
+                mix(&mut *cr.add(x / 2), color[2], color[3]);
+            }
+        }
+    }
+}
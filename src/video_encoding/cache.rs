@@ -0,0 +1,429 @@
+use super::{sink, transcode::Plan, Error};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::Receiver,
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Size cap used when none is configured, 10GiB
+pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024 * 1024;
+/// Extension of outputs that are still being written
+const PARTIAL_EXTENSION: &str = "part";
+/// Parameters of the 64 bit FNV-1a hash output names are made from
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Identifies a converted output, a source file at a point in time converted a
+/// particular way. Editing the source changes its modification time, so stale
+/// outputs are never matched.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Key {
+    pub path: PathBuf,
+    /// Modification time of the source in seconds since the unix epoch
+    pub modified: u64,
+    /// Describes the conversion, e.g. `remux`
+    pub profile: String,
+    /// Extension of the output, e.g. `mp4`
+    pub extension: &'static str,
+}
+
+impl Key {
+    /// Key of a file remuxed into MP4 by `video_encoding::remux`.
+    pub fn remux(path: &Path, modified: u64) -> Self {
+        Self { path: path.to_path_buf(), modified, profile: "remux".into(), extension: "mp4" }
+    }
+
+    /// Key of a file converted into MP4 by `transcode::transcode`.
+    pub fn transcode(path: &Path, modified: u64, plan: &Plan) -> Self {
+        Self { path: path.to_path_buf(), modified, profile: plan.profile(), extension: "mp4" }
+    }
+
+    /// Key of one HLS segment of a file, as converted by `hls::segment`.
+    pub fn segment(path: &Path, modified: u64, plan: &Plan, index: usize) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            modified,
+            profile: format!("hls-{}-{}", plan.profile(), index),
+            extension: "ts",
+        }
+    }
+
+    /// Key of a subtitle stream of a file converted into WebVTT.
+    pub fn subtitles(path: &Path, modified: u64, index: usize) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            modified,
+            profile: format!("subtitles-{}", index),
+            extension: "vtt",
+        }
+    }
+
+    /// The name the output is stored under within the cache directory. It is
+    /// a hash of the key, which has to stay the same from one build to the
+    /// next for outputs to be found again, so FNV-1a is used over std's hasher.
+    fn file_name(&self) -> String {
+        let path = self.path.to_string_lossy();
+        let modified = self.modified.to_le_bytes();
+        let fields = [path.as_bytes(), &modified, self.profile.as_bytes(), self.extension.as_bytes()];
+
+        // Fields are ended with a 0, so a path can't run into the fields after it
+        let hash = fields.iter()
+            .flat_map(|field| field.iter().chain(&[0]))
+            .fold(FNV_OFFSET_BASIS, |hash, byte| (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME));
+        format!("{:016x}.{}", hash, self.extension)
+    }
+}
+
+/// A finished output within the cache directory.
+#[derive(Debug, Clone, Copy)]
+struct CachedFile {
+    size: u64,
+    /// Seconds since the unix epoch
+    last_used: u64,
+}
+
+/// Finished outputs of `video_encoding::remux` and the transcode pipeline,
+/// kept so rewatching doesn't convert the same file again. Once the outputs
+/// grow past the size cap, the least recently used are deleted.
+pub struct Cache {
+    /// `None` if the cache is disabled
+    dir: Option<PathBuf>,
+    max_size: u64,
+    files: HashMap<String, CachedFile>,
+    /// Counter to give concurrent writes of the same output their own file
+    next_write: AtomicUsize,
+}
+
+impl Cache {
+    /// Open the cache in `dir`, picking up outputs left from a previous run.
+    /// Partial outputs of interrupted conversions are deleted.
+    /// ### Arguments
+    /// - `dir` - Directory to store outputs in, created if needed
+    /// - `max_size` - Size in bytes the outputs are kept under
+    pub fn open(dir: PathBuf, max_size: u64) -> Result<Self, io::Error> {
+        fs::create_dir_all(&dir)?;
+
+        let mut files = HashMap::new();
+        for file in fs::read_dir(&dir)? {
+            let path = file?.path();
+            if path.extension().map_or(false, |ext| ext == PARTIAL_EXTENSION) {
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            let (name, meta) = match (path.file_name(), fs::metadata(&path)) {
+                (Some(name), Ok(meta)) if meta.is_file() => (name.to_string_lossy().to_string(), meta),
+                _ => continue,
+            };
+            // Last use isn't stored, the file being written is a good stand-in
+            let last_used = meta.modified().map(unix_seconds).unwrap_or(0);
+            files.insert(name, CachedFile { size: meta.len(), last_used });
+        }
+
+        let mut cache = Self {
+            dir: Some(dir),
+            max_size,
+            files,
+            next_write: AtomicUsize::new(0),
+        };
+        cache.evict();
+        log::info!("[Cache] Opened with {} outputs, {} of {} bytes used",
+            cache.files.len(), cache.size(), max_size);
+        Ok(cache)
+    }
+
+    /// Create a cache that never stores anything.
+    pub fn disabled() -> Self {
+        Self {
+            dir: None,
+            max_size: 0,
+            files: HashMap::new(),
+            next_write: AtomicUsize::new(0),
+        }
+    }
+
+    /// Check if outputs are being kept at all.
+    pub fn is_enabled(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    /// Total size in bytes of the cached outputs.
+    pub fn size(&self) -> u64 {
+        self.files.values().map(|file| file.size).sum()
+    }
+
+    /// Look up a finished output, marking it as used.
+    /// ### Returns
+    /// The path of the output, `None` if it isn't cached
+    pub fn get(&mut self, key: &Key) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        let name = key.file_name();
+        let file = self.files.get_mut(&name)?;
+
+        let path = dir.join(&name);
+        if !path.is_file() {
+            // Deleted from under us
+            self.files.remove(&name);
+            return None;
+        }
+        file.last_used = unix_seconds(SystemTime::now());
+        Some(path)
+    }
+
+    /// Check if an output is cached, without marking it as used.
+    pub fn contains(&self, key: &Key) -> bool {
+        self.files.contains_key(&key.file_name())
+    }
+
+    /// Start writing an output for `key`.
+    /// ### Returns
+    /// The path to write the output to, `None` if the cache is disabled
+    fn begin(&self, key: &Key) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        let write = self.next_write.fetch_add(1, Ordering::Relaxed);
+        Some(dir.join(format!("{}.{}.{}", key.file_name(), write, PARTIAL_EXTENSION)))
+    }
+
+    /// Move a fully written output into the cache, evicting older outputs
+    /// if the cap is exceeded.
+    /// ### Arguments
+    /// - `key` - The output that was written
+    /// - `partial` - Where it was written, as returned by `begin`
+    fn finish(&mut self, key: &Key, partial: &Path) -> Result<(), io::Error> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let name = key.file_name();
+        let size = fs::metadata(partial)?.len();
+        fs::rename(partial, dir.join(&name))?;
+
+        log::info!("[Cache] Stored {} of {:?} ({} bytes)", &key.profile, &key.path, size);
+        self.files.insert(name, CachedFile { size, last_used: unix_seconds(SystemTime::now()) });
+        self.evict();
+        Ok(())
+    }
+
+    /// Delete the least recently used outputs until the cache fits its cap.
+    fn evict(&mut self) {
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => return,
+        };
+        let mut size = self.size();
+        while size > self.max_size {
+            let oldest = self.files.iter()
+                .min_by_key(|(_, file)| file.last_used)
+                .map(|(name, file)| (name.clone(), *file));
+            let (name, file) = match oldest {
+                Some(oldest) => oldest,
+                None => break,
+            };
+
+            if let Err(err) = fs::remove_file(dir.join(&name)) {
+                log::warn!("[Cache] Failed to evict {}: {:?}", &name, err);
+            }
+            log::info!("[Cache] Evicted {} ({} bytes)", &name, file.size);
+            self.files.remove(&name);
+            size -= file.size;
+        }
+    }
+}
+
+/// Run a conversion on its own thread like `sink::spawn`, also writing its
+/// output into the cache. The output is only stored if the conversion runs
+/// to the end, a client leaving early stops it as usual.
+/// ### Arguments
+/// - `cache` - Cache to store the output in
+/// - `key` - What the output is
+/// - `name` - What the job is working on, for logging
+/// - `job` - Converts into the writer it is given
+pub fn spawn<F>(cache: Arc<Mutex<Cache>>, key: Key, name: String, job: F) -> Receiver<Vec<u8>>
+where F: FnOnce(Box<dyn Write + Send>) -> Result<(), Error> + Send + 'static {
+    sink::spawn(name, move |writer| write_through(&cache, &key, writer, job))
+}
+
+/// Run a conversion straight into the cache, blocking until it is done.
+/// ### Arguments
+/// - `cache` - Cache to store the output in
+/// - `key` - What the output is
+/// - `job` - Converts into the writer it is given
+pub fn store<F>(cache: &Mutex<Cache>, key: &Key, job: F) -> Result<(), Error>
+where F: FnOnce(Box<dyn Write + Send>) -> Result<(), Error> {
+    write_through(cache, key, Box::new(io::sink()), job)
+}
+
+/// Run a conversion into `writer`, keeping a copy of the output in the
+/// cache if it finishes.
+fn write_through<F>(cache: &Mutex<Cache>, key: &Key, writer: Box<dyn Write + Send>, job: F)
+    -> Result<(), Error>
+where F: FnOnce(Box<dyn Write + Send>) -> Result<(), Error> {
+    let partial = cache.lock().unwrap().begin(key);
+    let file = partial.as_ref().and_then(|partial| match File::create(partial) {
+        Ok(file) => Some(BufWriter::new(file)),
+        Err(err) => {
+            log::warn!("[Cache] Failed to create {:?}: {:?}", partial, err);
+            None
+        }
+    });
+    let failed = Arc::new(AtomicBool::new(file.is_none()));
+
+    // The tee is dropped, closing the file, before the job returns
+    let tee = Tee { writer, file, failed: failed.clone() };
+    let result = job(Box::new(tee));
+
+    if let Some(partial) = partial {
+        if result.is_ok() && !failed.load(Ordering::Relaxed) {
+            if let Err(err) = cache.lock().unwrap().finish(key, &partial) {
+                log::warn!("[Cache] Failed to store {:?}: {:?}", &partial, err);
+            }
+        }
+        let _ = fs::remove_file(&partial);
+    }
+    result
+}
+
+/// Passes everything written to it on to `writer`, keeping a copy in `file`.
+/// Failing to write the copy only stops the copy.
+struct Tee {
+    writer: Box<dyn Write + Send>,
+    file: Option<BufWriter<File>>,
+    failed: Arc<AtomicBool>,
+}
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        if let Some(file) = &mut self.file {
+            if let Err(err) = file.write_all(&buf[..written]) {
+                log::warn!("[Cache] Failed to write output: {:?}", err);
+                self.file = None;
+                self.failed.store(true, Ordering::Relaxed);
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Drop for Tee {
+    fn drop(&mut self) {
+        if let Some(file) = &mut self.file {
+            if file.flush().is_err() {
+                self.failed.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names_are_stable() {
+        // Outputs of earlier builds are only found if this never changes
+        let key = Key::remux(Path::new("/media/movie.mkv"), 1_600_000_000);
+        assert_eq!(key.file_name(), "8ae1b90125ecb3b6.mp4");
+    }
+
+    #[test]
+    fn file_names_differ_by_field() {
+        let key = Key::remux(Path::new("/media/movie.mkv"), 1_600_000_000);
+        let renamed = Key::remux(Path::new("/media/movie2.mkv"), 1_600_000_000);
+        let edited = Key::remux(Path::new("/media/movie.mkv"), 1_600_000_001);
+        assert_ne!(key.file_name(), renamed.file_name());
+        assert_ne!(key.file_name(), edited.file_name());
+    }
+
+    /// Create an empty directory for a test's cache.
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mucaster-cache-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Put an output of `size` bytes last used at `last_used` into the cache.
+    fn add(cache: &mut Cache, key: &Key, size: usize, last_used: u64) {
+        let name = key.file_name();
+        fs::write(cache.dir.as_ref().unwrap().join(&name), vec![0; size]).unwrap();
+        cache.files.insert(name, CachedFile { size: size as u64, last_used });
+    }
+
+    fn output(index: usize) -> Key {
+        Key::subtitles(Path::new("/media/movie.mkv"), 1_600_000_000, index)
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let dir = temp_dir("evict");
+        let mut cache = Cache::open(dir.clone(), 25).unwrap();
+        add(&mut cache, &output(0), 10, 3);
+        add(&mut cache, &output(1), 10, 1);
+        add(&mut cache, &output(2), 10, 2);
+        cache.evict();
+
+        assert!(!cache.contains(&output(1)));
+        assert!(!dir.join(output(1).file_name()).exists());
+        assert!(cache.contains(&output(0)) && cache.contains(&output(2)));
+        assert_eq!(cache.size(), 20);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn get_marks_output_used() {
+        let dir = temp_dir("get");
+        let mut cache = Cache::open(dir.clone(), 25).unwrap();
+        add(&mut cache, &output(0), 10, 1);
+        add(&mut cache, &output(1), 10, 2);
+        add(&mut cache, &output(2), 10, 3);
+        assert_eq!(cache.get(&output(0)), Some(dir.join(output(0).file_name())));
+        cache.evict();
+
+        assert!(cache.contains(&output(0)));
+        assert!(!cache.contains(&output(1)));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn evicts_until_it_fits() {
+        let dir = temp_dir("fit");
+        let mut cache = Cache::open(dir.clone(), 10).unwrap();
+        add(&mut cache, &output(0), 4, 1);
+        add(&mut cache, &output(1), 4, 2);
+        add(&mut cache, &output(2), 4, 3);
+        add(&mut cache, &output(3), 4, 4);
+        cache.evict();
+
+        assert_eq!(cache.size(), 8);
+        assert!(cache.contains(&output(2)) && cache.contains(&output(3)));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn open_drops_partial_outputs() {
+        let dir = temp_dir("open");
+        fs::write(dir.join(output(0).file_name()), b"WEBVTT").unwrap();
+        fs::write(dir.join(format!("{}.0.{}", output(1).file_name(), PARTIAL_EXTENSION)), b"WEB").unwrap();
+        let cache = Cache::open(dir.clone(), DEFAULT_MAX_SIZE).unwrap();
+
+        assert!(cache.contains(&output(0)));
+        assert!(!cache.contains(&output(1)));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
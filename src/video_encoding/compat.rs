@@ -0,0 +1,479 @@
+use super::probe::{AudioStream, HdrFormat, MediaInfo, VideoStream};
+use serde::{Serialize, Deserialize};
+
+/// Chromecast hardware generations, each with its own decoding capabilities.
+#[derive(Eq, PartialEq, PartialOrd, Ord, Hash, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Chromecast {
+    FirstAndSecond,
+    Third,
+    Ultra,
+    GoogleTV,
+    NestHub,
+}
+
+/// What has to be done to a file before a Chromecast can play it.
+/// Ordered from least to most work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Action {
+    /// The file can be served as is
+    Direct,
+    /// The streams can be copied as is, but into a different container
+    Remux,
+    /// At least one stream has to be re-encoded
+    Transcode,
+}
+
+/// The compatibility of a single stream.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamVerdict {
+    /// Stream index within the container
+    pub index: usize,
+    pub compatible: bool,
+    /// Why the stream can't be played, empty when it is compatible
+    pub reasons: Vec<String>,
+}
+
+/// The compatibility of a whole file with one Chromecast generation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Verdict {
+    pub chromecast: Chromecast,
+    pub container: String,
+    pub container_supported: bool,
+    pub video: Vec<StreamVerdict>,
+    pub audio: Vec<StreamVerdict>,
+    /// Action needed to play the default video and audio streams
+    pub action: Action,
+}
+
+impl Verdict {
+    /// Returns the verdict for the video stream with the matching container index.
+    pub fn video(&self, index: usize) -> Option<&StreamVerdict> {
+        self.video.iter().find(|verdict| verdict.index == index)
+    }
+
+    /// Returns the verdict for the audio stream with the matching container index.
+    pub fn audio(&self, index: usize) -> Option<&StreamVerdict> {
+        self.audio.iter().find(|verdict| verdict.index == index)
+    }
+
+    /// Work out the action needed to play a specific pair of streams.
+    /// ### Arguments
+    /// - `video` - Container index of the video stream, `None` for no video
+    /// - `audio` - Container index of the audio stream, `None` for no audio
+    /// ### Returns
+    /// `Action::Transcode` if either stream doesn't exist or can't be played
+    pub fn action_for(&self, video: Option<usize>, audio: Option<usize>) -> Action {
+        let video_ok = video.map_or(true, |index| {
+            self.video(index).map_or(false, |verdict| verdict.compatible)
+        });
+        let audio_ok = audio.map_or(true, |index| {
+            self.audio(index).map_or(false, |verdict| verdict.compatible)
+        });
+
+        if !video_ok || !audio_ok {
+            Action::Transcode
+        } else if !self.container_supported {
+            Action::Remux
+        } else {
+            Action::Direct
+        }
+    }
+}
+
+/// A video codec a device can decode, and the limits it can decode it within.
+struct VideoProfile {
+    /// ffmpeg codec name
+    codec: &'static str,
+    /// Allowed codec profiles as named by the probe, empty allows any
+    profiles: &'static [&'static str],
+    /// Highest level in ffmpeg's units (H.264 `41` is 4.1, HEVC `153` is 5.1)
+    max_level: Option<i32>,
+    max_bit_depth: u8,
+    /// Supported (width, height, fps) modes, the stream must fit one of them
+    modes: &'static [(u32, u32, f64)],
+    hdr: &'static [HdrFormat],
+}
+
+/// An audio codec a device can decode.
+struct AudioProfile {
+    /// ffmpeg codec name
+    codec: &'static str,
+    max_sample_rate: u32,
+}
+
+/// Everything a Chromecast generation is able to play.
+struct DeviceProfile {
+    video: &'static [VideoProfile],
+    /// Tables of decodable audio codecs, searched in order
+    audio: &'static [&'static [AudioProfile]],
+}
+
+const H264_PROFILES: [&str; 4] = ["Baseline", "ConstrainedBaseline", "Main", "High"];
+const HEVC_PROFILES: [&str; 2] = ["Main", "Main10"];
+
+const MODES_720P60_1080P30: [(u32, u32, f64); 2] = [(1280, 720, 60.0), (1920, 1080, 30.0)];
+const MODES_1080P60: [(u32, u32, f64); 1] = [(1920, 1080, 60.0)];
+const MODES_1080P60_2160P30: [(u32, u32, f64); 2] = [(1920, 1080, 60.0), (3840, 2160, 30.0)];
+const MODES_2160P60: [(u32, u32, f64); 1] = [(3840, 2160, 60.0)];
+
+/// Audio codecs decoded by every generation.
+const COMMON_AUDIO: [AudioProfile; 7] = [
+    AudioProfile { codec: "aac", max_sample_rate: 48000 },
+    AudioProfile { codec: "mp3", max_sample_rate: 48000 },
+    AudioProfile { codec: "vorbis", max_sample_rate: 48000 },
+    AudioProfile { codec: "opus", max_sample_rate: 48000 },
+    AudioProfile { codec: "flac", max_sample_rate: 96000 },
+    AudioProfile { codec: "pcm_s16le", max_sample_rate: 96000 },
+    AudioProfile { codec: "pcm_s24le", max_sample_rate: 96000 },
+];
+
+/// Dolby codecs passed through to a receiver or TV by the surround capable
+/// generations, on top of `COMMON_AUDIO`.
+const DOLBY_AUDIO: [AudioProfile; 2] = [
+    AudioProfile { codec: "ac3", max_sample_rate: 48000 },
+    AudioProfile { codec: "eac3", max_sample_rate: 48000 },
+];
+
+const FIRST_AND_SECOND: DeviceProfile = DeviceProfile {
+    video: &[
+        VideoProfile { codec: "h264", profiles: &H264_PROFILES, max_level: Some(41),
+            max_bit_depth: 8, modes: &MODES_720P60_1080P30, hdr: &[] },
+        VideoProfile { codec: "vp8", profiles: &[], max_level: None,
+            max_bit_depth: 8, modes: &MODES_720P60_1080P30, hdr: &[] },
+    ],
+    audio: &[&COMMON_AUDIO],
+};
+
+const THIRD: DeviceProfile = DeviceProfile {
+    video: &[
+        VideoProfile { codec: "h264", profiles: &H264_PROFILES, max_level: Some(42),
+            max_bit_depth: 8, modes: &MODES_1080P60, hdr: &[] },
+        VideoProfile { codec: "vp8", profiles: &[], max_level: None,
+            max_bit_depth: 8, modes: &MODES_1080P60, hdr: &[] },
+    ],
+    audio: &[&COMMON_AUDIO],
+};
+
+const ULTRA: DeviceProfile = DeviceProfile {
+    video: &[
+        VideoProfile { codec: "h264", profiles: &H264_PROFILES, max_level: Some(42),
+            max_bit_depth: 8, modes: &MODES_1080P60, hdr: &[] },
+        VideoProfile { codec: "vp8", profiles: &[], max_level: None,
+            max_bit_depth: 8, modes: &MODES_1080P60, hdr: &[] },
+        VideoProfile { codec: "hevc", profiles: &HEVC_PROFILES, max_level: Some(153),
+            max_bit_depth: 10, modes: &MODES_2160P60, hdr: &[HdrFormat::Hdr10] },
+        VideoProfile { codec: "vp9", profiles: &[], max_level: None,
+            max_bit_depth: 10, modes: &MODES_2160P60, hdr: &[HdrFormat::Hdr10] },
+    ],
+    audio: &[&COMMON_AUDIO, &DOLBY_AUDIO],
+};
+
+const GOOGLE_TV: DeviceProfile = DeviceProfile {
+    video: &[
+        VideoProfile { codec: "h264", profiles: &H264_PROFILES, max_level: Some(51),
+            max_bit_depth: 8, modes: &MODES_1080P60_2160P30, hdr: &[] },
+        VideoProfile { codec: "vp8", profiles: &[], max_level: None,
+            max_bit_depth: 8, modes: &MODES_1080P60, hdr: &[] },
+        VideoProfile { codec: "hevc", profiles: &HEVC_PROFILES, max_level: Some(153),
+            max_bit_depth: 10, modes: &MODES_2160P60, hdr: &[HdrFormat::Hdr10, HdrFormat::Hlg] },
+        VideoProfile { codec: "vp9", profiles: &[], max_level: None,
+            max_bit_depth: 10, modes: &MODES_2160P60, hdr: &[HdrFormat::Hdr10, HdrFormat::Hlg] },
+    ],
+    audio: &[&COMMON_AUDIO, &DOLBY_AUDIO],
+};
+
+const NEST_HUB: DeviceProfile = DeviceProfile {
+    video: &[
+        VideoProfile { codec: "h264", profiles: &H264_PROFILES, max_level: Some(41),
+            max_bit_depth: 8, modes: &MODES_720P60_1080P30, hdr: &[] },
+        VideoProfile { codec: "vp8", profiles: &[], max_level: None,
+            max_bit_depth: 8, modes: &MODES_720P60_1080P30, hdr: &[] },
+        VideoProfile { codec: "vp9", profiles: &[], max_level: None,
+            max_bit_depth: 8, modes: &MODES_720P60_1080P30, hdr: &[] },
+    ],
+    audio: &[&COMMON_AUDIO],
+};
+
+/// The stream to produce when a file has to be transcoded for a generation.
+/// Video is always H.264, audio is always stereo AAC.
+#[derive(Debug, Clone, Copy)]
+pub struct TranscodeTarget {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_fps: f64,
+    /// H.264 level passed to the encoder, e.g. `4.1`
+    pub h264_level: &'static str,
+}
+
+/// Codecs that may be served in a WebM file without remuxing.
+const WEBM_CODECS: [&str; 5] = ["vp8", "vp9", "av1", "vorbis", "opus"];
+
+impl Chromecast {
+    fn profile(self) -> &'static DeviceProfile {
+        match self {
+            Chromecast::FirstAndSecond => &FIRST_AND_SECOND,
+            Chromecast::Third => &THIRD,
+            Chromecast::Ultra => &ULTRA,
+            Chromecast::GoogleTV => &GOOGLE_TV,
+            Chromecast::NestHub => &NEST_HUB,
+        }
+    }
+
+    /// Returns what to transcode into for this generation. 4K is never
+    /// targeted, encoding it live is too slow to be useful.
+    pub fn transcode_target(self) -> TranscodeTarget {
+        match self {
+            Chromecast::FirstAndSecond | Chromecast::NestHub => TranscodeTarget {
+                max_width: 1920, max_height: 1080, max_fps: 30.0, h264_level: "4.1",
+            },
+            Chromecast::Third | Chromecast::Ultra | Chromecast::GoogleTV => TranscodeTarget {
+                max_width: 1920, max_height: 1080, max_fps: 60.0, h264_level: "4.2",
+            },
+        }
+    }
+}
+
+/// Check every video and audio stream of a probed file against the
+/// capabilities of a Chromecast generation.
+/// ### Arguments
+/// - `info` - Probed media info of the file
+/// - `chromecast` - Generation of the target device
+/// ### Returns
+/// A `Verdict` listing which streams can be played as is, and the action
+/// needed to play the default streams.
+pub fn check(info: &MediaInfo, chromecast: Chromecast) -> Verdict {
+    let profile = chromecast.profile();
+
+    let video = info.video.iter()
+        .map(|stream| check_video(stream, profile))
+        .collect();
+    let audio = info.audio.iter()
+        .map(|stream| check_audio(stream, profile))
+        .collect();
+
+    let mut verdict = Verdict {
+        chromecast,
+        container: info.container.clone(),
+        container_supported: is_container_supported(info),
+        video,
+        audio,
+        action: Action::Direct,
+    };
+    verdict.action = verdict.action_for(
+        info.default_video().map(|stream| stream.index),
+        info.default_audio().map(|stream| stream.index),
+    );
+
+    verdict
+}
+
+fn check_video(stream: &VideoStream, profile: &DeviceProfile) -> StreamVerdict {
+    let mut reasons = Vec::new();
+
+    match profile.video.iter().find(|video| video.codec == stream.codec) {
+        None => reasons.push(format!("Unsupported video codec {}", stream.codec)),
+        Some(video) => {
+            if let Some(name) = &stream.profile {
+                if !video.profiles.is_empty() && !video.profiles.contains(&name.as_str()) {
+                    reasons.push(format!("Unsupported {} profile {}", stream.codec, name));
+                }
+            }
+            if let (Some(level), Some(max_level)) = (stream.level, video.max_level) {
+                if level > max_level {
+                    reasons.push(format!("Level {} is above {}", level, max_level));
+                }
+            }
+            if stream.bit_depth.unwrap_or(8) > video.max_bit_depth {
+                reasons.push(format!("{}-bit colour is unsupported", stream.bit_depth.unwrap_or(8)));
+            }
+            if !fits_mode(stream, video.modes) {
+                reasons.push(format!("{}x{} at {:.2} fps is too demanding",
+                    stream.width, stream.height, stream.frame_rate.unwrap_or(0.0)));
+            }
+            if let Some(hdr) = stream.hdr {
+                if !video.hdr.contains(&hdr) {
+                    reasons.push(format!("{:?} is unsupported", hdr));
+                }
+            }
+        }
+    }
+
+    StreamVerdict { index: stream.index, compatible: reasons.is_empty(), reasons }
+}
+
+fn check_audio(stream: &AudioStream, profile: &DeviceProfile) -> StreamVerdict {
+    let mut reasons = Vec::new();
+
+    let supported = profile.audio.iter().copied().flatten()
+        .find(|audio| audio.codec == stream.codec);
+    match supported {
+        None => reasons.push(format!("Unsupported audio codec {}", stream.codec)),
+        Some(audio) => {
+            if stream.sample_rate > audio.max_sample_rate {
+                reasons.push(format!("Sample rate {} Hz is above {} Hz",
+                    stream.sample_rate, audio.max_sample_rate));
+            }
+        }
+    }
+
+    StreamVerdict { index: stream.index, compatible: reasons.is_empty(), reasons }
+}
+
+/// Check if a video stream fits within one of the supported modes.
+/// Portrait video is compared with its sides swapped.
+fn fits_mode(stream: &VideoStream, modes: &[(u32, u32, f64)]) -> bool {
+    let long = stream.width.max(stream.height);
+    let short = stream.width.min(stream.height);
+    // Allow a little slack for 29.97/59.94 style rates
+    let fps = stream.frame_rate.unwrap_or(0.0) - 0.1;
+
+    modes.iter().any(|(width, height, max_fps)| {
+        long <= *width && short <= *height && fps <= *max_fps
+    })
+}
+
+/// Check if the Chromecast can load the file's container directly.
+/// MP4 is always fine, Matroska only when it is really a WebM file.
+fn is_container_supported(info: &MediaInfo) -> bool {
+    let formats: Vec<&str> = info.container.split(',').collect();
+    if formats.contains(&"mp4") {
+        return true;
+    }
+    if formats.contains(&"webm") {
+        return info.video.iter().map(|stream| &stream.codec)
+            .chain(info.audio.iter().map(|stream| &stream.codec))
+            .all(|codec| WEBM_CODECS.contains(&codec.as_str()));
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(codec: &str, profile: &str, level: i32, size: (u32, u32), fps: f64) -> VideoStream {
+        VideoStream {
+            index: 0,
+            codec: codec.into(),
+            profile: Some(profile.into()),
+            level: Some(level),
+            width: size.0,
+            height: size.1,
+            frame_rate: Some(fps),
+            pixel_format: None,
+            bit_depth: Some(8),
+            hdr: None,
+            language: None,
+            title: None,
+            default: true,
+        }
+    }
+
+    fn audio(index: usize, codec: &str, sample_rate: u32) -> AudioStream {
+        AudioStream {
+            index,
+            codec: codec.into(),
+            profile: None,
+            channels: 2,
+            channel_layout: "stereo".into(),
+            sample_rate,
+            language: None,
+            title: None,
+            default: index == 1,
+        }
+    }
+
+    fn media(container: &str, video: Vec<VideoStream>, audio: Vec<AudioStream>) -> MediaInfo {
+        MediaInfo { container: container.into(), video, audio, ..Default::default() }
+    }
+
+    fn hevc_4k(hdr: HdrFormat) -> VideoStream {
+        VideoStream { bit_depth: Some(10), hdr: Some(hdr), ..video("hevc", "Main10", 153, (3840, 2160), 24.0) }
+    }
+
+    const MP4: &str = "mov,mp4,m4a,3gp,3g2,mj2";
+    const MATROSKA: &str = "matroska,webm";
+    const GENERATIONS: [Chromecast; 5] = [
+        Chromecast::FirstAndSecond, Chromecast::Third, Chromecast::Ultra, Chromecast::GoogleTV, Chromecast::NestHub,
+    ];
+
+    #[test]
+    fn h264_1080p_plays_everywhere() {
+        let info = media(MP4, vec![video("h264", "High", 41, (1920, 1080), 23.976)], vec![audio(1, "aac", 48000)]);
+        for chromecast in GENERATIONS.iter() {
+            assert_eq!(check(&info, *chromecast).action, Action::Direct, "{:?}", chromecast);
+        }
+    }
+
+    #[test]
+    fn matroska_is_remuxed() {
+        let info = media(MATROSKA, vec![video("h264", "High", 41, (1920, 1080), 24.0)], vec![audio(1, "aac", 48000)]);
+        let verdict = check(&info, Chromecast::FirstAndSecond);
+        assert!(!verdict.container_supported);
+        assert_eq!(verdict.action, Action::Remux);
+    }
+
+    #[test]
+    fn webm_plays_directly() {
+        let info = media(MATROSKA, vec![video("vp9", "Profile 0", 0, (1920, 1080), 30.0)], vec![audio(1, "opus", 48000)]);
+        assert_eq!(check(&info, Chromecast::NestHub).action, Action::Direct);
+        // Only the later generations decode VP9
+        let verdict = check(&info, Chromecast::FirstAndSecond);
+        assert_eq!(verdict.action, Action::Transcode);
+        assert_eq!(verdict.video[0].reasons, vec!["Unsupported video codec vp9"]);
+    }
+
+    #[test]
+    fn h264_1080p60_needs_a_third_generation() {
+        let info = media(MP4, vec![video("h264", "High", 42, (1920, 1080), 59.94)], vec![audio(1, "aac", 48000)]);
+        let verdict = check(&info, Chromecast::FirstAndSecond);
+        assert_eq!(verdict.action, Action::Transcode);
+        assert_eq!(verdict.video[0].reasons.len(), 2);
+        assert_eq!(verdict.video[0].reasons[0], "Level 42 is above 41");
+
+        for chromecast in [Chromecast::Third, Chromecast::Ultra, Chromecast::GoogleTV].iter() {
+            assert_eq!(check(&info, *chromecast).action, Action::Direct, "{:?}", chromecast);
+        }
+    }
+
+    #[test]
+    fn portrait_video_is_compared_on_its_side() {
+        let info = media(MP4, vec![video("h264", "High", 40, (1080, 1920), 30.0)], vec![]);
+        assert_eq!(check(&info, Chromecast::FirstAndSecond).action, Action::Direct);
+    }
+
+    #[test]
+    fn hdr10_hevc_needs_an_ultra() {
+        let info = media(MP4, vec![hevc_4k(HdrFormat::Hdr10)], vec![audio(1, "eac3", 48000)]);
+        let actions: Vec<Action> = GENERATIONS.iter().map(|chromecast| check(&info, *chromecast).action).collect();
+        assert_eq!(actions, vec![Action::Transcode, Action::Transcode, Action::Direct, Action::Direct, Action::Transcode]);
+    }
+
+    #[test]
+    fn hlg_needs_a_google_tv() {
+        let info = media(MP4, vec![hevc_4k(HdrFormat::Hlg)], vec![]);
+        let verdict = check(&info, Chromecast::Ultra);
+        assert_eq!(verdict.action, Action::Transcode);
+        assert_eq!(verdict.video[0].reasons, vec!["Hlg is unsupported"]);
+        assert_eq!(check(&info, Chromecast::GoogleTV).action, Action::Direct);
+    }
+
+    #[test]
+    fn audio_is_checked_per_stream() {
+        let info = media(MP4, vec![video("h264", "High", 41, (1920, 1080), 24.0)],
+            vec![audio(1, "ac3", 48000), audio(2, "aac", 48000), audio(3, "flac", 192000)]);
+        let verdict = check(&info, Chromecast::FirstAndSecond);
+        // The default audio is Dolby, which only passes through on the later generations
+        assert_eq!(verdict.action, Action::Transcode);
+        assert_eq!(verdict.action_for(Some(0), Some(2)), Action::Direct);
+        assert_eq!(verdict.audio(3).unwrap().reasons, vec!["Sample rate 192000 Hz is above 96000 Hz"]);
+        assert_eq!(verdict.action_for(Some(0), Some(3)), Action::Transcode);
+        // Streams that don't exist can't be played
+        assert_eq!(verdict.action_for(Some(0), Some(9)), Action::Transcode);
+        assert_eq!(check(&info, Chromecast::Ultra).action, Action::Direct);
+    }
+}
@@ -0,0 +1,40 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum EncodingError {
+    FfmpegError(ffmpeg::Error),
+    IoError(std::io::Error),
+    /// The file has no stream with this index, or not of the kind needed
+    StreamNotFound(usize),
+    /// There is no decoder or encoder for the codec
+    UnsupportedCodec(String),
+    /// The file was never probed, so there is nothing to plan a conversion with
+    NotProbed,
+    /// The file's duration is unknown, so it can't be split into segments
+    UnknownDuration,
+    /// The conversion was stopped through its `Progress`
+    Cancelled,
+}
+impl From<ffmpeg::Error> for EncodingError {
+    fn from(err: ffmpeg::Error) -> Self {
+        EncodingError::FfmpegError(err)
+    }
+}
+impl From<std::io::Error> for EncodingError {
+    fn from(err: std::io::Error) -> Self {
+        EncodingError::IoError(err)
+    }
+}
+impl fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodingError::FfmpegError(err) => write!(f, "ffmpeg failed: {}", err),
+            EncodingError::IoError(err) => write!(f, "I/O failed: {}", err),
+            EncodingError::StreamNotFound(index) => write!(f, "No usable stream {} in the file", index),
+            EncodingError::UnsupportedCodec(codec) => write!(f, "The codec {} is not supported", codec),
+            EncodingError::NotProbed => write!(f, "The file has not been probed"),
+            EncodingError::UnknownDuration => write!(f, "The duration of the file is unknown"),
+            EncodingError::Cancelled => write!(f, "The conversion was cancelled"),
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use super::{probe, Error};
+use ffmpeg::{format, media, subtitle::Rect, Subtitle};
+use std::path::Path;
+
+/// A subtitle decoded from a text subtitle stream. Times are in seconds.
+#[derive(Debug, Clone)]
+pub struct TextEvent {
+    pub start: f64,
+    pub end: f64,
+    /// The text in ASS dialogue markup, which ffmpeg converts every text
+    /// subtitle codec into
+    pub text: String,
+}
+
+/// Decode a text subtitle stream of a file, e.g. SubRip, ASS or mov_text.
+/// ### Arguments
+/// - `input` - Path of the media file
+/// - `index` - Container index of the subtitle stream
+/// ### Returns
+/// The events in the order they appear, or `Error::UnsupportedCodec` if the
+/// stream holds bitmap subtitles
+pub fn text_subtitles(input: &Path, index: usize) -> Result<Vec<TextEvent>, Error> {
+    ffmpeg::init()?;
+    let mut ictx = format::input(&input)?;
+
+    let (mut decoder, time_base) = {
+        let stream = ictx.stream(index)
+            .filter(|stream| stream.codec().medium() == media::Type::Subtitle)
+            .ok_or(Error::StreamNotFound(index))?;
+        let codec = stream.codec();
+        let id = codec.id();
+        if probe::is_bitmap_subtitle(id) {
+            return Err(Error::UnsupportedCodec(id.name().to_string()));
+        }
+        let decoder = codec.decoder().subtitle().map_err(|err| match err {
+            ffmpeg::Error::DecoderNotFound => Error::UnsupportedCodec(id.name().to_string()),
+            err => err.into(),
+        })?;
+        (decoder, stream.time_base())
+    };
+
+    // Events without an end are closed by the event after them
+    let mut events: Vec<(f64, Option<f64>, String)> = Vec::new();
+    for (stream, packet) in ictx.packets() {
+        if stream.index() != index {
+            continue;
+        }
+        let mut subtitle = Subtitle::new();
+        if !decoder.decode(&packet, &mut subtitle)? {
+            continue;
+        }
+
+        let text: Vec<String> = subtitle.rects()
+            .filter_map(|rect| match rect {
+                Rect::Ass(ass) => Some(dialogue_text(ass.get()).to_string()),
+                Rect::Text(text) => Some(text.get().to_string()),
+                _ => None,
+            })
+            .collect();
+        if text.is_empty() {
+            continue;
+        }
+
+        // Display times are in milliseconds from the subtitle's timestamp
+        let packet_time = packet.pts().or(packet.dts())
+            .map(|pts| pts as f64 * f64::from(time_base));
+        let base = match subtitle.pts() {
+            Some(pts) => pts as f64 * f64::from(ffmpeg::rescale::TIME_BASE),
+            None => match packet_time {
+                Some(time) => time,
+                None => continue,
+            },
+        };
+        let start = base + f64::from(subtitle.start()) / 1000.0;
+        let end = if subtitle.end() > subtitle.start() && subtitle.end() != u32::MAX {
+            Some(base + f64::from(subtitle.end()) / 1000.0)
+        } else if packet.duration() > 0 {
+            packet_time.map(|time| time + packet.duration() as f64 * f64::from(time_base))
+        } else {
+            None
+        };
+        events.push((start, end, text.join("\\N")));
+    }
+
+    let starts: Vec<f64> = events.iter().map(|(start, _, _)| *start).skip(1).collect();
+    let events = events.into_iter()
+        .enumerate()
+        .filter_map(|(i, (start, end, text))| {
+            let end = end.or_else(|| starts.get(i).copied())?;
+            Some(TextEvent { start, end, text })
+        })
+        .collect::<Vec<_>>();
+
+    log::info!("[Extract] Decoded {} subtitles from stream {} of {:?}", events.len(), index, input);
+    Ok(events)
+}
+
+/// Pick the text out of an ASS event as ffmpeg writes it, either
+/// `ReadOrder,Layer,Style,Name,MarginL,MarginR,MarginV,Effect,Text` or, by
+/// older versions, a full `Dialogue:` line.
+fn dialogue_text(event: &str) -> &str {
+    let (fields, event) = match event.strip_prefix("Dialogue:") {
+        Some(dialogue) => (10, dialogue),
+        None => (9, event),
+    };
+    event.trim_end().splitn(fields, ',').last().unwrap_or_default()
+}
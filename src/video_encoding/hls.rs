@@ -0,0 +1,214 @@
+use super::{
+    probe::MediaInfo,
+    transcode::{self, Mode, Plan},
+    Error,
+};
+use ffmpeg::{format, media, Dictionary};
+use std::{fmt::Write as _, io::Write, path::Path};
+
+/// Content type of an HLS playlist
+pub const CONTENT_TYPE: &str = "application/x-mpegurl";
+/// Content type of an HLS segment
+pub const SEGMENT_CONTENT_TYPE: &str = "video/mp2t";
+/// Length segments are cut at, copied video is cut at the keyframe before this
+const SEGMENT_SECONDS: f64 = 6.0;
+
+/// A time range of the media, in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment {
+    pub start: f64,
+    pub end: f64,
+}
+
+impl Segment {
+    pub fn duration(&self) -> f64 {
+        self.end - self.start
+    }
+}
+
+/// The segments a file is split into for HLS, covering its full duration.
+/// Segments are only produced when the receiver asks for them, so seeking
+/// anywhere only costs the segment it lands in.
+#[derive(Debug, Clone)]
+pub struct Playlist {
+    pub segments: Vec<Segment>,
+}
+
+impl Playlist {
+    /// Split a file into segments for `plan`.
+    ///
+    /// Transcoded video starts every segment on a fresh keyframe, so it is
+    /// cut every `SEGMENT_SECONDS`. Copied video can only be cut where it already
+    /// has keyframes, which are found by seeking to each cut.
+    /// ### Arguments
+    /// - `input` - Path of the media file
+    /// - `info` - Probed info of the file
+    /// - `plan` - How the file is being converted
+    /// ### Returns
+    /// `Error::UnknownDuration` if the file has no known duration
+    pub fn new(input: &Path, info: &MediaInfo, plan: &Plan) -> Result<Self, Error> {
+        let duration = info.duration.ok_or(Error::UnknownDuration)?;
+
+        let mut cuts = match plan.video {
+            Some(video) if video.mode == Mode::Copy => keyframe_cuts(input, video.index, duration)?,
+            _ => {
+                let count = (duration / SEGMENT_SECONDS).ceil() as usize;
+                (0..count).map(|i| i as f64 * SEGMENT_SECONDS).collect()
+            }
+        };
+        cuts.retain(|cut| *cut < duration);
+        // The first segment always starts at the beginning, whatever the stream's start time
+        if cuts.is_empty() {
+            cuts.push(0.0);
+        }
+        cuts[0] = 0.0;
+        cuts.push(duration);
+
+        let segments = cuts.windows(2)
+            .map(|cut| Segment { start: cut[0], end: cut[1] })
+            .collect();
+        Ok(Self { segments })
+    }
+
+    /// Write the playlist as an m3u8 VOD playlist.
+    /// ### Arguments
+    /// - `segment_uri` - Builds the URI of a segment from its index
+    pub fn to_m3u8<F: Fn(usize) -> String>(&self, segment_uri: F) -> String {
+        let target_duration = self.segments.iter()
+            .map(Segment::duration)
+            .fold(0.0, f64::max)
+            .ceil();
+
+        let mut m3u8 = String::new();
+        // Writing to a String can't fail
+        let _ = writeln!(m3u8, "#EXTM3U");
+        let _ = writeln!(m3u8, "#EXT-X-VERSION:3");
+        let _ = writeln!(m3u8, "#EXT-X-TARGETDURATION:{}", target_duration);
+        let _ = writeln!(m3u8, "#EXT-X-MEDIA-SEQUENCE:0");
+        let _ = writeln!(m3u8, "#EXT-X-PLAYLIST-TYPE:VOD");
+        for (index, segment) in self.segments.iter().enumerate() {
+            let _ = writeln!(m3u8, "#EXTINF:{:.3},", segment.duration());
+            let _ = writeln!(m3u8, "{}", segment_uri(index));
+        }
+        let _ = writeln!(m3u8, "#EXT-X-ENDLIST");
+        m3u8
+    }
+}
+
+/// Convert one segment of a file into MPEG-TS following `plan`, writing it
+/// to `writer` as it is produced.
+/// ### Arguments
+/// - `input` - Path of the media file
+/// - `plan` - Streams to include and how to convert them
+/// - `segment` - Time range of the file to convert
+/// - `writer` - Destination of the MPEG-TS data
+pub fn segment(input: &Path, plan: &Plan, segment: &Segment, writer: Box<dyn Write + Send>)
+    -> Result<(), Error> {
+    let output = transcode::Output {
+        format: "mpegts",
+        options: Dictionary::new(),
+        window: Some((segment.start, segment.end)),
+    };
+    transcode::run(input, plan, output, writer, None)
+}
+
+/// Find where a video stream can be cut into segments of about `SEGMENT_SECONDS`.
+/// ### Returns
+/// The time of the keyframe at or before each multiple of `SEGMENT_SECONDS`,
+/// in seconds and ascending
+fn keyframe_cuts(input: &Path, index: usize, duration: f64) -> Result<Vec<f64>, Error> {
+    ffmpeg::init()?;
+    let mut ictx = format::input(&input)?;
+    let time_base = ictx.stream(index)
+        .filter(|stream| stream.codec().medium() == media::Type::Video)
+        .ok_or(Error::StreamNotFound(index))?
+        .time_base();
+
+    let mut cuts: Vec<f64> = Vec::new();
+    let mut target = 0.0;
+    while target < duration {
+        let position = (target / f64::from(ffmpeg::rescale::TIME_BASE)) as i64;
+        ictx.seek(position, ..=position)?;
+
+        // The first keyframe of the stream after seeking is where the demuxer landed
+        let keyframe = ictx.packets()
+            .filter(|(stream, packet)| stream.index() == index && packet.is_key())
+            .find_map(|(_, packet)| packet.pts().or(packet.dts()));
+        let time = match keyframe {
+            Some(pts) => pts as f64 * f64::from(time_base),
+            None => break,
+        };
+
+        // Long GOPs land on the same keyframe more than once
+        if cuts.last().map_or(true, |last| time > *last) {
+            cuts.push(time);
+        }
+        target += SEGMENT_SECONDS;
+    }
+
+    log::info!("[HLS] Found {} keyframe cuts in {:?}", cuts.len(), input);
+    Ok(cuts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video_encoding::{compat::Chromecast, transcode::PlannedStream};
+
+    fn transcoded_playlist(duration: Option<f64>) -> Result<Playlist, Error> {
+        let info = MediaInfo { duration, ..Default::default() };
+        let plan = Plan {
+            chromecast: Chromecast::FirstAndSecond,
+            video: Some(PlannedStream { index: 0, mode: Mode::Transcode }),
+            audio: Some(PlannedStream { index: 1, mode: Mode::Copy }),
+            burn_in: None,
+        };
+        // Transcoded video is cut without opening the file
+        Playlist::new(Path::new("/nonexistent.mkv"), &info, &plan)
+    }
+
+    fn bounds(playlist: &Playlist) -> Vec<(f64, f64)> {
+        playlist.segments.iter().map(|segment| (segment.start, segment.end)).collect()
+    }
+
+    #[test]
+    fn cuts_every_segment_length() {
+        let playlist = transcoded_playlist(Some(20.0)).unwrap();
+        assert_eq!(bounds(&playlist), vec![(0.0, 6.0), (6.0, 12.0), (12.0, 18.0), (18.0, 20.0)]);
+    }
+
+    #[test]
+    fn no_empty_segment_at_the_end() {
+        let playlist = transcoded_playlist(Some(18.0)).unwrap();
+        assert_eq!(bounds(&playlist), vec![(0.0, 6.0), (6.0, 12.0), (12.0, 18.0)]);
+    }
+
+    #[test]
+    fn short_media_is_one_segment() {
+        let playlist = transcoded_playlist(Some(0.5)).unwrap();
+        assert_eq!(bounds(&playlist), vec![(0.0, 0.5)]);
+    }
+
+    #[test]
+    fn unknown_duration_fails() {
+        assert!(matches!(transcoded_playlist(None), Err(Error::UnknownDuration)));
+    }
+
+    #[test]
+    fn writes_m3u8() {
+        let playlist = Playlist {
+            segments: vec![Segment { start: 0.0, end: 5.005 }, Segment { start: 5.005, end: 11.5 }],
+        };
+        let m3u8 = playlist.to_m3u8(|index| format!("segment/{}.ts", index));
+        assert_eq!(m3u8, "#EXTM3U\n\
+            #EXT-X-VERSION:3\n\
+            #EXT-X-TARGETDURATION:7\n\
+            #EXT-X-MEDIA-SEQUENCE:0\n\
+            #EXT-X-PLAYLIST-TYPE:VOD\n\
+            #EXTINF:5.005,\n\
+            segment/0.ts\n\
+            #EXTINF:6.495,\n\
+            segment/1.ts\n\
+            #EXT-X-ENDLIST\n");
+    }
+}
@@ -0,0 +1,109 @@
+mod burn_in;
+pub mod cache;
+pub mod compat;
+pub mod error;
+pub mod extract;
+pub mod hls;
+pub mod probe;
+pub mod progress;
+pub mod sink;
+pub mod transcode;
+
+use ffmpeg::{
+    codec, encoder, format, log, media, Dictionary, Rational,
+};
+use sink::Sink;
+use std::{io::Write, path::Path};
+
+pub use compat::{Action, Chromecast};
+pub use probe::{probe, MediaInfo};
+pub use progress::Progress;
+
+pub type Error = error::EncodingError;
+
+/// Test if the default video and audio streams of a file can be played
+/// by a specific chromecast without any work.
+/// 
+/// Use `compat::check` for a breakdown of each stream.
+#[allow(dead_code)]
+pub fn is_chromecast_compatible(info: &MediaInfo, chromecast: Chromecast) -> bool {
+    compat::check(info, chromecast).action == Action::Direct
+}
+
+/// Move the video and audio streams of a file into fragmented MP4, which is
+/// written to `writer` as it is produced. No stream is re-encoded, so this
+/// only works for codecs the Chromecast can already play.
+///
+/// This is ripped straight from ffmpeg-next examples.
+/// https://github.com/zmwangx/rust-ffmpeg/blob/5ed41c84ff877dc9ae9bd76412c86ee03afb5282/examples/remux.rs
+/// Bless their soul for providing the multimedia voodoo code.
+///
+/// `progress` is updated as the file is read, and checked for cancellation.
+///
+/// #### Usage
+/// `remux(Path::new("media.mkv"), Box::new(File::create("media.mp4")?), None);`
+pub fn remux(input: &Path, writer: Box<dyn Write + Send>, progress: Option<&Progress>)
+    -> Result<(), Error> { 
+    ffmpeg::init()?;
+    log::set_level(log::Level::Warning);
+
+    let mut ictx = format::input(&input)?;
+    let writer = match progress {
+        Some(progress) => progress.count_output(writer),
+        None => writer,
+    };
+    let mut octx = Sink::new("mp4", writer)?;
+
+    let mut stream_mapping = vec![0; ictx.nb_streams() as _];
+    let mut ist_time_bases = vec![Rational(0, 1); ictx.nb_streams() as _];
+    let mut ost_index = 0;
+    for (ist_index, ist) in ictx.streams().enumerate() {
+        let ist_medium = ist.codec().medium();
+        // Subtitles are left out, MP4 can only carry mov_text
+        if ist_medium != media::Type::Audio
+            && ist_medium != media::Type::Video
+        {
+            stream_mapping[ist_index] = -1;
+            continue;
+        }
+        stream_mapping[ist_index] = ost_index;
+        ist_time_bases[ist_index] = ist.time_base();
+        ost_index += 1;
+        let mut ost = octx.add_stream(encoder::find(codec::Id::None))?;
+        ost.set_parameters(ist.parameters());
+        // We need to set codec_tag to 0 lest we run into incompatible codec tag
+        // issues when muxing into a different container format. Unfortunately
+        // there's no high level API to do this (yet).
+        unsafe {
+            (*ost.parameters().as_mut_ptr()).codec_tag = 0;
+        }
+    }
+
+    octx.set_metadata(ictx.metadata().to_owned());
+    let mut options = Dictionary::new();
+    options.set("movflags", sink::FRAGMENTED_MP4_FLAGS);
+    octx.write_header_with(options)?;
+
+    for (stream, mut packet) in ictx.packets() {
+        let ist_index = stream.index();
+        let ost_index = stream_mapping[ist_index];
+        if ost_index < 0 {
+            continue;
+        }
+        if let Some(progress) = progress {
+            let time = packet.dts().or(packet.pts()).unwrap_or(0);
+            progress.update(time as f64 * f64::from(ist_time_bases[ist_index]))?;
+            if stream.codec().medium() == media::Type::Video {
+                progress.add_frame();
+            }
+        }
+        let ost = octx.stream(ost_index as _).ok_or(Error::StreamNotFound(ist_index))?;
+        packet.rescale_ts(ist_time_bases[ist_index], ost.time_base());
+        packet.set_position(-1);
+        packet.set_stream(ost_index as _);
+        packet.write_interleaved(&mut octx)?;
+    }
+
+    octx.write_trailer()?;
+    Ok(())
+}
@@ -0,0 +1,312 @@
+use ffmpeg::{
+    codec::{self, Profile}, color::TransferCharacteristic, format::{self, stream::Disposition}, 
+    media, ChannelLayout, DictionaryRef, Rational, Stream,
+};
+use super::Error;
+use serde::{Serialize, Deserialize};
+use std::{collections::BTreeMap, path::Path};
+
+/// Everything worth knowing about a media file, as reported by ffmpeg.
+/// Times are in seconds.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaInfo {
+    /// Short name(s) of the container format, e.g. `mov,mp4,m4a,3gp,3g2,mj2`
+    pub container: String,
+    pub duration: Option<f64>,
+    /// Total bit rate in bits/s
+    pub bit_rate: Option<i64>,
+    pub video: Vec<VideoStream>,
+    pub audio: Vec<AudioStream>,
+    pub subtitles: Vec<SubtitleStream>,
+    pub chapters: Vec<Chapter>,
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VideoStream {
+    /// Index of the stream within the container
+    pub index: usize,
+    /// ffmpeg's short name for the codec, e.g. `h264`
+    pub codec: String,
+    pub profile: Option<String>,
+    pub level: Option<i32>,
+    pub width: u32,
+    pub height: u32,
+    pub frame_rate: Option<f64>,
+    pub pixel_format: Option<String>,
+    pub bit_depth: Option<u8>,
+    pub hdr: Option<HdrFormat>,
+    pub language: Option<String>,
+    pub title: Option<String>,
+    pub default: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AudioStream {
+    pub index: usize,
+    pub codec: String,
+    pub profile: Option<String>,
+    pub channels: u16,
+    pub channel_layout: String,
+    pub sample_rate: u32,
+    pub language: Option<String>,
+    pub title: Option<String>,
+    pub default: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubtitleStream {
+    pub index: usize,
+    pub codec: String,
+    /// Bitmap subtitles (PGS, VobSub...) can't be converted to text
+    pub bitmap: bool,
+    pub language: Option<String>,
+    pub title: Option<String>,
+    pub default: bool,
+    pub forced: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Chapter {
+    pub start: f64,
+    pub end: f64,
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HdrFormat {
+    /// SMPTE ST 2084 (PQ) transfer, used by HDR10 and HDR10+
+    Hdr10,
+    /// ARIB STD-B67 transfer
+    Hlg,
+}
+
+impl MediaInfo {
+    /// Returns the video stream with the matching container index, if there is one.
+    pub fn video_stream(&self, index: usize) -> Option<&VideoStream> {
+        self.video.iter().find(|stream| stream.index == index)
+    }
+
+    /// Returns the audio stream with the matching container index, if there is one.
+    pub fn audio_stream(&self, index: usize) -> Option<&AudioStream> {
+        self.audio.iter().find(|stream| stream.index == index)
+    }
+
+    /// Returns the subtitle stream with the matching container index, if there is one.
+    pub fn subtitle_stream(&self, index: usize) -> Option<&SubtitleStream> {
+        self.subtitles.iter().find(|stream| stream.index == index)
+    }
+
+    /// Returns the video stream a player would pick, the one flagged default
+    /// or else the first.
+    pub fn default_video(&self) -> Option<&VideoStream> {
+        self.video.iter().find(|stream| stream.default).or_else(|| self.video.first())
+    }
+
+    /// Returns the audio stream a player would pick, the one flagged default
+    /// or else the first.
+    pub fn default_audio(&self) -> Option<&AudioStream> {
+        self.audio.iter().find(|stream| stream.default).or_else(|| self.audio.first())
+    }
+}
+
+/// Probe a media file for its container, streams, chapters and metadata.
+/// Streams that ffmpeg has no decoder for are still listed, with whatever
+/// could be read without one.
+pub fn probe(input: &Path) -> Result<MediaInfo, Error> {
+    ffmpeg::init()?;
+
+    let ictx = format::input(&input)?;
+    let mut info = MediaInfo {
+        container: ictx.format().name().to_string(),
+        duration: to_seconds(ictx.duration(), ffmpeg::rescale::TIME_BASE)
+            .filter(|duration| *duration > 0.0),
+        bit_rate: Some(ictx.bit_rate()).filter(|rate| *rate > 0),
+        metadata: to_map(ictx.metadata()),
+        ..MediaInfo::default()
+    };
+
+    for stream in ictx.streams() {
+        // Cover art is stored as a video stream, it isn't worth listing
+        if stream.disposition().contains(Disposition::ATTACHED_PIC) {
+            continue;
+        }
+        match stream.codec().medium() {
+            media::Type::Video => info.video.push(probe_video(&stream)),
+            media::Type::Audio => info.audio.push(probe_audio(&stream)),
+            media::Type::Subtitle => info.subtitles.push(probe_subtitle(&stream)),
+            _ => {}
+        }
+    }
+
+    for chapter in ictx.chapters() {
+        let time_base = chapter.time_base();
+        info.chapters.push(Chapter {
+            start: to_seconds(chapter.start(), time_base).unwrap_or(0.0),
+            end: to_seconds(chapter.end(), time_base).unwrap_or(0.0),
+            title: chapter.metadata().get("title").map(String::from),
+        });
+    }
+
+    Ok(info)
+}
+
+fn probe_video(stream: &Stream) -> VideoStream {
+    let codec = stream.codec();
+    let mut info = VideoStream {
+        index: stream.index(),
+        codec: codec.id().name().to_string(),
+        profile: None,
+        level: None,
+        width: 0,
+        height: 0,
+        frame_rate: to_f64(stream.avg_frame_rate()),
+        pixel_format: None,
+        bit_depth: None,
+        hdr: None,
+        language: stream.metadata().get("language").map(String::from),
+        title: stream.metadata().get("title").map(String::from),
+        default: stream.disposition().contains(Disposition::DEFAULT),
+    };
+
+    // The rest needs an opened decoder
+    let decoder = match codec.decoder().video() {
+        Ok(decoder) => decoder,
+        Err(err) => {
+            log::warn!("[Probe] No decoder for video stream {}: {:?}", info.index, err);
+            return info;
+        }
+    };
+    info.profile = profile_name(decoder.profile());
+    info.level = Some(unsafe { (*decoder.as_ptr()).level }).filter(|level| *level > 0);
+    info.width = decoder.width();
+    info.height = decoder.height();
+    if let Some(descriptor) = decoder.format().descriptor() {
+        info.pixel_format = Some(descriptor.name().to_string());
+        info.bit_depth = Some(unsafe { (*descriptor.as_ptr()).comp[0].depth as u8 });
+    }
+    info.hdr = match decoder.color_transfer_characteristic() {
+        TransferCharacteristic::SMPTE2084 => Some(HdrFormat::Hdr10),
+        TransferCharacteristic::ARIB_STD_B67 => Some(HdrFormat::Hlg),
+        _ => None,
+    };
+
+    info
+}
+
+fn probe_audio(stream: &Stream) -> AudioStream {
+    let codec = stream.codec();
+    let mut info = AudioStream {
+        index: stream.index(),
+        codec: codec.id().name().to_string(),
+        profile: None,
+        channels: 0,
+        channel_layout: String::new(),
+        sample_rate: 0,
+        language: stream.metadata().get("language").map(String::from),
+        title: stream.metadata().get("title").map(String::from),
+        default: stream.disposition().contains(Disposition::DEFAULT),
+    };
+
+    let decoder = match codec.decoder().audio() {
+        Ok(decoder) => decoder,
+        Err(err) => {
+            log::warn!("[Probe] No decoder for audio stream {}: {:?}", info.index, err);
+            return info;
+        }
+    };
+    info.profile = profile_name(decoder.profile());
+    info.channels = decoder.channels();
+    info.channel_layout = layout_name(decoder.channel_layout(), decoder.channels());
+    info.sample_rate = decoder.rate();
+
+    info
+}
+
+fn probe_subtitle(stream: &Stream) -> SubtitleStream {
+    let id = stream.codec().id();
+    let disposition = stream.disposition();
+    SubtitleStream {
+        index: stream.index(),
+        codec: id.name().to_string(),
+        bitmap: is_bitmap_subtitle(id),
+        language: stream.metadata().get("language").map(String::from),
+        title: stream.metadata().get("title").map(String::from),
+        default: disposition.contains(Disposition::DEFAULT),
+        forced: disposition.contains(Disposition::FORCED),
+    }
+}
+
+/// Check if a subtitle codec stores its subtitles as images rather than text.
+pub fn is_bitmap_subtitle(id: codec::Id) -> bool {
+    match id {
+        codec::Id::HDMV_PGS_SUBTITLE
+        | codec::Id::DVD_SUBTITLE
+        | codec::Id::DVB_SUBTITLE
+        | codec::Id::XSUB => true,
+        _ => false,
+    }
+}
+
+/// Build a readable name for a codec profile, e.g. `High` or `Main10`.
+fn profile_name(profile: Profile) -> Option<String> {
+    let name = match profile {
+        Profile::Unknown | Profile::Reserved => return None,
+        Profile::AAC(profile) => format!("{:?}", profile),
+        Profile::MPEG2(profile) => format!("{:?}", profile),
+        Profile::DTS(profile) => format!("{:?}", profile),
+        Profile::H264(profile) => format!("{:?}", profile),
+        Profile::VC1(profile) => format!("{:?}", profile),
+        Profile::MPEG4(profile) => format!("{:?}", profile),
+        Profile::JPEG2000(profile) => format!("{:?}", profile),
+        Profile::HEVC(profile) => format!("{:?}", profile),
+        Profile::VP9(profile) => format!("{:?}", profile),
+    };
+    Some(name)
+}
+
+/// Build a readable name for a channel layout, e.g. `stereo` or `5.1`.
+fn layout_name(layout: ChannelLayout, channels: u16) -> String {
+    let name = match layout {
+        ChannelLayout::MONO => "mono",
+        ChannelLayout::STEREO => "stereo",
+        ChannelLayout::_2POINT1 => "2.1",
+        ChannelLayout::QUAD => "quad",
+        ChannelLayout::_5POINT0 | ChannelLayout::_5POINT0_BACK => "5.0",
+        ChannelLayout::_5POINT1 | ChannelLayout::_5POINT1_BACK => "5.1",
+        ChannelLayout::_6POINT1 => "6.1",
+        ChannelLayout::_7POINT1 | ChannelLayout::_7POINT1_WIDE => "7.1",
+        _ => return format!("{} channels", channels),
+    };
+    name.to_string()
+}
+
+/// Convert a timestamp in `time_base` units into seconds.
+/// ### Returns
+/// `None` if the timestamp is unset
+fn to_seconds(timestamp: i64, time_base: Rational) -> Option<f64> {
+    if timestamp < 0 || time_base.denominator() == 0 {
+        return None;
+    }
+    Some(timestamp as f64 * f64::from(time_base))
+}
+
+/// Convert a rational to a float, if it is valid.
+fn to_f64(rational: Rational) -> Option<f64> {
+    if rational.numerator() == 0 || rational.denominator() == 0 {
+        return None;
+    }
+    Some(f64::from(rational))
+}
+
+fn to_map(dictionary: DictionaryRef) -> BTreeMap<String, String> {
+    dictionary
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
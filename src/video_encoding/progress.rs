@@ -0,0 +1,173 @@
+use super::Error;
+use serde::Serialize;
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Least time between two reports sent down the channel
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How far along a conversion is. Times are in seconds.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    /// Media time of the input converted so far
+    pub position: f64,
+    pub duration: Option<f64>,
+    /// Fraction of the input converted, from 0 to 1
+    pub fraction: Option<f64>,
+    /// Video frames converted so far
+    pub frames: u64,
+    /// Video frames converted per second of wall time
+    pub fps: f64,
+    /// Media time converted per second of wall time, 2.0 is twice real time
+    pub speed: f64,
+    /// Estimated time until the conversion is done
+    pub eta: Option<f64>,
+    /// Bytes of output produced so far
+    pub output_size: u64,
+    pub elapsed: f64,
+}
+
+/// Shared between a running conversion and whoever is watching it, to follow
+/// how far it has got and to stop it early. Reports can also be pushed down
+/// a channel as the conversion goes, see `Progress::with_reports`.
+#[derive(Debug)]
+pub struct Progress {
+    duration: Option<f64>,
+    started: Instant,
+    /// Position of the last packet read, in milliseconds
+    position: AtomicU64,
+    frames: AtomicU64,
+    /// Shared with the writer the output is counted by
+    output_size: Arc<AtomicU64>,
+    cancelled: AtomicBool,
+    /// Channel for reports, and when the last one was sent
+    reports: Mutex<Option<(Sender<Report>, Instant)>>,
+}
+
+impl Progress {
+    /// Track a conversion of an input `duration` seconds long, if known.
+    pub fn new(duration: Option<f64>) -> Self {
+        Self {
+            duration: duration.filter(|duration| *duration > 0.0),
+            started: Instant::now(),
+            position: AtomicU64::new(0),
+            frames: AtomicU64::new(0),
+            output_size: Arc::new(AtomicU64::new(0)),
+            cancelled: AtomicBool::new(false),
+            reports: Mutex::new(None),
+        }
+    }
+
+    /// Track a conversion, sending a report down the returned channel at most
+    /// every `REPORT_INTERVAL` while it runs. The channel closes once the
+    /// `Progress` is dropped.
+    pub fn with_reports(duration: Option<f64>) -> (Self, Receiver<Report>) {
+        let (tx, rx) = channel();
+        let progress = Self::new(duration);
+        *progress.reports.lock().unwrap() = Some((tx, Instant::now()));
+        (progress, rx)
+    }
+
+    /// Returns how far into the input the conversion is, in seconds.
+    pub fn position(&self) -> f64 {
+        self.position.load(Ordering::Relaxed) as f64 / 1000.0
+    }
+
+    /// Take a snapshot of how far along the conversion is.
+    pub fn report(&self) -> Report {
+        let position = self.position();
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let frames = self.frames.load(Ordering::Relaxed);
+        let (fps, speed) = if elapsed > 0.0 {
+            (frames as f64 / elapsed, position / elapsed)
+        } else {
+            (0.0, 0.0)
+        };
+        let eta = self.duration
+            .filter(|_| speed > 0.0)
+            .map(|duration| (duration - position).max(0.0) / speed);
+
+        Report {
+            position,
+            duration: self.duration,
+            fraction: self.duration.map(|duration| (position / duration).min(1.0)),
+            frames,
+            fps,
+            speed,
+            eta,
+            output_size: self.output_size.load(Ordering::Relaxed),
+            elapsed,
+        }
+    }
+
+    /// Ask the conversion to stop, it ends with `Error::Cancelled` at
+    /// the next packet.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Record that the conversion has read up to `seconds` into the input,
+    /// sending a report if one is due.
+    /// ### Returns
+    /// `Error::Cancelled` if the conversion has been cancelled
+    pub(super) fn update(&self, seconds: f64) -> Result<(), Error> {
+        if self.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        if seconds.is_finite() && seconds > 0.0 {
+            self.position.fetch_max((seconds * 1000.0) as u64, Ordering::Relaxed);
+        }
+
+        let mut reports = self.reports.lock().unwrap();
+        if let Some((tx, last)) = reports.as_mut() {
+            if last.elapsed() >= REPORT_INTERVAL {
+                *last = Instant::now();
+                // Nobody listening isn't a reason to stop converting
+                if tx.send(self.report()).is_err() {
+                    *reports = None;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Record that a video frame was converted.
+    pub(super) fn add_frame(&self) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Wrap the writer a conversion outputs to, so the output size is counted.
+    pub(super) fn count_output(&self, writer: Box<dyn Write + Send>) -> Box<dyn Write + Send> {
+        Box::new(CountingWriter { writer, count: self.output_size.clone() })
+    }
+}
+
+/// Passes everything written to it on to `writer`, counting the bytes.
+struct CountingWriter {
+    writer: Box<dyn Write + Send>,
+    count: Arc<AtomicU64>,
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.count.fetch_add(written as u64, Ordering::Relaxed);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,161 @@
+use super::Error;
+use ffmpeg::{ffi, format};
+use std::{
+    ffi::CString,
+    io::{self, Write},
+    ops::{Deref, DerefMut},
+    os::raw::{c_int, c_void},
+    ptr, slice,
+    sync::mpsc::{sync_channel, Receiver, SyncSender},
+    thread,
+};
+
+/// Size of the buffer ffmpeg fills before handing data to the writer.
+const BUFFER_SIZE: usize = 32 * 1024;
+/// Number of chunks that can be queued before the muxer blocks on the reader.
+const CHANNEL_CHUNKS: usize = 64;
+
+/// Flags for the mp4 muxer to write fragments as it goes rather than a
+/// single index at the end, so the output can be played while it is produced.
+pub const FRAGMENTED_MP4_FLAGS: &str = "frag_keyframe+empty_moov+default_base_moof";
+
+/// An output context that writes into any `Write` rather than a file.
+/// Derefs to the wrapped `format::context::Output`.
+pub struct Sink {
+    octx: format::context::Output,
+    avio: *mut ffi::AVIOContext,
+    writer: *mut Box<dyn Write + Send>,
+}
+
+// The raw pointers are owned by the Sink and only touched through it.
+unsafe impl Send for Sink {}
+
+impl Sink {
+    /// Create an output context for the muxer `format_name` (e.g. `mp4`),
+    /// which passes everything it muxes to `writer`.
+    pub fn new(format_name: &str, writer: Box<dyn Write + Send>) -> Result<Self, ffmpeg::Error> {
+        let format_name = CString::new(format_name).map_err(|_| ffmpeg::Error::MuxerNotFound)?;
+
+        unsafe {
+            let mut ctx = ptr::null_mut();
+            match ffi::avformat_alloc_output_context2(
+                &mut ctx, ptr::null_mut(), format_name.as_ptr(), ptr::null()) {
+                0 => {}
+                err => return Err(ffmpeg::Error::from(err)),
+            }
+            // Wrap it immediately so it is freed on any early return
+            let octx = format::context::Output::wrap(ctx);
+
+            let buffer = ffi::av_malloc(BUFFER_SIZE) as *mut u8;
+            if buffer.is_null() {
+                return Err(ffmpeg::Error::Bug);
+            }
+            let writer = Box::into_raw(Box::new(writer));
+            let avio = ffi::avio_alloc_context(
+                buffer,
+                BUFFER_SIZE as c_int,
+                1,
+                writer as *mut c_void,
+                None,
+                Some(write_packet),
+                None,
+            );
+            if avio.is_null() {
+                ffi::av_free(buffer as *mut c_void);
+                drop(Box::from_raw(writer));
+                return Err(ffmpeg::Error::Bug);
+            }
+
+            (*ctx).pb = avio;
+            (*ctx).flags |= ffi::AVFMT_FLAG_CUSTOM_IO as c_int;
+
+            Ok(Self { octx, avio, writer })
+        }
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::avio_flush(self.avio);
+            // Output's destructor would avio_close the custom context, detach it first
+            (*self.octx.as_mut_ptr()).pb = ptr::null_mut();
+            // ffmpeg may have swapped the buffer, free whichever it holds now
+            ffi::av_freep(&mut (*self.avio).buffer as *mut *mut u8 as *mut c_void);
+            ffi::avio_context_free(&mut self.avio);
+            drop(Box::from_raw(self.writer));
+        }
+    }
+}
+
+impl Deref for Sink {
+    type Target = format::context::Output;
+
+    fn deref(&self) -> &Self::Target {
+        &self.octx
+    }
+}
+
+impl DerefMut for Sink {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.octx
+    }
+}
+
+/// AVIO write callback, forwards the buffer to the Sink's writer.
+unsafe extern "C" fn write_packet(opaque: *mut c_void, buf: *mut u8, buf_size: c_int) -> c_int {
+    let writer = &mut *(opaque as *mut Box<dyn Write + Send>);
+    let data = slice::from_raw_parts(buf, buf_size as usize);
+    match writer.write_all(data) {
+        Ok(()) => buf_size,
+        // The reader is gone, end the muxing
+        Err(_) => ffi::AVERROR_EOF,
+    }
+}
+
+/// A writer that sends everything written to it down a channel as chunks.
+/// Writing fails once the receiver is dropped.
+pub struct ChannelWriter {
+    tx: SyncSender<Vec<u8>>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx.send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Stream receiver dropped"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Create a bounded chunk channel, the writer blocks while the receiver
+/// falls behind.
+pub fn channel() -> (ChannelWriter, Receiver<Vec<u8>>) {
+    let (tx, rx) = sync_channel(CHANNEL_CHUNKS);
+    (ChannelWriter { tx }, rx)
+}
+
+/// Run a muxing job on its own thread, with a channel writer as its output.
+/// ### Arguments
+/// - `name` - What the job is working on, for logging
+/// - `job` - Muxes into the writer it is given, e.g. `video_encoding::remux`
+/// ### Returns
+/// A channel the output is streamed through as it is produced. Dropping
+/// it stops the job.
+pub fn spawn<F>(name: String, job: F) -> Receiver<Vec<u8>>
+where F: FnOnce(Box<dyn Write + Send>) -> Result<(), Error> + Send + 'static {
+    let (writer, rx) = channel();
+    thread::spawn(move || {
+        log::info!("[Sink] Starting {}", &name);
+        match job(Box::new(writer)) {
+            Ok(()) => log::info!("[Sink] Finished {}", &name),
+            // A dropped reader is reported as end of file by the write callback
+            Err(Error::FfmpegError(ffmpeg::Error::Eof)) => log::info!("[Sink] Stopped {}, reader closed", &name),
+            Err(err) => log::error!("[Sink] Failed {}: {}", &name, err),
+        }
+    });
+    rx
+}
@@ -0,0 +1,700 @@
+use super::{
+    burn_in::BurnIn,
+    compat::{self, Chromecast, TranscodeTarget, Verdict},
+    probe::MediaInfo,
+    progress::Progress,
+    sink::{self, Sink},
+    Error,
+};
+use ffmpeg::{
+    codec, color::TransferCharacteristic, encoder, ffi, filter, format, ChannelLayout, Dictionary, Frame,
+    Packet, Rational, Rescale, Stream,
+};
+use serde::{Serialize, Deserialize};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    path::Path,
+};
+
+/// Bit rate of transcoded stereo AAC audio
+const AUDIO_BIT_RATE: usize = 192_000;
+/// Transcoded audio is never resampled above this
+const MAX_SAMPLE_RATE: u32 = 48000;
+/// Seconds between keyframes in transcoded video, each starts a new fragment
+const KEYFRAME_INTERVAL: f64 = 2.0;
+/// Filters mapping HDR video onto SDR BT.709, as transcoded video is 8-bit H.264.
+/// Without them PQ and HLG video comes out washed out.
+const TONEMAP_FILTERS: &str =
+    "zscale=t=linear:npl=100,format=gbrpf32le,zscale=p=bt709,tonemap=tonemap=hable:desat=0,zscale=t=bt709:m=bt709:r=tv";
+
+/// How a stream makes it into the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Mode {
+    /// Packets are copied as is
+    Copy,
+    /// Packets are decoded and encoded into a compatible codec
+    Transcode,
+}
+
+/// A stream of the input file to include in the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedStream {
+    /// Stream index within the input container
+    pub index: usize,
+    pub mode: Mode,
+}
+
+/// Which streams of a file to convert for a chromecast, and how.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Plan {
+    pub chromecast: Chromecast,
+    pub video: Option<PlannedStream>,
+    pub audio: Option<PlannedStream>,
+    /// Container index of a bitmap subtitle stream to draw onto the video
+    #[serde(default)]
+    pub burn_in: Option<usize>,
+}
+
+/// Query of a transcoded media request, e.g. `?chromecast=Ultra&audio=2&burn_in=4`.
+/// The API writes it into the URLs it casts, the media server reads it back.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TranscodeQuery {
+    pub chromecast: Chromecast,
+    /// Container index of the audio stream to play, the default if unset
+    pub audio: Option<usize>,
+    /// Container index of a bitmap subtitle stream to draw onto the video
+    pub burn_in: Option<usize>,
+}
+
+impl TranscodeQuery {
+    /// Plan the conversion the query asks for.
+    /// ### Returns
+    /// `Error::StreamNotFound` if the chosen audio stream isn't in the file,
+    /// or `Error::UnsupportedCodec` if the subtitles to burn in aren't bitmaps
+    pub fn plan(&self, info: &MediaInfo) -> Result<Plan, Error> {
+        if let Some(audio) = self.audio {
+            info.audio_stream(audio).ok_or(Error::StreamNotFound(audio))?;
+        }
+        if let Some(burn_in) = self.burn_in {
+            let stream = info.subtitle_stream(burn_in)
+                .ok_or(Error::StreamNotFound(burn_in))?;
+            if !stream.bitmap {
+                return Err(Error::UnsupportedCodec(stream.codec.clone()));
+            }
+        }
+        let verdict = compat::check(info, self.chromecast);
+        Ok(Plan::with_audio(info, &verdict, self.audio).with_burn_in(self.burn_in))
+    }
+
+    /// Write the query out, e.g. `chromecast=Ultra&audio=2`, without the `?`.
+    pub fn to_query_string(&self) -> String {
+        let mut query = format!("chromecast={:?}", self.chromecast);
+        if let Some(audio) = self.audio {
+            query += &format!("&audio={}", audio);
+        }
+        if let Some(burn_in) = self.burn_in {
+            query += &format!("&burn_in={}", burn_in);
+        }
+        query
+    }
+}
+
+impl Plan {
+    /// Plan a conversion of the default video and audio streams.
+    pub fn new(info: &MediaInfo, verdict: &Verdict) -> Self {
+        Self::with_audio(info, verdict, None)
+    }
+
+    /// Plan a conversion of the default video stream and a chosen audio stream,
+    /// leaving every other audio stream out.
+    /// ### Arguments
+    /// - `info` - Probed info of the file
+    /// - `verdict` - Compatibility of the file with the target chromecast
+    /// - `audio` - Container index of the audio stream, `None` for the default
+    pub fn with_audio(info: &MediaInfo, verdict: &Verdict, audio: Option<usize>) -> Self {
+        Self::with_streams(
+            verdict,
+            info.default_video().map(|stream| stream.index),
+            audio.or_else(|| info.default_audio().map(|stream| stream.index)),
+        )
+    }
+
+    /// Plan a conversion of specific video and audio streams. Only streams
+    /// the verdict found incompatible are transcoded, the rest are copied.
+    /// ### Arguments
+    /// - `verdict` - Compatibility of the file with the target chromecast
+    /// - `video` - Container index of the video stream, `None` to leave it out
+    /// - `audio` - Container index of the audio stream, `None` to leave it out
+    pub fn with_streams(verdict: &Verdict, video: Option<usize>, audio: Option<usize>) -> Self {
+        let mode = |compatible: Option<bool>| match compatible {
+            Some(true) => Mode::Copy,
+            _ => Mode::Transcode,
+        };
+
+        Self {
+            chromecast: verdict.chromecast,
+            video: video.map(|index| PlannedStream {
+                index,
+                mode: mode(verdict.video(index).map(|verdict| verdict.compatible)),
+            }),
+            audio: audio.map(|index| PlannedStream {
+                index,
+                mode: mode(verdict.audio(index).map(|verdict| verdict.compatible)),
+            }),
+            burn_in: None,
+        }
+    }
+
+    /// Draw a bitmap subtitle stream onto the video, which means transcoding it.
+    /// ### Arguments
+    /// - `burn_in` - Container index of the subtitle stream, `None` to leave subtitles out
+    pub fn with_burn_in(mut self, burn_in: Option<usize>) -> Self {
+        self.burn_in = burn_in;
+        if let (Some(video), Some(_)) = (&mut self.video, burn_in) {
+            video.mode = Mode::Transcode;
+        }
+        self
+    }
+
+    /// Describe the conversion, e.g. `Ultra-v0copy-a1transcode`, or
+    /// `Ultra-v0transcode-a1copy-s3burn` with subtitles burnt in. Plans with
+    /// the same profile produce the same output from the same file.
+    pub fn profile(&self) -> String {
+        let mut profile = format!("{:?}", self.chromecast);
+        for (kind, planned) in [("v", &self.video), ("a", &self.audio)] {
+            if let Some(planned) = planned {
+                profile += &format!("-{}{}{:?}", kind, planned.index, planned.mode).to_lowercase();
+            }
+        }
+        if let (Some(_), Some(burn_in)) = (&self.video, self.burn_in) {
+            profile += &format!("-s{}burn", burn_in);
+        }
+        profile
+    }
+
+    /// Iterate over the planned streams, video first.
+    pub fn streams(&self) -> impl Iterator<Item = &PlannedStream> {
+        self.video.iter().chain(self.audio.iter())
+    }
+}
+
+/// Where the packets of an input stream go.
+enum Route {
+    Copy { ost_index: usize },
+    Transcode(Box<Transcoder>),
+}
+
+/// Convert a file into fragmented MP4 following `plan`, writing the output
+/// to `writer` as it is produced. Blocks until the whole file is converted
+/// or the writer fails.
+/// ### Arguments
+/// - `input` - Path of the media file
+/// - `plan` - Streams to include and how to convert them
+/// - `writer` - Destination of the MP4 data
+/// - `progress` - Updated as the file is read, and checked for cancellation
+pub fn transcode(input: &Path, plan: &Plan, writer: Box<dyn Write + Send>, progress: Option<&Progress>)
+    -> Result<(), Error> {
+    let mut options = Dictionary::new();
+    options.set("movflags", sink::FRAGMENTED_MP4_FLAGS);
+    run(input, plan, Output { format: "mp4", options, window: None }, writer, progress)
+}
+
+/// The container to produce and which part of the input to put in it.
+pub(super) struct Output<'a> {
+    /// Name of the muxer, e.g. `mp4`
+    pub format: &'a str,
+    pub options: Dictionary<'a>,
+    /// Only the time range from start to end (in seconds) is converted when set
+    pub window: Option<(f64, f64)>,
+}
+
+/// Convert a file following `plan` into `output`'s container, writing it to `writer`.
+/// Timestamps are kept as in the input, so windows of the same file line up.
+pub(super) fn run(input: &Path, plan: &Plan, output: Output, writer: Box<dyn Write + Send>,
+    progress: Option<&Progress>) -> Result<(), Error> {
+    ffmpeg::init()?;
+
+    let mut ictx = format::input(&input)?;
+    let writer = match progress {
+        Some(progress) => progress.count_output(writer),
+        None => writer,
+    };
+    let mut octx = Sink::new(output.format, writer)?;
+    let target = plan.chromecast.transcode_target();
+
+    // Subtitles are only drawn onto transcoded video
+    let mut burn_in = match (plan.burn_in, plan.video) {
+        (Some(index), Some(video)) if video.mode == Mode::Transcode => {
+            let ist = ictx.stream(index).ok_or(Error::StreamNotFound(index))?;
+            let video = ictx.stream(video.index).ok_or(Error::StreamNotFound(video.index))?;
+            Some(BurnIn::new(&ist, &video)?)
+        }
+        _ => None,
+    };
+
+    // Add an output stream for each planned stream
+    let mut routes = HashMap::new();
+    for (ost_index, planned) in plan.streams().enumerate() {
+        let ist = ictx.stream(planned.index).ok_or(Error::StreamNotFound(planned.index))?;
+        let route = match planned.mode {
+            Mode::Copy => {
+                let mut ost = octx.add_stream(encoder::find(codec::Id::None))?;
+                ost.set_parameters(ist.parameters());
+                // We need to set codec_tag to 0 lest we run into incompatible codec tag
+                // issues when muxing into a different container format.
+                unsafe {
+                    (*ost.parameters().as_mut_ptr()).codec_tag = 0;
+                }
+                Route::Copy { ost_index }
+            }
+            Mode::Transcode => {
+                let transcoder = match ist.codec().medium() {
+                    ffmpeg::media::Type::Video => Transcoder::video(&ist, &mut octx, ost_index, &target),
+                    ffmpeg::media::Type::Audio => Transcoder::audio(&ist, &mut octx, ost_index),
+                    _ => return Err(Error::StreamNotFound(planned.index)),
+                };
+                let mut transcoder = transcoder.map_err(|err| match err {
+                    ffmpeg::Error::DecoderNotFound => Error::UnsupportedCodec(ist.codec().id().name().to_string()),
+                    ffmpeg::Error::EncoderNotFound => Error::UnsupportedCodec(match ist.codec().medium() {
+                        ffmpeg::media::Type::Video => "h264".to_string(),
+                        _ => "aac".to_string(),
+                    }),
+                    err => Error::from(err),
+                })?;
+                transcoder.window = output.window.map(|(start, end)| {
+                    (to_timestamp(start, ist.time_base()), to_timestamp(end, ist.time_base()))
+                });
+                if Some(planned) == plan.video.as_ref() {
+                    transcoder.burn_in = burn_in.take();
+                }
+                Route::Transcode(Box::new(transcoder))
+            }
+        };
+        routes.insert(planned.index, route);
+    }
+
+    octx.set_metadata(ictx.metadata().to_owned());
+    octx.write_header_with(output.options)?;
+
+    // The muxer picks its own time bases when writing the header
+    let ost_time_bases: Vec<Rational> = octx.streams()
+        .map(|stream| stream.time_base())
+        .collect();
+
+    // Jump to the keyframe at or before the window
+    if let Some((start, _)) = output.window {
+        let position = to_timestamp(start, ffmpeg::rescale::TIME_BASE);
+        ictx.seek(position, ..position)?;
+    }
+    // Input streams that have gone past the end of the window
+    let mut finished = HashSet::new();
+    let route_count = routes.len();
+
+    for (stream, mut packet) in ictx.packets() {
+        // Subtitle packets are decoded into the video they are drawn onto
+        if Some(stream.index()) == plan.burn_in {
+            let video = plan.video.and_then(|video| routes.get_mut(&video.index));
+            if let Some(Route::Transcode(transcoder)) = video {
+                if let Some(burn_in) = &mut transcoder.burn_in {
+                    burn_in.send_packet(&packet);
+                }
+            }
+            continue;
+        }
+        let route = match routes.get_mut(&stream.index()) {
+            Some(route) => route,
+            None => continue,
+        };
+        if let Some(progress) = progress {
+            let time = packet.dts().or(packet.pts()).unwrap_or(0);
+            progress.update(time as f64 * f64::from(stream.time_base()))?;
+            if plan.video.map(|video| video.index) == Some(stream.index()) {
+                progress.add_frame();
+            }
+        }
+
+        if let Some((start, end)) = output.window {
+            let time = packet.dts().or(packet.pts())
+                .map(|ts| ts as f64 * f64::from(stream.time_base()));
+            if let Some(time) = time {
+                if time >= end {
+                    finished.insert(stream.index());
+                    if finished.len() == route_count {
+                        break;
+                    }
+                }
+                // Transcoders decode from the keyframe and drop frames themselves
+                if let Route::Copy { .. } = route {
+                    let pts = packet.pts()
+                        .map_or(time, |ts| ts as f64 * f64::from(stream.time_base()));
+                    if pts < start || pts >= end {
+                        continue;
+                    }
+                }
+            }
+        }
+
+        match route {
+            Route::Copy { ost_index } => {
+                packet.rescale_ts(stream.time_base(), ost_time_bases[*ost_index]);
+                packet.set_position(-1);
+                packet.set_stream(*ost_index);
+                packet.write_interleaved(&mut octx)?;
+            }
+            Route::Transcode(transcoder) => {
+                let ost_time_base = ost_time_bases[transcoder.ost_index];
+                transcoder.send_packet(&packet, &mut octx, ost_time_base)?;
+            }
+        }
+    }
+
+    // Flush whatever the decoders, filters and encoders are still holding
+    for route in routes.values_mut() {
+        if let Route::Transcode(transcoder) = route {
+            let ost_time_base = ost_time_bases[transcoder.ost_index];
+            transcoder.finish(&mut octx, ost_time_base)?;
+        }
+    }
+
+    octx.write_trailer()?;
+    Ok(())
+}
+
+/// Decodes one input stream, filters it into the encoder's format and
+/// encodes it into an output stream.
+struct Transcoder {
+    ost_index: usize,
+    decoder: codec::decoder::Opened,
+    encoder: codec::encoder::Encoder,
+    filter: filter::Graph,
+    filter_time_base: Rational,
+    encoder_time_base: Rational,
+    /// Decoded frames outside of this (start, end) range, in the input stream's
+    /// time base, are dropped
+    window: Option<(i64, i64)>,
+    /// Subtitles drawn onto filtered video frames
+    burn_in: Option<BurnIn>,
+}
+
+impl Transcoder {
+    /// Set up transcoding of a video stream to H.264, scaled down to fit the
+    /// target. HDR video is tonemapped to SDR if FFmpeg was built with zimg.
+    fn video(ist: &Stream, octx: &mut format::context::Output, ost_index: usize,
+        target: &TranscodeTarget) -> Result<Self, ffmpeg::Error> {
+        let decoder = ist.codec().decoder().video()?;
+
+        let (width, height) = fit_within(decoder.width(), decoder.height(),
+            target.max_width, target.max_height);
+        let mut frame_rate = ist.avg_frame_rate();
+        let mut spec = String::new();
+        let transfer = decoder.color_transfer_characteristic();
+        if matches!(transfer, TransferCharacteristic::SMPTE2084 | TransferCharacteristic::ARIB_STD_B67) {
+            if filter::find("zscale").is_some() && filter::find("tonemap").is_some() {
+                spec.push_str(TONEMAP_FILTERS);
+                spec.push(',');
+            } else {
+                log::warn!("[Transcode] FFmpeg lacks the zscale filter, {:?} video won't be tonemapped to SDR",
+                    transfer);
+            }
+        }
+        spec.push_str(&format!("scale={}:{},format=yuv420p", width, height));
+        if frame_rate.denominator() == 0 || f64::from(frame_rate) > target.max_fps + 0.1 {
+            frame_rate = Rational(target.max_fps as i32, 1);
+            spec.push_str(&format!(",fps={}", target.max_fps));
+        }
+        let aspect_ratio = match decoder.aspect_ratio() {
+            ratio if ratio.numerator() == 0 => Rational(1, 1),
+            ratio => ratio,
+        };
+
+        // Build the filter graph, tonemapping, scaling and converting to 8-bit 4:2:0
+        let pixel_format = decoder.format().descriptor()
+            .ok_or(ffmpeg::Error::InvalidData)?
+            .name();
+        let args = format!("video_size={}x{}:pix_fmt={}:time_base={}:pixel_aspect={}",
+            decoder.width(), decoder.height(), pixel_format, ist.time_base(), aspect_ratio);
+        let mut filter = filter::Graph::new();
+        filter.add(&filter::find("buffer").ok_or(ffmpeg::Error::FilterNotFound)?, "in", &args)?;
+        filter.add(&filter::find("buffersink").ok_or(ffmpeg::Error::FilterNotFound)?, "out", "")?;
+        filter_output(&mut filter)?.set_pixel_format(format::Pixel::YUV420P);
+        filter.output("in", 0)?.input("out", 0)?.parse(&spec)?;
+        filter.validate()?;
+        let filter_time_base = sink_time_base(&mut filter)?;
+
+        let codec = encoder::find(codec::Id::H264).ok_or(ffmpeg::Error::EncoderNotFound)?;
+        let mut ost = octx.add_stream(codec)?;
+        let mut encoder = ost.codec().encoder().video()?;
+        encoder.set_width(width);
+        encoder.set_height(height);
+        encoder.set_format(format::Pixel::YUV420P);
+        encoder.set_aspect_ratio(aspect_ratio);
+        encoder.set_time_base(filter_time_base);
+        encoder.set_frame_rate(Some(frame_rate));
+        encoder.set_gop((f64::from(frame_rate) * KEYFRAME_INTERVAL).round().max(1.0) as u32);
+        // MP4 keeps codec headers in the stream parameters
+        encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+
+        let mut options = Dictionary::new();
+        options.set("preset", "veryfast");
+        options.set("crf", "21");
+        options.set("profile", "high");
+        options.set("level", target.h264_level);
+        let encoder = encoder.open_with(options)?;
+        ost.set_parameters(&encoder);
+
+        Ok(Self {
+            ost_index,
+            decoder: decoder.0,
+            encoder: (encoder.0).0,
+            filter,
+            filter_time_base,
+            encoder_time_base: filter_time_base,
+            window: None,
+            burn_in: None,
+        })
+    }
+
+    /// Set up transcoding of an audio stream to stereo AAC.
+    fn audio(ist: &Stream, octx: &mut format::context::Output, ost_index: usize)
+        -> Result<Self, ffmpeg::Error> {
+        let decoder = ist.codec().decoder().audio()?;
+        let rate = decoder.rate().min(MAX_SAMPLE_RATE);
+
+        let codec = encoder::find(codec::Id::AAC).ok_or(ffmpeg::Error::EncoderNotFound)?;
+        let mut ost = octx.add_stream(codec)?;
+        let mut encoder = ost.codec().encoder().audio()?;
+        encoder.set_rate(rate as i32);
+        encoder.set_channel_layout(ChannelLayout::STEREO);
+        encoder.set_channels(ChannelLayout::STEREO.channels());
+        encoder.set_format(format::Sample::F32(format::sample::Type::Planar));
+        encoder.set_bit_rate(AUDIO_BIT_RATE);
+        encoder.set_time_base((1, rate as i32));
+        encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+        let encoder = encoder.open_as(codec)?;
+        ost.set_parameters(&encoder);
+
+        // Some containers leave the layout unset, guess it from the channel count
+        let layout = match decoder.channel_layout() {
+            layout if layout.is_empty() => ChannelLayout::default(decoder.channels() as i32),
+            layout => layout,
+        };
+
+        // Build the filter graph, resampling and downmixing into the encoder's format
+        let args = format!("time_base={}:sample_rate={}:sample_fmt={}:channel_layout=0x{:x}",
+            ist.time_base(), decoder.rate(), decoder.format().name(), layout.bits());
+        let mut filter = filter::Graph::new();
+        filter.add(&filter::find("abuffer").ok_or(ffmpeg::Error::FilterNotFound)?, "in", &args)?;
+        filter.add(&filter::find("abuffersink").ok_or(ffmpeg::Error::FilterNotFound)?, "out", "")?;
+        {
+            let mut out = filter_output(&mut filter)?;
+            out.set_sample_format(encoder.format());
+            out.set_channel_layout(encoder.channel_layout());
+            out.set_sample_rate(encoder.rate());
+        }
+        filter.output("in", 0)?.input("out", 0)?.parse("anull")?;
+        filter.validate()?;
+        // AAC takes a fixed number of samples per frame
+        filter_output(&mut filter)?.sink().set_frame_size(encoder.frame_size());
+        let filter_time_base = sink_time_base(&mut filter)?;
+
+        Ok(Self {
+            ost_index,
+            decoder: decoder.0,
+            encoder: (encoder.0).0,
+            filter,
+            filter_time_base,
+            encoder_time_base: Rational(1, rate as i32),
+            window: None,
+            burn_in: None,
+        })
+    }
+
+    /// Decode a packet and push everything it produces through to the output.
+    /// Packets that fail to decode are skipped rather than ending the transcode.
+    fn send_packet(&mut self, packet: &Packet, octx: &mut format::context::Output,
+        ost_time_base: Rational) -> Result<(), ffmpeg::Error> {
+        if let Err(err) = self.decoder.send_packet(packet) {
+            log::warn!("[Transcode] Skipping undecodable packet: {:?}", err);
+            return Ok(());
+        }
+        self.receive_frames(octx, ost_time_base)
+    }
+
+    /// Flush the decoder, filter and encoder once the input is exhausted.
+    fn finish(&mut self, octx: &mut format::context::Output, ost_time_base: Rational)
+        -> Result<(), ffmpeg::Error> {
+        self.decoder.send_eof()?;
+        self.receive_frames(octx, ost_time_base)?;
+        filter_input(&mut self.filter)?.source().flush()?;
+        self.receive_filtered(octx, ost_time_base)?;
+        self.encoder.send_eof()?;
+        self.receive_packets(octx, ost_time_base)
+    }
+
+    fn receive_frames(&mut self, octx: &mut format::context::Output, ost_time_base: Rational)
+        -> Result<(), ffmpeg::Error> {
+        let mut decoded = unsafe { Frame::empty() };
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            let timestamp = decoded.timestamp();
+            if let (Some((start, end)), Some(timestamp)) = (self.window, timestamp) {
+                if timestamp < start || timestamp >= end {
+                    continue;
+                }
+            }
+            decoded.set_pts(timestamp);
+            filter_input(&mut self.filter)?.source().add(&decoded)?;
+            self.receive_filtered(octx, ost_time_base)?;
+        }
+        Ok(())
+    }
+
+    fn receive_filtered(&mut self, octx: &mut format::context::Output, ost_time_base: Rational)
+        -> Result<(), ffmpeg::Error> {
+        let mut filtered = unsafe { Frame::empty() };
+        while filter_output(&mut self.filter)?.sink().frame(&mut filtered).is_ok() {
+            let pts = filtered.pts()
+                .map(|pts| pts.rescale(self.filter_time_base, self.encoder_time_base));
+            if let (Some(burn_in), Some(timestamp)) = (&mut self.burn_in, filtered.pts()) {
+                burn_in.draw(&mut filtered, timestamp as f64 * f64::from(self.filter_time_base));
+            }
+            filtered.set_pts(pts);
+            // Let the encoder place keyframes itself rather than copying the source's
+            unsafe {
+                (*filtered.as_mut_ptr()).pict_type = ffi::AVPictureType::AV_PICTURE_TYPE_NONE;
+            }
+            self.encoder.send_frame(&filtered)?;
+            self.receive_packets(octx, ost_time_base)?;
+        }
+        Ok(())
+    }
+
+    fn receive_packets(&mut self, octx: &mut format::context::Output, ost_time_base: Rational)
+        -> Result<(), ffmpeg::Error> {
+        let mut encoded = Packet::empty();
+        while self.encoder.receive_packet(&mut encoded).is_ok() {
+            encoded.set_stream(self.ost_index);
+            encoded.rescale_ts(self.encoder_time_base, ost_time_base);
+            encoded.write_interleaved(octx)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the source frames are added to in a filter graph built by `Transcoder`.
+fn filter_input(filter: &mut filter::Graph) -> Result<filter::Context<'_>, ffmpeg::Error> {
+    filter.get("in").ok_or(ffmpeg::Error::FilterNotFound)
+}
+
+/// Returns the sink frames are taken from in a filter graph built by `Transcoder`.
+fn filter_output(filter: &mut filter::Graph) -> Result<filter::Context<'_>, ffmpeg::Error> {
+    filter.get("out").ok_or(ffmpeg::Error::FilterNotFound)
+}
+
+/// Returns the time base of the frames coming out of a configured filter graph.
+fn sink_time_base(filter: &mut filter::Graph) -> Result<Rational, ffmpeg::Error> {
+    let out = filter_output(filter)?;
+    unsafe {
+        Ok(Rational::from(ffi::av_buffersink_get_time_base(out.as_ptr())))
+    }
+}
+
+/// Convert seconds into a timestamp in `time_base` units.
+fn to_timestamp(seconds: f64, time_base: Rational) -> i64 {
+    (seconds / f64::from(time_base)).round() as i64
+}
+
+/// Scale dimensions down to fit within a bounding box, keeping the aspect
+/// ratio. Portrait video is fit against the box turned on its side.
+/// The result is always even, as 4:2:0 requires.
+fn fit_within(width: u32, height: u32, max_width: u32, max_height: u32) -> (u32, u32) {
+    let (max_width, max_height) = if height > width {
+        (max_height, max_width)
+    } else {
+        (max_width, max_height)
+    };
+    let scale = (max_width as f64 / width as f64)
+        .min(max_height as f64 / height as f64)
+        .min(1.0);
+    let even = |value: f64| ((value / 2.0).round() as u32 * 2).max(2);
+
+    (even(width as f64 * scale), even(height as f64 * scale))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fit_within_scales_down() {
+        assert_eq!(fit_within(3840, 2160, 1920, 1080), (1920, 1080));
+        // Ultrawide video is limited by its width
+        assert_eq!(fit_within(3840, 1608, 1920, 1080), (1920, 804));
+        // 4:3 video is limited by its height
+        assert_eq!(fit_within(1440, 1080, 1280, 720), (960, 720));
+    }
+
+    #[test]
+    fn fit_within_never_scales_up() {
+        assert_eq!(fit_within(1280, 720, 1920, 1080), (1280, 720));
+    }
+
+    #[test]
+    fn fit_within_turns_box_for_portrait() {
+        assert_eq!(fit_within(1080, 1920, 1920, 1080), (1080, 1920));
+        assert_eq!(fit_within(2160, 3840, 1920, 1080), (1080, 1920));
+    }
+
+    #[test]
+    fn fit_within_rounds_to_even() {
+        assert_eq!(fit_within(719, 481, 1920, 1080), (720, 482));
+        assert_eq!(fit_within(3840, 1634, 1920, 1080), (1920, 818));
+        assert_eq!(fit_within(1, 1, 1920, 1080), (2, 2));
+    }
+
+    fn plan(video: Option<Mode>, audio: Option<Mode>) -> Plan {
+        Plan {
+            chromecast: Chromecast::Ultra,
+            video: video.map(|mode| PlannedStream { index: 0, mode }),
+            audio: audio.map(|mode| PlannedStream { index: 1, mode }),
+            burn_in: None,
+        }
+    }
+
+    #[test]
+    fn profile_names_streams() {
+        assert_eq!(plan(Some(Mode::Copy), Some(Mode::Transcode)).profile(), "Ultra-v0copy-a1transcode");
+        assert_eq!(plan(None, Some(Mode::Copy)).profile(), "Ultra-a1copy");
+    }
+
+    #[test]
+    fn profile_names_burnt_in_subtitles() {
+        let burnt = plan(Some(Mode::Copy), Some(Mode::Copy)).with_burn_in(Some(3));
+        assert_eq!(burnt.profile(), "Ultra-v0transcode-a1copy-s3burn");
+        // Without video there is nothing to burn them into
+        assert_eq!(plan(None, Some(Mode::Copy)).with_burn_in(Some(3)).profile(), "Ultra-a1copy");
+    }
+
+    /// Read a query string back the way the media server does.
+    fn parse_query(query: &str) -> TranscodeQuery {
+        let request = warp::test::request().path(&format!("/media/0/transcode?{}", query));
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(request.filter(&warp::query::<TranscodeQuery>())).unwrap()
+    }
+
+    #[test]
+    fn query_string_round_trips() {
+        let queries = [
+            TranscodeQuery { chromecast: Chromecast::FirstAndSecond, audio: None, burn_in: None },
+            TranscodeQuery { chromecast: Chromecast::Ultra, audio: Some(2), burn_in: None },
+            TranscodeQuery { chromecast: Chromecast::GoogleTV, audio: Some(1), burn_in: Some(4) },
+        ];
+        for query in queries.iter() {
+            assert_eq!(&parse_query(&query.to_query_string()), query);
+        }
+        assert_eq!(queries[1].to_query_string(), "chromecast=Ultra&audio=2");
+    }
+}